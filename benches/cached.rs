@@ -0,0 +1,20 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use lazy_thread_local::ThreadLocal;
+
+fn cached(c: &mut Criterion) {
+    let tls: ThreadLocal<u64> = ThreadLocal::new(|| 1);
+    let cached = tls.cached();
+
+    c.bench_function("get", |b| b.iter(|| black_box(tls.get())));
+    c.bench_function("cached get", |b| b.iter(|| black_box(cached.get())));
+
+    // invalidated instances check the registry on every uncached access
+    tls.invalidate();
+    c.bench_function("get after invalidate", |b| b.iter(|| black_box(tls.get())));
+    c.bench_function("cached get after invalidate", |b| {
+        b.iter(|| black_box(cached.get()))
+    });
+}
+
+criterion_group!(benches, cached);
+criterion_main!(benches);
@@ -0,0 +1,41 @@
+//! compares the backends, run with and without `--features registry-backend`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use lazy_thread_local::ThreadLocal;
+
+const THREADS: usize = 32;
+
+fn enumerate(c: &mut Criterion) {
+    let backend = if cfg!(feature = "registry-backend") {
+        "table"
+    } else {
+        "key"
+    };
+
+    let mut tls: ThreadLocal<u64> = ThreadLocal::new(|| 1);
+    tls.get();
+    std::thread::scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| tls.get());
+        }
+    });
+
+    c.bench_function(
+        &format!("iter {} threads ({})", THREADS + 1, backend),
+        |b| b.iter(|| black_box(tls.iter().sum::<u64>())),
+    );
+
+    c.bench_function(&format!("get ({})", backend), |b| {
+        b.iter(|| black_box(*tls.get()))
+    });
+
+    c.bench_function(&format!("new + get ({})", backend), |b| {
+        b.iter(|| {
+            let tls: ThreadLocal<u64> = ThreadLocal::new(|| 1);
+            black_box(*tls.get())
+        })
+    });
+}
+
+criterion_group!(benches, enumerate);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use lazy_thread_local::ThreadLocal;
+
+// too large to be kept inline in the key slot
+static CONST: ThreadLocal<[u64; 4]> = ThreadLocal::const_new([1; 4]);
+
+fn get(c: &mut Criterion) {
+    let tls: ThreadLocal<[u64; 4]> = ThreadLocal::new(|| [1; 4]);
+    tls.get();
+    CONST.get();
+
+    // `new` instances skip the key state check entirely
+    c.bench_function("get new", |b| b.iter(|| black_box(tls.get())));
+    c.bench_function("get const_new", |b| b.iter(|| black_box(CONST.get())));
+}
+
+criterion_group!(benches, get);
+criterion_main!(benches);
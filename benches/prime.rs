@@ -0,0 +1,23 @@
+use std::cell::Cell;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use lazy_thread_local::ThreadLocal;
+
+static UNPRIMED: ThreadLocal<[u64; 2]> = ThreadLocal::const_new([1; 2]);
+static PRIMED: ThreadLocal<[u64; 2]> = ThreadLocal::const_new([1; 2]);
+
+std::thread_local! {
+    static STD: Cell<[u64; 2]> = const { Cell::new([1; 2]) };
+}
+
+fn prime(c: &mut Criterion) {
+    UNPRIMED.get();
+    PRIMED.prime_all(&std::sync::Barrier::new(1));
+
+    c.bench_function("get (unprimed)", |b| b.iter(|| black_box(*UNPRIMED.get())));
+    c.bench_function("get (primed)", |b| b.iter(|| black_box(*PRIMED.get())));
+    c.bench_function("std thread_local!", |b| b.iter(|| black_box(STD.get())));
+}
+
+criterion_group!(benches, prime);
+criterion_main!(benches);
@@ -0,0 +1,28 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use lazy_thread_local::ThreadLocal;
+
+fn reinit(c: &mut Criterion) {
+    let tls: ThreadLocal<[u64; 8]> = ThreadLocal::new(|| [0; 8]);
+    tls.get();
+
+    c.bench_function("reinit", |b| {
+        b.iter(|| {
+            unsafe { tls.reinit() };
+            black_box(tls.get());
+        })
+    });
+
+    c.bench_function("deinitialize + get", |b| {
+        b.iter(|| {
+            unsafe { tls.deinitialize() };
+            black_box(tls.get());
+        })
+    });
+
+    c.bench_function("replace", |b| {
+        b.iter(|| black_box(unsafe { tls.replace([1; 8]) }))
+    });
+}
+
+criterion_group!(benches, reinit);
+criterion_main!(benches);
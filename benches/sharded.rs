@@ -0,0 +1,37 @@
+//! compares initialising 64 threads on one registry against 8 shards.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use lazy_thread_local::{ShardedThreadLocal, ThreadLocal};
+
+const THREADS: usize = 64;
+const SHARDS: usize = 8;
+
+fn sharded(c: &mut Criterion) {
+    c.bench_function(&format!("init {} threads (single)", THREADS), |b| {
+        b.iter(|| {
+            let tls: ThreadLocal<u64> = ThreadLocal::new(|| 1);
+            std::thread::scope(|s| {
+                for _ in 0..THREADS {
+                    s.spawn(|| black_box(*tls.get()));
+                }
+            });
+        })
+    });
+
+    c.bench_function(
+        &format!("init {} threads ({} shards)", THREADS, SHARDS),
+        |b| {
+            b.iter(|| {
+                let tls: ShardedThreadLocal<u64> = ThreadLocal::sharded(|| 1, SHARDS);
+                std::thread::scope(|s| {
+                    for _ in 0..THREADS {
+                        s.spawn(|| black_box(*tls.get()));
+                    }
+                });
+            })
+        },
+    );
+}
+
+criterion_group!(benches, sharded);
+criterion_main!(benches);
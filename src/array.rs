@@ -0,0 +1,207 @@
+use core::cell::{Cell, UnsafeCell};
+use core::mem::MaybeUninit;
+
+use crate::{private, Allocator, ThreadLocal};
+
+/// the elements of one thread, each initialised on its own.
+struct Slots<T, const N: usize> {
+    initialised: [Cell<bool>; N],
+    values: UnsafeCell<[MaybeUninit<T>; N]>,
+}
+
+impl<T, const N: usize> Slots<T, N> {
+    const fn new() -> Self {
+        Self {
+            initialised: [const { Cell::new(false) }; N],
+            values: UnsafeCell::new([const { MaybeUninit::uninit() }; N]),
+        }
+    }
+
+    fn element(&self, index: usize) -> *mut T {
+        unsafe { (*self.values.get())[index].as_mut_ptr() }
+    }
+}
+
+impl<T, const N: usize> Drop for Slots<T, N> {
+    fn drop(&mut self) {
+        for (index, initialised) in self.initialised.iter().enumerate() {
+            if initialised.get() {
+                unsafe { core::ptr::drop_in_place(self.element(index)) };
+            }
+        }
+    }
+}
+
+/// produces the elements of a `ThreadLocalArray`, given their index.
+pub trait ElementInitialiser<T> {
+    fn init(&self, index: usize) -> T;
+}
+
+impl<F, T> ElementInitialiser<T> for F
+where
+    F: Fn(usize) -> T,
+{
+    fn init(&self, index: usize) -> T {
+        self(index)
+    }
+}
+
+/// A constant array copied element by element into each thread.
+///
+/// the initialiser of arrays created by `ThreadLocalArray::const_new`.
+pub struct SeedArray<T, const N: usize>([T; N]);
+
+impl<T: Copy, const N: usize> ElementInitialiser<T> for SeedArray<T, N> {
+    fn init(&self, index: usize) -> T {
+        self.0[index]
+    }
+}
+
+/// Per-thread arrays whose elements are initialised independently.
+///
+/// every thread gets one allocation holding `N` elements, and an element
+/// is only produced by the initialiser, given its index, on first access.
+pub struct ThreadLocalArray<
+    T,
+    const N: usize,
+    F = fn(usize) -> T,
+    A: Allocator = private::DefaultAllocator,
+> {
+    inner: ThreadLocal<Slots<T, N>, A>,
+    init: F,
+}
+
+impl<T, const N: usize, F: Fn(usize) -> T> ThreadLocalArray<T, N, F> {
+    /// creates an array whose element `i` is produced by `init(i)`.
+    pub fn new(init: F) -> Self {
+        Self::new_in(init)
+    }
+}
+
+impl<T, const N: usize, F: Fn(usize) -> T, A: Allocator> ThreadLocalArray<T, N, F, A> {
+    pub fn new_in(init: F) -> Self {
+        Self {
+            // `Slots::new` borrows nothing, whatever `T` is
+            inner: ThreadLocal::try_new_with(Slots::new, false, None)
+                .unwrap_or_else(|e| panic!("{}", e)),
+            init,
+        }
+    }
+}
+
+impl<T: Copy, const N: usize> ThreadLocalArray<T, N, SeedArray<T, N>> {
+    /// creates an array whose elements are copied from `seed`.
+    ///
+    /// unlike `ThreadLocal::const_new`, a thread copies only the elements
+    /// it accesses, so large seeds are not copied whole into every thread.
+    pub const fn const_new(seed: [T; N]) -> Self {
+        Self::const_new_in(seed)
+    }
+}
+
+impl<T: Copy, const N: usize, A: Allocator> ThreadLocalArray<T, N, SeedArray<T, N>, A> {
+    pub const fn const_new_in(seed: [T; N]) -> Self {
+        fn slots<T, const N: usize>(_: *mut u8) -> Slots<T, N> {
+            Slots::new()
+        }
+
+        Self {
+            inner: ThreadLocal::const_with(None, None, slots::<T, N>),
+            init: SeedArray(seed),
+        }
+    }
+}
+
+impl<T, const N: usize, F: ElementInitialiser<T>, A: Allocator> ThreadLocalArray<T, N, F, A> {
+    /// returns element `index` of the current thread, initialising it if needed.
+    ///
+    /// panics if `index` is out of bounds.
+    pub fn get(&self, index: usize) -> &T {
+        let slots = self.inner.get();
+        assert!(
+            index < N,
+            "index {} out of bounds for {} elements",
+            index,
+            N
+        );
+
+        if !slots.initialised[index].get() {
+            let value = self.init.init(index);
+
+            // the initialiser may have accessed the element itself
+            if slots.initialised[index].get() {
+                drop(value);
+            } else {
+                unsafe { slots.element(index).write(value) };
+                slots.initialised[index].set(true);
+            }
+        }
+
+        unsafe { &*slots.element(index) }
+    }
+
+    /// like `get`, but hands out a mutable reference.
+    pub fn get_mut(&mut self, index: usize) -> &mut T {
+        self.get(index);
+        unsafe { &mut *self.inner.get().element(index) }
+    }
+
+    /// whether element `index` of the current thread has been initialised.
+    pub fn is_initialised(&self, index: usize) -> bool {
+        match self.inner.try_get() {
+            Ok(slots) => return slots.initialised.get(index).is_some_and(|i| i.get()),
+            Err(_) => return false,
+        }
+    }
+}
+
+#[test]
+fn elements_initialise_independently() {
+    let mut tls: ThreadLocalArray<String, 8> = ThreadLocalArray::new(|i| i.to_string());
+
+    assert_eq!(tls.get(2), "2");
+    tls.get_mut(5).push('!');
+    assert_eq!(tls.get(5), "5!");
+
+    for i in 0..8 {
+        assert_eq!(tls.is_initialised(i), i == 2 || i == 5);
+    }
+    assert!(!tls.is_initialised(8));
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            assert!(!tls.is_initialised(2));
+            assert_eq!(tls.get(7), "7");
+        });
+    });
+    assert!(!tls.is_initialised(7));
+}
+
+#[test]
+fn const_seed_is_copied_per_element() {
+    const fn seed() -> [u32; 256] {
+        let mut seed = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            seed[i] = i as u32 * 3;
+            i += 1;
+        }
+        return seed;
+    }
+
+    static TABLE: ThreadLocalArray<u32, 256, SeedArray<u32, 256>> =
+        ThreadLocalArray::const_new(seed());
+
+    assert_eq!(*TABLE.get(255), 765);
+    assert!(!TABLE.is_initialised(0));
+
+    std::thread::spawn(|| {
+        assert!(!TABLE.is_initialised(255));
+        assert!((0..256).all(|i| *TABLE.get(i) == i as u32 * 3));
+    })
+    .join()
+    .unwrap();
+
+    assert!(TABLE.is_initialised(255));
+    assert!(!TABLE.is_initialised(1));
+}
@@ -0,0 +1,37 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// a `ThreadLocal` registered for cleanup, with the function releasing it.
+struct Hook {
+    this: usize,
+    release: unsafe fn(usize),
+}
+
+static HOOKS: Mutex<Vec<Hook>> = Mutex::new(Vec::new());
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn run_hooks() {
+    let hooks = core::mem::take(&mut *HOOKS.lock().unwrap_or_else(|e| e.into_inner()));
+
+    // like `atexit` itself, the last registered runs first
+    for hook in hooks.into_iter().rev() {
+        unsafe { (hook.release)(hook.this) };
+    }
+}
+
+/// runs `release(this)` at process exit.
+///
+/// `this` must stay valid until then, and `release` must be sound to call
+/// while other threads are still running.
+pub(crate) unsafe fn register(this: usize, release: unsafe fn(usize)) {
+    HOOKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(Hook { this, release });
+
+    if !INSTALLED.swap(true, Ordering::AcqRel) {
+        unsafe {
+            libc::atexit(run_hooks);
+        }
+    }
+}
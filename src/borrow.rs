@@ -0,0 +1,134 @@
+//! the borrow guards of `ThreadLocal::get_ref` and `ThreadLocal::mutate`.
+//!
+//! with the `borrow-tracking` feature every guard counts itself against the
+//! address of its value, so that a `mutate` overlapping another borrow of
+//! the same value panics rather than aliasing it. without the feature the
+//! guards are plain references.
+
+#[cfg(feature = "borrow-tracking")]
+use std::collections::BTreeMap;
+#[cfg(feature = "borrow-tracking")]
+use std::sync::Mutex;
+
+/// the borrows of each tracked value by address, -1 if borrowed mutably.
+#[cfg(feature = "borrow-tracking")]
+static BORROWS: Mutex<BTreeMap<usize, isize>> = Mutex::new(BTreeMap::new());
+
+/// counts a borrow of the value at `addr`, panicking if it conflicts.
+#[cfg(feature = "borrow-tracking")]
+fn acquire(addr: usize, exclusive: bool) {
+    let mut borrows = BORROWS.lock().unwrap_or_else(|e| e.into_inner());
+    let count = borrows.entry(addr).or_insert(0);
+
+    let conflict = match exclusive {
+        true => *count != 0,
+        false => *count < 0,
+    };
+    if !conflict {
+        *count = if exclusive { -1 } else { *count + 1 };
+        return;
+    }
+
+    // unlocked first, so the panic does not poison the other borrows
+    drop(borrows);
+    if exclusive {
+        panic!("the thread local value is already borrowed");
+    }
+    panic!("the thread local value is already borrowed mutably");
+}
+
+#[cfg(feature = "borrow-tracking")]
+fn release(addr: usize) {
+    let mut borrows = BORROWS.lock().unwrap_or_else(|e| e.into_inner());
+
+    if let Some(count) = borrows.get_mut(&addr) {
+        *count = if *count < 0 { 0 } else { *count - 1 };
+        if *count == 0 {
+            borrows.remove(&addr);
+        }
+    }
+}
+
+/// A shared borrow of the value of the current thread.
+///
+/// obtained from `ThreadLocal::get_ref`.
+pub struct Ref<'a, T> {
+    value: &'a T,
+}
+
+impl<'a, T> Ref<'a, T> {
+    pub(crate) fn new(value: &'a T) -> Self {
+        #[cfg(feature = "borrow-tracking")]
+        acquire(value as *const T as usize, false);
+
+        return Self { value };
+    }
+}
+
+impl<T> core::ops::Deref for Ref<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+#[cfg(feature = "borrow-tracking")]
+impl<T> Drop for Ref<'_, T> {
+    fn drop(&mut self) {
+        release(self.value as *const T as usize);
+    }
+}
+
+/// A mutable borrow of the value of the current thread.
+///
+/// obtained from `ThreadLocal::mutate`.
+pub struct RefMut<'a, T> {
+    value: &'a mut T,
+}
+
+impl<'a, T> RefMut<'a, T> {
+    pub(crate) fn new(value: &'a mut T) -> Self {
+        #[cfg(feature = "borrow-tracking")]
+        acquire(value as *mut T as usize, true);
+
+        return Self { value };
+    }
+}
+
+impl<T> core::ops::Deref for RefMut<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> core::ops::DerefMut for RefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+#[cfg(feature = "borrow-tracking")]
+impl<T> Drop for RefMut<'_, T> {
+    fn drop(&mut self) {
+        release(self.value as *mut T as usize);
+    }
+}
+
+#[cfg(feature = "borrow-tracking")]
+#[test]
+fn overlapping_mutate_is_detected() {
+    let tls: crate::ThreadLocal<Vec<u32>> = crate::ThreadLocal::new(Vec::new);
+
+    let shared = tls.get_ref();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        unsafe { tls.mutate() }.push(1);
+    }));
+    assert!(result.is_err());
+    assert!(shared.is_empty());
+    drop(shared);
+
+    // once the shared borrow is gone, mutating is fine again
+    unsafe { tls.mutate() }.push(2);
+    assert_eq!(*tls.get_ref(), [2]);
+}
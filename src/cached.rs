@@ -0,0 +1,121 @@
+//! a per-thread handle caching the value of a `ThreadLocal`.
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::sync::atomic::Ordering;
+
+use crate::{Allocator, Backend, ThreadLocal};
+
+/// A handle caching the value of the current thread, from
+/// `ThreadLocal::cached`.
+///
+/// repeated `get`s return the cached reference after comparing two
+/// counters, skipping the key lookup and, once the `ThreadLocal` was
+/// invalidated, the registry lock. the value is fetched again after
+/// `invalidate` or `clear`, or once any thread gave up its value with
+/// `take` or `deinitialize`. instances created by `new_with_ttl` are never
+/// cached, since their values expire without either counter changing.
+///
+/// a cached reference stays valid even if the value was replaced since:
+/// values replaced after `invalidate` are only freed once the `ThreadLocal`
+/// is borrowed mutably, which the handle prevents, and `take`, `reinit` and
+/// the like require in their safety contract that no reference from the
+/// handle is used afterwards.
+///
+/// the handle is bound to the thread that created it.
+pub struct Cached<'a, T, A: Allocator, B: Backend> {
+    tls: &'a ThreadLocal<T, A, B>,
+    /// the cached value, with the generation and releases it was fetched in.
+    value: Cell<Option<(*const T, usize, usize)>>,
+    _thread: PhantomData<*const ()>,
+}
+
+impl<'a, T, A: Allocator, B: Backend> Cached<'a, T, A, B> {
+    /// returns the value of the current thread, initialising it if needed.
+    pub fn get(&self) -> &T {
+        if self.tls.ttl.is_some() {
+            return self.tls.get();
+        }
+
+        let generation = self.tls.generation.load(Ordering::Acquire);
+        let releases = self.tls.releases.load(Ordering::Acquire);
+
+        if let Some((ptr, cached_generation, cached_releases)) = self.value.get() {
+            if cached_generation == generation && cached_releases == releases {
+                return unsafe { &*ptr };
+            }
+        }
+
+        // the counters are read first, so a change while fetching is seen next time
+        let value = self.tls.get();
+        self.value.set(Some((value, generation, releases)));
+        return value;
+    }
+
+    /// forgets the cached value, fetching it again on the next `get`.
+    pub fn refresh(&self) {
+        self.value.set(None);
+    }
+}
+
+impl<T, A: Allocator, B: Backend> ThreadLocal<T, A, B> {
+    /// returns a handle caching the value of the current thread, for
+    /// read-mostly access.
+    pub fn cached(&self) -> Cached<'_, T, A, B> {
+        return Cached {
+            tls: self,
+            value: Cell::new(None),
+            _thread: PhantomData,
+        };
+    }
+}
+
+#[test]
+fn invalidate_forces_a_refetch() {
+    use core::sync::atomic::AtomicUsize;
+
+    static INITS: AtomicUsize = AtomicUsize::new(0);
+
+    let tls: ThreadLocal<usize> = ThreadLocal::new(|| INITS.fetch_add(1, Ordering::SeqCst));
+    let cached = tls.cached();
+
+    assert_eq!(*cached.get(), 0);
+    assert_eq!(*cached.get(), 0);
+    assert_eq!(INITS.load(Ordering::SeqCst), 1);
+
+    tls.invalidate();
+    assert_eq!(*cached.get(), 1);
+    assert_eq!(*cached.get(), 1);
+
+    unsafe { tls.deinitialize() };
+    assert_eq!(*cached.get(), 2);
+    assert_eq!(INITS.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn cached_references_outlive_invalidate() {
+    let tls: ThreadLocal<Vec<u32>> = ThreadLocal::new(|| vec![1, 2]);
+    let cached = tls.cached();
+
+    let old = cached.get().as_slice();
+    tls.invalidate();
+    let new = cached.get().as_slice();
+
+    assert_ne!(old.as_ptr(), new.as_ptr());
+    assert_eq!(old, [1, 2]);
+}
+
+#[test]
+fn ttl_values_are_not_cached() {
+    use core::sync::atomic::AtomicUsize;
+
+    static INITS: AtomicUsize = AtomicUsize::new(0);
+
+    let ttl = std::time::Duration::from_millis(10);
+    let tls = ThreadLocal::new_with_ttl(ttl, || INITS.fetch_add(1, Ordering::SeqCst));
+    let cached = tls.cached();
+
+    let first = *cached.get();
+    std::thread::sleep(ttl * 2);
+    assert_ne!(*cached.get(), first);
+}
@@ -0,0 +1,110 @@
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// the address and id of every `ThreadLocal` with cleanup enabled, sorted.
+static LIVE: Mutex<Vec<(usize, u64)>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+fn live() -> std::sync::MutexGuard<'static, Vec<(usize, u64)>> {
+    LIVE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// a `ThreadLocal` the thread holds a value of, with the function dropping it.
+struct Hook {
+    addr: usize,
+    id: u64,
+    /// returns false if the instance was dropped in the meantime.
+    run: unsafe fn(usize, u64) -> bool,
+}
+
+/// drops the values of the thread when its thread locals are destroyed.
+struct Hooks(RefCell<Vec<Hook>>);
+
+impl Drop for Hooks {
+    fn drop(&mut self) {
+        run(self.0.take());
+    }
+}
+
+fn run(hooks: Vec<Hook>) {
+    for hook in hooks {
+        unsafe { (hook.run)(hook.addr, hook.id) };
+    }
+}
+
+std::thread_local! {
+    static HOOKS: Hooks = const { Hooks(RefCell::new(Vec::new())) };
+}
+
+/// returns whether exiting threads run destructors on this platform, which
+/// `ThreadLocal::enable_cleanup` relies on.
+///
+/// true on Unix and Windows. wasm threads exit without destroying their
+/// thread locals, so values are only dropped with their `ThreadLocal`
+/// there, as if cleanup was never enabled.
+pub const fn supports_thread_exit_cleanup() -> bool {
+    return !cfg!(target_family = "wasm");
+}
+
+/// enables cleanup for the instance at `addr`, returning its id.
+pub(crate) fn enable(addr: usize) -> u64 {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    let mut live = live();
+    let idx = live.partition_point(|entry| *entry < (addr, id));
+    live.insert(idx, (addr, id));
+
+    return id;
+}
+
+/// disables cleanup for the instance, exit hooks skip it afterwards.
+pub(crate) fn disable(addr: usize, id: u64) {
+    let mut live = live();
+
+    if let Ok(idx) = live.binary_search(&(addr, id)) {
+        live.remove(idx);
+    }
+}
+
+/// runs `f` if the instance still has cleanup enabled, with `enter` called
+/// under the lock so the instance can keep its drop waiting for `f` to finish.
+pub(crate) fn with_live<F: FnOnce()>(addr: usize, id: u64, enter: impl FnOnce(), f: F) -> bool {
+    {
+        let live = live();
+        if live.binary_search(&(addr, id)).is_err() {
+            return false;
+        }
+        enter();
+    }
+
+    f();
+    return true;
+}
+
+/// runs the hooks of the current thread ahead of its thread locals being
+/// destroyed, which may never happen for threads detaching from a DLL.
+#[cfg(all(windows, feature = "dll-detach"))]
+pub(crate) fn run_current() {
+    run(HOOKS.try_with(|hooks| hooks.0.take()).unwrap_or_default());
+}
+
+/// makes the current thread run `run` for the instance when it exits.
+///
+/// does nothing once the thread is tearing down its thread locals.
+pub(crate) fn register(addr: usize, id: u64, run: unsafe fn(usize, u64) -> bool) {
+    let _ = HOOKS.try_with(|hooks| {
+        let mut hooks = hooks.0.borrow_mut();
+
+        if !hooks.iter().any(|hook| hook.addr == addr && hook.id == id) {
+            hooks.push(Hook { addr, id, run });
+        }
+    });
+}
+
+#[test]
+fn thread_exit_cleanup_support_matches_the_platform() {
+    const SUPPORTED: bool = supports_thread_exit_cleanup();
+
+    assert_eq!(SUPPORTED, cfg!(any(unix, windows)));
+}
@@ -0,0 +1,60 @@
+//! cleanup of threads detaching from a DLL, enabled by the `dll-detach`
+//! feature.
+//!
+//! the loader calls the TLS callbacks of an image, found in the `.CRT$XL?`
+//! sections, whenever a thread detaches. threads of a host process may
+//! detach from a DLL without the thread locals of the DLL being destroyed,
+//! so the exit hooks of `enable_cleanup` are run from the callback instead.
+
+use winapi::shared::minwindef::{DWORD, LPVOID};
+
+/// the reason given to TLS callbacks when a thread exits.
+const DLL_THREAD_DETACH: DWORD = 3;
+
+#[used]
+#[link_section = ".CRT$XLB"]
+static ON_DETACH: unsafe extern "system" fn(LPVOID, DWORD, LPVOID) = on_tls_callback;
+
+unsafe extern "system" fn on_tls_callback(_image: LPVOID, reason: DWORD, _reserved: LPVOID) {
+    if reason == DLL_THREAD_DETACH {
+        crate::cleanup::run_current();
+    }
+}
+
+#[test]
+fn detach_drops_values_of_the_thread() {
+    use core::pin::Pin;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted;
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let tls: Pin<Box<crate::ThreadLocal<Counted>>> = Box::pin(crate::ThreadLocal::new(|| Counted));
+    tls.as_ref().enable_cleanup();
+    let before = tls.len();
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            tls.get();
+            // as the loader would on detach, before thread locals are destroyed
+            unsafe {
+                on_tls_callback(
+                    core::ptr::null_mut(),
+                    DLL_THREAD_DETACH,
+                    core::ptr::null_mut(),
+                )
+            };
+            assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+        });
+    });
+
+    assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    assert_eq!(tls.len(), before);
+}
@@ -0,0 +1,134 @@
+//! values moved out of a `ThreadLocal` without leaving their allocation.
+
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+use crate::{Allocator, Backend, ThreadLocal, ThreadLocalError};
+
+/// A value detached from a `ThreadLocal`, from `ThreadLocal::detach`.
+///
+/// the handle owns the allocation the value was kept in, and drops and
+/// frees it on its own drop, so it may outlive the `ThreadLocal` and be
+/// moved between threads.
+pub struct Detached<T, A: Allocator = crate::private::DefaultAllocator> {
+    ptr: NonNull<T>,
+    _owns: PhantomData<(T, A)>,
+}
+
+impl<T, A: Allocator> Detached<T, A> {
+    /// moves the value out, freeing its allocation.
+    pub fn into_inner(self) -> T {
+        let this = core::mem::ManuallyDrop::new(self);
+
+        unsafe {
+            let value = this.ptr.as_ptr().read();
+            crate::deallocate_value::<T, A>(this.ptr.as_ptr());
+            return value;
+        }
+    }
+}
+
+impl<T, A: Allocator> core::ops::Deref for Detached<T, A> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*crate::cell_value(self.ptr.as_ptr()) }
+    }
+}
+
+impl<T, A: Allocator> core::ops::DerefMut for Detached<T, A> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *crate::cell_value(self.ptr.as_ptr()) }
+    }
+}
+
+impl<T, A: Allocator> Drop for Detached<T, A> {
+    fn drop(&mut self) {
+        unsafe {
+            crate::ThreadLocal::<T, A>::free_value(self.ptr.as_ptr(), crate::ThreadId::current())
+        };
+    }
+}
+
+impl<T: core::fmt::Debug, A: Allocator> core::fmt::Debug for Detached<T, A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+unsafe impl<T: Send, A: Allocator> Send for Detached<T, A> {}
+unsafe impl<T: Sync, A: Allocator> Sync for Detached<T, A> {}
+
+impl<T, A: Allocator, B: Backend> ThreadLocal<T, A, B> {
+    /// removes the value of the current thread, keeping it in its
+    /// allocation.
+    ///
+    /// the registry forgets the value, so the next access runs the
+    /// initialiser again. small values kept inline in the key slot (see
+    /// `const_new`) are moved into a new allocation.
+    ///
+    /// # Safety
+    ///
+    /// the handle owns the value from now on and may mutate or free it, so
+    /// no reference to the value of the current thread, from `get`, `peek`,
+    /// `with`, `Cached` or the like, may be used afterwards.
+    pub unsafe fn detach(&self) -> Option<Detached<T, A>> {
+        unsafe {
+            let word = self.clear_slot();
+
+            if word == 0 {
+                return None;
+            }
+
+            let ptr = if self.is_inline_word(word) {
+                let ptr = crate::allocate_value::<T, A>(false);
+                if ptr.is_null() {
+                    panic!("{}", ThreadLocalError::AllocFailed);
+                }
+                ptr.write(crate::unpack_inline(word));
+                ptr
+            } else {
+                word as *mut T
+            };
+
+            return Some(Detached {
+                ptr: NonNull::new_unchecked(ptr),
+                _owns: PhantomData,
+            });
+        }
+    }
+}
+
+#[test]
+fn detached_value_outlives_its_thread_local() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted(Vec<u32>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let tls: ThreadLocal<Counted> = ThreadLocal::new(|| Counted(vec![1]));
+    tls.get();
+    let mut detached = unsafe { tls.detach() }.unwrap();
+    assert!(tls.is_empty());
+
+    // the next access starts over
+    tls.get();
+    drop(tls);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+
+    detached.0.push(2);
+    std::thread::spawn(move || {
+        assert_eq!(detached.0, [1, 2]);
+        drop(detached);
+    })
+    .join()
+    .unwrap();
+    assert_eq!(DROPS.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,136 @@
+use crate::{Allocator, Backend, ThreadLocal, ThreadLocalError};
+
+/// object safe view of a `ThreadLocal`, hiding its allocator.
+trait ErasedThreadLocal<T> {
+    fn try_value_ptr(&self) -> Result<*mut T, ThreadLocalError>;
+}
+
+impl<T, A: Allocator, B: Backend> ErasedThreadLocal<T> for ThreadLocal<T, A, B> {
+    fn try_value_ptr(&self) -> Result<*mut T, ThreadLocalError> {
+        ThreadLocal::try_value_ptr(self)
+    }
+}
+
+/// A shared borrow of a `ThreadLocal` that does not name its allocator.
+///
+/// Useful to pass thread locals across APIs that should only be generic over
+/// the value type. Obtained from `ThreadLocal::as_dyn`.
+pub struct ThreadLocalRef<'a, T> {
+    inner: &'a dyn ErasedThreadLocal<T>,
+}
+
+impl<'a, T> ThreadLocalRef<'a, T> {
+    pub(crate) fn new<A: Allocator, B: Backend>(tls: &'a ThreadLocal<T, A, B>) -> Self {
+        Self { inner: tls }
+    }
+
+    pub fn get(&self) -> &'a T {
+        self.try_get().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// like `get`, but reports failures instead of panicking.
+    pub fn try_get(&self) -> Result<&'a T, ThreadLocalError> {
+        unsafe { Ok(&*crate::cell_value(self.inner.try_value_ptr()?)) }
+    }
+
+    /// runs `f` with the value of the current thread.
+    pub fn with<R, F: FnOnce(&T) -> R>(&self, f: F) -> R {
+        f(self.get())
+    }
+}
+
+impl<T> Clone for ThreadLocalRef<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ThreadLocalRef<'_, T> {}
+
+impl<'a, T, A: Allocator, B: Backend> From<&'a ThreadLocal<T, A, B>> for ThreadLocalRef<'a, T> {
+    fn from(tls: &'a ThreadLocal<T, A, B>) -> Self {
+        Self::new(tls)
+    }
+}
+
+/// An exclusive borrow of a `ThreadLocal` that does not name its allocator.
+///
+/// Unlike `ThreadLocalRef` it also hands out mutable references. Obtained
+/// from `ThreadLocal::as_dyn_mut`.
+pub struct ThreadLocalMut<'a, T> {
+    inner: &'a mut dyn ErasedThreadLocal<T>,
+}
+
+impl<'a, T> ThreadLocalMut<'a, T> {
+    pub(crate) fn new<A: Allocator, B: Backend>(tls: &'a mut ThreadLocal<T, A, B>) -> Self {
+        Self { inner: tls }
+    }
+
+    pub fn get(&self) -> &T {
+        unsafe {
+            &*crate::cell_value(
+                self.inner
+                    .try_value_ptr()
+                    .unwrap_or_else(|e| panic!("{}", e)),
+            )
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe {
+            &mut *crate::cell_value(
+                self.inner
+                    .try_value_ptr()
+                    .unwrap_or_else(|e| panic!("{}", e)),
+            )
+        }
+    }
+
+    /// runs `f` with the value of the current thread.
+    pub fn with<R, F: FnOnce(&T) -> R>(&self, f: F) -> R {
+        f(self.get())
+    }
+
+    /// reborrows as a shared handle.
+    pub fn as_ref(&self) -> ThreadLocalRef<'_, T> {
+        ThreadLocalRef {
+            inner: &*self.inner,
+        }
+    }
+}
+
+impl<'a, T, A: Allocator, B: Backend> From<&'a mut ThreadLocal<T, A, B>> for ThreadLocalMut<'a, T> {
+    fn from(tls: &'a mut ThreadLocal<T, A, B>) -> Self {
+        Self::new(tls)
+    }
+}
+
+#[test]
+fn refs_hide_the_allocator() {
+    type CountingAllocator = crate::testing::CountingAllocator<2>;
+
+    fn read<T: Copy>(tls: ThreadLocalRef<'_, T>) -> T {
+        tls.with(|v| *v)
+    }
+
+    fn bump(mut tls: ThreadLocalMut<'_, u32>) {
+        *tls.get_mut() += 1;
+    }
+
+    let mut custom: ThreadLocal<u32, CountingAllocator> = ThreadLocal::new_in(|| 1);
+    let default: ThreadLocal<u32> = ThreadLocal::new(|| 2);
+
+    assert_eq!(read(custom.as_dyn()), 1);
+    assert_eq!(read((&default).into()), 2);
+
+    bump(custom.as_dyn_mut());
+    assert_eq!(read(custom.as_dyn()), 2);
+    std::thread::scope(|s| {
+        s.spawn(|| assert_eq!(read(custom.as_dyn()), 1));
+    });
+
+    // the values reached through the refs came from the hidden allocator
+    assert!(CountingAllocator::allocs() >= 2);
+    drop(custom);
+    assert_eq!(CountingAllocator::allocs(), CountingAllocator::frees());
+}
@@ -0,0 +1,176 @@
+use core::cell::{Cell, UnsafeCell};
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::{private, Allocator, ThreadLocal};
+
+/// the value copied by every thread, with the epoch it was published in.
+struct Published<T> {
+    epoch: AtomicU64,
+    template: Mutex<T>,
+}
+
+impl<T: Clone> Published<T> {
+    /// returns a copy of the template with its epoch.
+    fn copy(&self) -> (u64, T) {
+        let template = self.template.lock().unwrap_or_else(|e| e.into_inner());
+        // the epoch only changes while the template is locked
+        return (self.epoch.load(Ordering::Relaxed), template.clone());
+    }
+}
+
+/// the copy of a thread, with its epoch and the number of `EpochRef`s
+/// borrowing it.
+struct Local<T> {
+    value: UnsafeCell<(u64, T)>,
+    readers: Cell<usize>,
+}
+
+/// A value published by writers and read through per-thread copies.
+///
+/// every thread reads its own copy of the value, taking no lock. `publish`
+/// stores a new value and bumps the epoch, and each thread copies the new
+/// value on its next `get` once it sees a newer epoch, as in a simple RCU.
+/// threads that do not call `get` keep their old copy meanwhile.
+pub struct EpochThreadLocal<T, A: Allocator = private::DefaultAllocator> {
+    shared: Arc<Published<T>>,
+    copies: ThreadLocal<Local<T>, A>,
+}
+
+/// A borrow of the copy of the current thread, from `EpochThreadLocal::get`.
+///
+/// the copy is not refreshed while any guard of its thread is alive, so a
+/// new `get` returns the same copy until every guard is dropped.
+pub struct EpochRef<'a, T> {
+    copy: &'a Local<T>,
+}
+
+impl<T> core::ops::Deref for EpochRef<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // only `get` replaces the copy, never while a guard is alive
+        unsafe { &(*self.copy.value.get()).1 }
+    }
+}
+
+impl<T> Drop for EpochRef<'_, T> {
+    fn drop(&mut self) {
+        self.copy.readers.set(self.copy.readers.get() - 1);
+    }
+}
+
+impl<T: Clone + Send + 'static> EpochThreadLocal<T> {
+    /// creates a per-thread copied value, starting out as `value`.
+    pub fn new(value: T) -> Self {
+        Self::new_in(value)
+    }
+}
+
+impl<T: Clone + Send + 'static, A: Allocator> EpochThreadLocal<T, A> {
+    pub fn new_in(value: T) -> Self {
+        let shared = Arc::new(Published {
+            epoch: AtomicU64::new(0),
+            template: Mutex::new(value),
+        });
+        let template = shared.clone();
+
+        return Self {
+            shared,
+            copies: ThreadLocal::new_in(move || Local {
+                value: UnsafeCell::new(template.copy()),
+                readers: Cell::new(0),
+            }),
+        };
+    }
+
+    /// returns the copy of the current thread, refreshing it first if a
+    /// newer value was published since it was made and no guard of the
+    /// thread still borrows it.
+    pub fn get(&self) -> EpochRef<'_, T> {
+        let copy = self.copies.get();
+
+        if copy.readers.get() == 0 {
+            let epoch = self.shared.epoch.load(Ordering::Acquire);
+
+            // only the current thread reaches its copy while `self` is shared
+            unsafe {
+                if (*copy.value.get()).0 != epoch {
+                    let old = core::mem::replace(&mut *copy.value.get(), self.shared.copy());
+                    // a `get` from the drop of the old copy finds the new one
+                    drop(old);
+                }
+            }
+        }
+
+        copy.readers.set(copy.readers.get() + 1);
+        return EpochRef { copy };
+    }
+
+    /// publishes `value`, which every thread copies on its next `get`.
+    pub fn publish(&self, value: T) {
+        let old = {
+            let mut template = self
+                .shared
+                .template
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            let old = core::mem::replace(&mut *template, value);
+            self.shared.epoch.fetch_add(1, Ordering::Release);
+            old
+        };
+        // dropped outside the lock
+        drop(old);
+    }
+
+    /// returns the epoch of the latest value, the number of `publish`es.
+    pub fn epoch(&self) -> u64 {
+        return self.shared.epoch.load(Ordering::Acquire);
+    }
+}
+
+#[test]
+fn readers_see_the_latest_publish_on_their_next_get() {
+    use std::sync::Barrier;
+
+    let config = EpochThreadLocal::new(String::from("v0"));
+    assert_eq!(*config.get(), "v0");
+
+    let published = Barrier::new(5);
+    let read = Barrier::new(5);
+
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                assert_eq!(*config.get(), "v0");
+                read.wait();
+                published.wait();
+                assert_eq!(*config.get(), "v2");
+                read.wait();
+            });
+        }
+
+        read.wait();
+        config.publish(String::from("v1"));
+        config.publish(String::from("v2"));
+        published.wait();
+        read.wait();
+    });
+
+    assert_eq!(config.epoch(), 2);
+    assert_eq!(*config.get(), "v2");
+}
+
+#[test]
+fn copies_are_not_refreshed_while_borrowed() {
+    let config = EpochThreadLocal::new(vec![0u32]);
+
+    let old = config.get();
+    config.publish(vec![1]);
+    let nested = config.get();
+    assert_eq!(*nested, [0]);
+
+    drop(nested);
+    assert_eq!(*old, [0]);
+    drop(old);
+    assert_eq!(*config.get(), [1]);
+}
@@ -0,0 +1,46 @@
+/// Errors reported by the fallible `try_*` methods of `ThreadLocal`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadLocalError {
+    /// the platform could not create a key, carrying its error code.
+    KeyCreate(i32),
+    /// every key of the emulated key space is in use.
+    KeyExhausted,
+    /// the allocator returned a null pointer.
+    AllocFailed,
+    /// the initialiser panicked on the current thread.
+    Poisoned,
+    /// the initialiser accessed the `ThreadLocal` it is initialising.
+    RecursiveInit,
+    /// as many threads as allowed by `set_max_threads` already hold a value.
+    ThreadLimit,
+    /// the `ThreadLocal` was frozen by `freeze` before the current thread
+    /// initialised its value.
+    Frozen,
+    /// another value would exceed the budget set by `set_memory_budget`.
+    MemoryBudget,
+    /// the current thread accessed the `ThreadLocal` while holding its
+    /// registry lock, e.g. from within `for_each`.
+    RegistryLocked,
+}
+
+impl core::fmt::Display for ThreadLocalError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::KeyCreate(code) => {
+                write!(f, "failed to create thread local key (error {})", code)
+            }
+            Self::KeyExhausted => f.write_str("thread local keys exhausted"),
+            Self::AllocFailed => f.write_str("failed to allocate thread local storage"),
+            Self::Poisoned => f.write_str("ThreadLocal poisoned"),
+            Self::RecursiveInit => f.write_str("ThreadLocal initialiser accessed itself"),
+            Self::ThreadLimit => f.write_str("ThreadLocal thread limit reached"),
+            Self::Frozen => f.write_str("ThreadLocal frozen"),
+            Self::MemoryBudget => f.write_str("ThreadLocal memory budget exceeded"),
+            Self::RegistryLocked => {
+                f.write_str("ThreadLocal accessed while the current thread iterates it")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ThreadLocalError {}
@@ -0,0 +1,31 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::ThreadId;
+
+/// bumped in the child every time the process forks.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+/// the thread that called `fork()`, the only one alive in the child.
+static SURVIVOR: AtomicU64 = AtomicU64::new(0);
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn child() {
+    SURVIVOR.store(ThreadId::current().as_u64(), Ordering::Relaxed);
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// registers the `pthread_atfork` handler once per process.
+pub(crate) fn install() {
+    if !INSTALLED.swap(true, Ordering::AcqRel) {
+        unsafe {
+            libc::pthread_atfork(None, None, Some(child));
+        }
+    }
+}
+
+pub(crate) fn generation() -> usize {
+    GENERATION.load(Ordering::Acquire)
+}
+
+pub(crate) fn survivor() -> ThreadId {
+    ThreadId::from_u64(SURVIVOR.load(Ordering::Relaxed))
+}
@@ -0,0 +1,61 @@
+//! canaries around per-thread values, enabled by the `debug-guards` feature.
+//!
+//! an allocation is laid out as `[front canary][value][back canary]`, where
+//! the front canary is padded to keep the value aligned.
+
+/// the byte every canary is filled with.
+const CANARY: u8 = 0xA5;
+/// the length of the back canary, and the least length of the front one.
+const CANARY_LEN: usize = 8;
+
+/// the offset of the value from the start of its allocation.
+const fn front<T>() -> usize {
+    if core::mem::align_of::<T>() > CANARY_LEN {
+        return core::mem::align_of::<T>();
+    }
+    return CANARY_LEN;
+}
+
+/// the size of the allocation holding a guarded `T`.
+pub(crate) const fn size<T>() -> usize {
+    front::<T>() + core::mem::size_of::<T>() + CANARY_LEN
+}
+
+/// writes the canaries into a fresh allocation, returning the value pointer.
+pub(crate) unsafe fn arm<T>(base: *mut u8) -> *mut T {
+    base.write_bytes(CANARY, front::<T>());
+    base.add(front::<T>() + core::mem::size_of::<T>())
+        .write_bytes(CANARY, CANARY_LEN);
+    return base.add(front::<T>()) as *mut T;
+}
+
+/// returns the start of the allocation holding `ptr`.
+pub(crate) unsafe fn base<T>(ptr: *mut T) -> *mut u8 {
+    (ptr as *mut u8).sub(front::<T>())
+}
+
+/// panics if either canary around `ptr` was overwritten.
+pub(crate) unsafe fn check<T>(ptr: *const T) {
+    let base = (ptr as *const u8).sub(front::<T>());
+    let head = core::slice::from_raw_parts(base, front::<T>());
+    let tail =
+        core::slice::from_raw_parts(base.add(head.len() + core::mem::size_of::<T>()), CANARY_LEN);
+
+    if head.iter().chain(tail).any(|b| *b != CANARY) {
+        panic!("the memory around a thread local value was overwritten");
+    }
+}
+
+#[test]
+fn get_panics_on_overwritten_canary() {
+    let tls: crate::ThreadLocal<u64> = crate::ThreadLocal::new(|| 1);
+    // a pointer of the allocation itself, writable past the value
+    let value = tls.value_ptr();
+    assert_eq!(*tls.get(), 1);
+
+    // an overrun of one element, as buggy FFI code would write it
+    unsafe { value.add(1).write(0) };
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| *tls.get()));
+    assert!(result.is_err());
+}
@@ -0,0 +1,99 @@
+use std::sync::Mutex;
+
+/// values at least this large are placed on huge pages.
+pub(crate) const THRESHOLD: usize = 2 << 20;
+
+/// the huge page size mappings are rounded to.
+const HUGE_PAGE: usize = 2 << 20;
+
+/// the address and length of every live huge page mapping, sorted by address.
+static MAPPINGS: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+fn mappings() -> std::sync::MutexGuard<'static, Vec<(usize, usize)>> {
+    MAPPINGS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// maps `size` bytes of zeroed huge pages, `None` if the system has none available.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn map(size: usize) -> Option<*mut u8> {
+    let len = size.checked_add(HUGE_PAGE - 1)? & !(HUGE_PAGE - 1);
+
+    let ptr = unsafe {
+        libc::mmap(
+            core::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return None;
+    }
+
+    let mut mappings = mappings();
+    let idx = mappings.partition_point(|(addr, _)| *addr < ptr as usize);
+    mappings.insert(idx, (ptr as usize, len));
+
+    return Some(ptr as *mut u8);
+}
+
+/// huge pages cannot be requested explicitly on this platform.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn map(_size: usize) -> Option<*mut u8> {
+    None
+}
+
+/// unmaps `ptr` if it was returned by `map`, reporting whether it was.
+pub(crate) fn unmap(ptr: *mut u8) -> bool {
+    let mut mappings = mappings();
+
+    match mappings.binary_search_by_key(&(ptr as usize), |(addr, _)| *addr) {
+        Ok(idx) => {
+            let (addr, len) = mappings.remove(idx);
+            unsafe { libc::munmap(addr as _, len) };
+            return true;
+        }
+        Err(_) => return false,
+    }
+}
+
+#[test]
+fn large_values_fall_back_to_malloc() {
+    use crate::Allocator;
+
+    type CAllocator = crate::private::CAllocator;
+
+    let size = 4 << 20;
+    let huge = map(size);
+    if let Some(ptr) = huge {
+        assert!(unmap(ptr));
+    }
+
+    // the allocator uses huge pages when the probe above got some
+    let ptr = CAllocator::allocate_zeroed(size);
+    assert!(!ptr.is_null());
+    unsafe {
+        assert!(core::slice::from_raw_parts(ptr, size)
+            .iter()
+            .all(|b| *b == 0));
+        ptr.write_bytes(7, size);
+        assert_eq!(*ptr.add(size - 1), 7);
+    }
+    let mapped = mappings().iter().any(|(addr, _)| *addr == ptr as usize);
+    assert!(huge.is_some() || !mapped);
+    CAllocator::deallocate(ptr);
+    assert!(!unmap(ptr));
+
+    // the values of a thread local go through the same path
+    std::thread::Builder::new()
+        .stack_size(64 << 20)
+        .spawn(|| {
+            let tls: crate::ThreadLocal<[u8; 4 << 20]> = crate::ThreadLocal::new_zeroed();
+            assert!(tls.get().iter().all(|b| *b == 0));
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
@@ -0,0 +1,173 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use crate::{ThreadId, ThreadLocalError, ThreadLocalInitialiser};
+
+/// the slot holds no value.
+const EMPTY: u8 = 0;
+/// the owner of the slot is running the initialiser.
+const INITIALISING: u8 = 1;
+/// the slot holds the value of its owner.
+const READY: u8 = 2;
+
+/// the value of one thread, claimed by writing its id into `owner`.
+struct Slot<T> {
+    /// the id of the thread owning the slot, 0 if unclaimed.
+    owner: AtomicU64,
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A thread local storing the values of up to `CAP` threads inline.
+///
+/// values never touch the allocator: each thread claims one of `CAP`
+/// slots on first access and keeps it until the thread local is dropped,
+/// even after the thread exits. threads beyond the first `CAP` are refused
+/// with `ThreadLocalError::ThreadLimit`, without allocating either. the
+/// slot of a thread is found by its `ThreadId`, which comes from a std
+/// thread local unless `set_thread_id_source` replaced it, so std is still
+/// needed. since a thread finds its slot by scanning, this suits small
+/// capacities.
+///
+/// `new` is a const fn, so a thread local can be placed in a static.
+pub struct InlineThreadLocal<T, const CAP: usize, I = fn() -> T> {
+    slots: [Slot<T>; CAP],
+    init: I,
+}
+
+impl<T, const CAP: usize, I> InlineThreadLocal<T, CAP, I> {
+    /// creates a thread local whose values are produced by `init`.
+    pub const fn new(init: I) -> Self {
+        Self {
+            slots: [const {
+                Slot {
+                    owner: AtomicU64::new(0),
+                    state: AtomicU8::new(EMPTY),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                }
+            }; CAP],
+            init,
+        }
+    }
+
+    /// returns the number of threads this thread local can hold values for.
+    pub const fn capacity(&self) -> usize {
+        CAP
+    }
+
+    /// returns the slot of the current thread, claiming a free one if needed.
+    fn slot(&self) -> Option<&Slot<T>> {
+        let id = ThreadId::current().as_u64();
+
+        if let Some(slot) = self
+            .slots
+            .iter()
+            .find(|slot| slot.owner.load(Ordering::Acquire) == id)
+        {
+            return Some(slot);
+        }
+
+        // only the current thread claims slots for itself, so it owns none yet
+        return self.slots.iter().find(|slot| {
+            slot.owner
+                .compare_exchange(0, id, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        });
+    }
+
+    /// returns the number of threads holding a value.
+    pub fn len(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.state.load(Ordering::Acquire) == READY)
+            .count()
+    }
+
+    /// returns whether no thread holds a value.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// iterates the values of all threads.
+    pub fn iter(&self) -> impl Iterator<Item = &T>
+    where
+        T: Sync,
+    {
+        self.slots
+            .iter()
+            .filter(|slot| slot.state.load(Ordering::Acquire) == READY)
+            .map(|slot| unsafe { (*slot.value.get()).assume_init_ref() })
+    }
+}
+
+impl<T, const CAP: usize, I: ThreadLocalInitialiser<T>> InlineThreadLocal<T, CAP, I> {
+    /// returns the value of the current thread, initialising it if needed.
+    ///
+    /// panics if `CAP` other threads already hold a value.
+    pub fn get(&self) -> &T {
+        self.try_get().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// like `get`, but reports failures instead of panicking.
+    pub fn try_get(&self) -> Result<&T, ThreadLocalError> {
+        /// returns the slot to empty if the initialiser panics.
+        struct ResetOnPanic<'a>(&'a AtomicU8);
+
+        impl Drop for ResetOnPanic<'_> {
+            fn drop(&mut self) {
+                self.0.store(EMPTY, Ordering::Release);
+            }
+        }
+
+        let slot = self.slot().ok_or(ThreadLocalError::ThreadLimit)?;
+
+        // only the owner writes the state, the others merely read it
+        match slot.state.load(Ordering::Acquire) {
+            READY => return Ok(unsafe { (*slot.value.get()).assume_init_ref() }),
+            INITIALISING => return Err(ThreadLocalError::RecursiveInit),
+            _ => {}
+        }
+
+        slot.state.store(INITIALISING, Ordering::Relaxed);
+        let guard = ResetOnPanic(&slot.state);
+        let value = self.init.init();
+        core::mem::forget(guard);
+
+        unsafe { (*slot.value.get()).write(value) };
+        slot.state.store(READY, Ordering::Release);
+
+        return Ok(unsafe { (*slot.value.get()).assume_init_ref() });
+    }
+}
+
+impl<T, const CAP: usize, I> Drop for InlineThreadLocal<T, CAP, I> {
+    fn drop(&mut self) {
+        for slot in self.slots.iter_mut() {
+            if *slot.state.get_mut() == READY {
+                unsafe { slot.value.get_mut().assume_init_drop() };
+            }
+        }
+    }
+}
+
+unsafe impl<T: Send, const CAP: usize, I: Sync> Sync for InlineThreadLocal<T, CAP, I> {}
+unsafe impl<T: Send, const CAP: usize, I: Send> Send for InlineThreadLocal<T, CAP, I> {}
+
+// tests/inline_no_alloc.rs checks at runtime that no allocation is made
+#[test]
+fn inline_refuses_threads_beyond_capacity() {
+    static TLS: InlineThreadLocal<ThreadId, 2> = InlineThreadLocal::new(ThreadId::current);
+
+    assert_eq!(*TLS.get(), ThreadId::current());
+    std::thread::spawn(|| assert_eq!(*TLS.get(), ThreadId::current()))
+        .join()
+        .unwrap();
+
+    let third = std::thread::spawn(|| TLS.try_get().copied())
+        .join()
+        .unwrap();
+    assert_eq!(third, Err(ThreadLocalError::ThreadLimit));
+    assert_eq!(TLS.len(), 2);
+    assert_eq!(*TLS.get(), ThreadId::current());
+}
@@ -0,0 +1,48 @@
+//! process wide counters of the keys created by the default backend,
+//! enabled by the `metrics` feature.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+static RECYCLED: AtomicUsize = AtomicUsize::new(0);
+
+/// The keys the default backend created in this process.
+///
+/// obtained from `global_key_usage`. platforms cap the number of live keys
+/// (e.g. `PTHREAD_KEYS_MAX`, 1024 on Linux, or the 4000 or so fiber local
+/// storage indices on Windows), so a `live` count growing towards the cap
+/// means `ThreadLocal`s are created faster than they are dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyUsage {
+    /// keys created so far.
+    pub created: usize,
+    /// keys deleted again, handed back to the platform or, on wasm, kept
+    /// for reuse by the next key.
+    pub recycled: usize,
+    /// keys in use, `created - recycled`.
+    pub live: usize,
+}
+
+/// returns the keys the default backend created so far.
+///
+/// only keys of the default backend are counted, not those of custom
+/// backends or adopted with `from_raw_key`.
+pub fn global_key_usage() -> KeyUsage {
+    // read in this order, so that `live` never underflows
+    let recycled = RECYCLED.load(Ordering::Acquire);
+    let created = CREATED.load(Ordering::Acquire);
+
+    return KeyUsage {
+        created,
+        recycled,
+        live: created - recycled,
+    };
+}
+
+pub(crate) fn created() {
+    CREATED.fetch_add(1, Ordering::AcqRel);
+}
+
+pub(crate) fn recycled() {
+    RECYCLED.fetch_add(1, Ordering::AcqRel);
+}
@@ -10,14 +10,22 @@
 //! This library provides the `ThreadLocal` type which allows a separate copy of
 //! an object to be used for each thread. This allows for per-object
 //! thread-local storage, unlike the crate `thread_local`, this crate provides
-//! lazy initialisation and does not depend on std.
+//! lazy initialisation. It requires std.
 //!
 //! Per-thread objects are not destroyed when a thread exits. Instead, objects
 //! are only destroyed when the `ThreadLocal` containing them is dropped.
+//! Until then the values of every thread can be visited with `for_each`, or
+//! with `iter` given exclusive access.
 //!
 //! This crate uses platform dependent methods to create thread local keys.
 //! On Unix, pthread local storage is used. On windows, Fibers storage is used.
-//! On wasm, it relies on std to provide thread id.
+//! On wasm, it relies on std to provide thread id, unless the host provides
+//! its own through `set_thread_id_source`.
+//! Other providers of slots can be plugged in through the `Backend` trait,
+//! such as `SharedKeyBackend`, which serves any number of instances without
+//! a platform key each.
+//! With the `registry-backend` feature, no platform keys are used: each
+//! `ThreadLocal` keeps its slots in its own table indexed by thread.
 //!
 //! # Examples
 //!
@@ -38,35 +46,425 @@
 //!
 //! static TLS: ThreadLocal<u32> = ThreadLocal::const_new(5);
 //!
-//! assert_eq!(TLS.get(), 5);
+//! assert_eq!(TLS.get_copied(), 5);
+//!
+//! let mut tls: ThreadLocal<u32> = ThreadLocal::const_new(5);
+//! tls.set(6);
+//! assert_eq!(tls.get_copied(), 6);
+//! ```
+//!
+//! Small `Copy` values created by `const_new` are stored directly in the
+//! platform key slot, so reading them through `get_copied` and writing them
+//! through `set_copied`, `update_copied` or `set` never allocates, even in a
+//! static. `get` and `Deref` hand out a reference, which needs an address,
+//! so they move the value of the thread to the heap first.
+//!
+//! ```rust
+//! use lazy_thread_local::ThreadLocal;
+//!
+//! static COUNTER: ThreadLocal<u32> = ThreadLocal::const_new(0);
+//!
+//! COUNTER.update_copied(|n| n + 1);
+//! assert_eq!(COUNTER.get_copied(), 1);
+//! ```
+//!
+//! Iterating the values of all threads:
+//!
+//! ```rust
+//! use lazy_thread_local::ThreadLocal;
+//!
+//! let mut tls: ThreadLocal<u32> = ThreadLocal::new(|| 1);
+//!
+//! tls.get();
+//! std::thread::scope(|s| {
+//!     s.spawn(|| tls.get());
+//!     s.spawn(|| tls.get());
+//! });
+//!
+//! assert_eq!(tls.iter().sum::<u32>(), 3);
+//! ```
+//!
+//! `iter` borrows the `ThreadLocal` uniquely, so no thread can free its value
+//! while the references are alive. `for_each` visits the values through a
+//! shared reference instead, holding the registry lock meanwhile.
+//!
+//! Sharing state between the values of all threads:
+//!
+//! The initialiser may capture an `Arc` and hand each thread a clone of it.
+//! Every value then points at the same state, which threads update through
+//! atomics or locks while `for_each` observes it from any thread.
+//!
+//! ```rust
+//! use lazy_thread_local::ThreadLocal;
+//! use std::sync::atomic::{AtomicU64, Ordering};
+//! use std::sync::Arc;
+//!
+//! let counter = Arc::new(AtomicU64::new(0));
+//! let shared = counter.clone();
+//! let tls: ThreadLocal<Arc<AtomicU64>> = ThreadLocal::new(move || shared.clone());
+//!
+//! std::thread::scope(|s| {
+//!     s.spawn(|| tls.get().fetch_add(1, Ordering::Relaxed));
+//!     s.spawn(|| tls.get().fetch_add(1, Ordering::Relaxed));
+//! });
+//!
+//! tls.for_each(|_, _, value| assert!(Arc::ptr_eq(value, &counter)));
+//! assert_eq!(counter.load(Ordering::Relaxed), 2);
+//! ```
+//!
+//! Migrating from `std::thread_local!`:
+//!
+//! A `ThreadLocal` in a static takes the place of a `thread_local!` block,
+//! and `with` has the signature of `LocalKey::with`, so uses of the key keep
+//! compiling. `const_new` and `const_new_cloned` create the static in
+//! constant context, for `Copy` and `Clone` values respectively.
+//!
+//! ```rust
+//! use lazy_thread_local::ThreadLocal;
+//! use std::cell::Cell;
+//!
+//! // thread_local! {
+//! //     static COUNTER: Cell<u32> = const { Cell::new(0) };
+//! // }
+//! static COUNTER: ThreadLocal<Cell<u32>> = ThreadLocal::const_new_cloned(Cell::new(0));
+//!
+//! fn bump() -> u32 {
+//!     COUNTER.with(|counter| {
+//!         counter.set(counter.get() + 1);
+//!         counter.get()
+//!     })
+//! }
+//!
+//! assert_eq!(bump(), 1);
+//! assert_eq!(std::thread::spawn(bump).join().unwrap(), 1);
+//! assert_eq!(bump(), 2);
 //! ```
 //!
+//! Unlike `thread_local!`, the value of an exited thread is kept until the
+//! `ThreadLocal` is dropped, which a static never is, and stays visible to
+//! `iter`. `enable_cleanup` drops values as their threads exit instead.
+//!
 
-use core::marker::PhantomData;
-use core::sync::atomic::{AtomicBool, Ordering};
+#![allow(clippy::needless_return)]
 
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::marker::{PhantomData, PhantomPinned};
+use core::mem::MaybeUninit;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+mod array;
+#[cfg(unix)]
+mod atexit;
+mod borrow;
+mod cached;
+mod cleanup;
+#[cfg(all(windows, feature = "dll-detach"))]
+mod detach;
+mod detached;
+mod dyn_ref;
+mod epoch;
+mod error;
+#[cfg(all(unix, feature = "fork"))]
+mod fork;
+#[cfg(feature = "debug-guards")]
+mod guards;
+#[cfg(all(unix, feature = "huge-pages"))]
+mod huge;
+mod inline;
+#[cfg(feature = "metrics")]
+mod key_usage;
+mod log;
+#[cfg(feature = "mock")]
+pub mod mock;
+#[cfg(all(unix, feature = "numa"))]
+mod numa;
+mod registry;
+mod scoped;
+mod sharded;
+mod shared_key;
+#[cfg(feature = "serde")]
+mod snapshot;
+#[cfg(feature = "registry-backend")]
+mod table;
+#[cfg(test)]
+mod testing;
+mod thread_id;
+mod trace;
 #[cfg(target_family = "wasm")]
 mod wasm32;
 
-pub trait ThreadLocalInitialiser<T>: Sized {
+pub use array::{ElementInitialiser, SeedArray, ThreadLocalArray};
+pub use borrow::{Ref, RefMut};
+pub use cached::Cached;
+pub use cleanup::supports_thread_exit_cleanup;
+pub use detached::Detached;
+pub use dyn_ref::{ThreadLocalMut, ThreadLocalRef};
+pub use epoch::{EpochRef, EpochThreadLocal};
+pub use error::ThreadLocalError;
+pub use inline::InlineThreadLocal;
+#[cfg(feature = "metrics")]
+pub use key_usage::{global_key_usage, KeyUsage};
+pub use log::ThreadLocalLog;
+#[cfg(all(unix, feature = "numa"))]
+pub use numa::NumaAllocator;
+#[cfg(feature = "metrics")]
+pub use registry::ContentionStats;
+pub use registry::LockedRef;
+use registry::{Registry, State};
+pub use scoped::ScopedThreadLocal;
+pub use sharded::ShardedThreadLocal;
+pub use shared_key::SharedKeyBackend;
+#[cfg(feature = "serde")]
+pub use snapshot::SnapshotSerialize;
+pub use thread_id::{set_thread_id_source, StdThreadIdSource, ThreadId, ThreadIdSource};
+
+/// Produces the value of a thread on its first access.
+///
+/// an initialiser may access other thread locals, whose values for the
+/// thread are initialised first if needed. values are thus initialised in
+/// the order initialisers reach them, each `ThreadLocal` on its own, so
+/// nesting never deadlocks. only accessing the thread local being
+/// initialised fails, with `ThreadLocalError::RecursiveInit`.
+///
+/// the initialiser runs on whichever thread first accesses its value, and
+/// is dropped on the thread dropping the `ThreadLocal`, hence the `Send`
+/// and `Sync` bounds. an initialiser capturing an `Rc` is rejected.
+pub trait ThreadLocalInitialiser<T>: Sized + Send + Sync {
     fn init(&self) -> T;
 }
 
 impl<F, T> ThreadLocalInitialiser<T> for F
 where
-    F: Fn() -> T,
+    F: Fn() -> T + Send + Sync,
 {
     fn init(&self) -> T {
         self()
     }
 }
 
+/// Provides the memory of values and boxed initialisers.
+///
+/// the destructor of a value may itself allocate with the same allocator:
+/// every value is dropped in full before its own block is freed, and the
+/// block of the initialiser is only freed once all values are gone.
 pub trait Allocator {
+    /// bytes the allocator keeps in front of every block, counted by
+    /// `ThreadLocal::storage_layout`.
+    const HEADER: usize = 0;
+
     fn allocate(size: usize) -> *mut u8;
     fn deallocate(ptr: *mut u8);
+
+    /// allocates `size` bytes set to zero.
+    fn allocate_zeroed(size: usize) -> *mut u8 {
+        let ptr = Self::allocate(size);
+        if !ptr.is_null() {
+            unsafe { ptr.write_bytes(0, size) };
+        }
+        return ptr;
+    }
+}
+
+/// Thread local slots holding one pointer per thread and key.
+///
+/// A `ThreadLocal` keeps a pointer to the value of each thread in the slot
+/// of a key it creates, while the values themselves are owned by its
+/// registry, so slots need no destructor. The default backend uses pthread
+/// keys on Unix, fiber local storage on Windows, and an emulation on wasm.
+///
+/// # Safety
+///
+/// `create` must return a key whose slot is null on every thread, `get`
+/// must return what the calling thread last stored with `set` for the key,
+/// and slots of different keys or threads must never alias.
+pub unsafe trait Backend {
+    type Key: Copy;
+
+    /// creates a key.
+    ///
+    /// # Safety
+    ///
+    /// the key must eventually be deleted with `delete`, or leaked.
+    unsafe fn create() -> Result<Self::Key, ThreadLocalError>;
+
+    /// returns the slot of the calling thread.
+    ///
+    /// # Safety
+    ///
+    /// `key` must have been created by `create` and not deleted yet.
+    unsafe fn get(key: Self::Key) -> *mut u8;
+
+    /// writes the slot of the calling thread.
+    ///
+    /// # Safety
+    ///
+    /// as for `get`.
+    unsafe fn set(key: Self::Key, value: *mut u8);
+
+    /// deletes a key, forgetting the slots of every thread.
+    ///
+    /// # Safety
+    ///
+    /// as for `get`, and the key must not be used afterwards.
+    unsafe fn delete(key: Self::Key);
+}
+
+/// Types for which all zero bytes is a valid value.
+///
+/// The values of a `ThreadLocal` created by `new_zeroed` are obtained from
+/// `Allocator::allocate_zeroed` without running an initialiser.
+///
+/// # Safety
+///
+/// the all zero bit pattern must be a valid value of the type.
+pub unsafe trait Zeroable {}
+
+macro_rules! impl_zeroable {
+    ($($t:ty),*) => {
+        $(unsafe impl Zeroable for $t {})*
+    };
+}
+
+impl_zeroable!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool, char
+);
+
+unsafe impl<T: Zeroable, const N: usize> Zeroable for [T; N] {}
+
+/// Collections that can be created with room for a number of elements.
+///
+/// the values of a `ThreadLocal` created by `with_capacity_init` start out
+/// with the given capacity.
+pub trait WithCapacity {
+    fn with_capacity(capacity: usize) -> Self;
+}
+
+impl<T> WithCapacity for Vec<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        Vec::with_capacity(capacity)
+    }
+}
+
+impl<T> WithCapacity for std::collections::VecDeque<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        std::collections::VecDeque::with_capacity(capacity)
+    }
+}
+
+impl<T: Ord> WithCapacity for std::collections::BinaryHeap<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        std::collections::BinaryHeap::with_capacity(capacity)
+    }
+}
+
+impl WithCapacity for String {
+    fn with_capacity(capacity: usize) -> Self {
+        String::with_capacity(capacity)
+    }
+}
+
+impl<K, V, S: Default> WithCapacity for std::collections::HashMap<K, V, S> {
+    fn with_capacity(capacity: usize) -> Self {
+        std::collections::HashMap::with_capacity_and_hasher(capacity, S::default())
+    }
+}
+
+impl<T, S: Default> WithCapacity for std::collections::HashSet<T, S> {
+    fn with_capacity(capacity: usize) -> Self {
+        std::collections::HashSet::with_capacity_and_hasher(capacity, S::default())
+    }
 }
 
 mod private {
+    #[cfg(any(unix, windows))]
+    use crate::ThreadLocalError;
+
+    #[cfg(target_family = "unix")]
+    pub type DefaultBackend = Pthread;
+
+    #[cfg(target_family = "unix")]
+    pub struct Pthread;
+
+    #[cfg(target_family = "unix")]
+    unsafe impl super::Backend for Pthread {
+        type Key = libc::pthread_key_t;
+
+        unsafe fn create() -> Result<Self::Key, ThreadLocalError> {
+            // values are owned by the registry, so the key needs no destructor
+            let mut key: libc::pthread_key_t = 0;
+            let re = libc::pthread_key_create(&mut key, None);
+
+            if re != 0 {
+                return Err(ThreadLocalError::KeyCreate(re));
+            }
+
+            #[cfg(feature = "metrics")]
+            crate::key_usage::created();
+            return Ok(key);
+        }
+
+        unsafe fn get(key: Self::Key) -> *mut u8 {
+            libc::pthread_getspecific(key) as *mut u8
+        }
+
+        unsafe fn set(key: Self::Key, value: *mut u8) {
+            libc::pthread_setspecific(key, value as _);
+        }
+
+        unsafe fn delete(key: Self::Key) {
+            libc::pthread_key_delete(key);
+
+            #[cfg(feature = "metrics")]
+            crate::key_usage::recycled();
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    pub type DefaultBackend = Fls;
+
+    #[cfg(target_os = "windows")]
+    pub struct Fls;
+
+    #[cfg(target_os = "windows")]
+    unsafe impl super::Backend for Fls {
+        type Key = winapi::shared::minwindef::DWORD;
+
+        unsafe fn create() -> Result<Self::Key, ThreadLocalError> {
+            // values are owned by the registry, so the key needs no destructor
+            let key = winapi::um::fibersapi::FlsAlloc(None);
+
+            // FLS_OUT_OF_INDEXES
+            if key == Self::Key::MAX {
+                let code = winapi::um::errhandlingapi::GetLastError();
+                return Err(ThreadLocalError::KeyCreate(code as i32));
+            }
+
+            #[cfg(feature = "metrics")]
+            crate::key_usage::created();
+            return Ok(key);
+        }
+
+        unsafe fn get(key: Self::Key) -> *mut u8 {
+            winapi::um::fibersapi::FlsGetValue(key) as *mut u8
+        }
+
+        unsafe fn set(key: Self::Key, value: *mut u8) {
+            winapi::um::fibersapi::FlsSetValue(key, value as _);
+        }
+
+        unsafe fn delete(key: Self::Key) {
+            winapi::um::fibersapi::FlsFree(key);
+
+            #[cfg(feature = "metrics")]
+            crate::key_usage::recycled();
+        }
+    }
+
+    #[cfg(target_family = "wasm")]
+    pub type DefaultBackend = crate::wasm32::Emulated;
+
     #[cfg(any(unix, windows))]
     pub type DefaultAllocator = CAllocator;
 
@@ -76,11 +474,31 @@ mod private {
     #[cfg(any(unix, windows))]
     impl super::Allocator for CAllocator {
         fn allocate(size: usize) -> *mut u8 {
+            #[cfg(all(unix, feature = "huge-pages"))]
+            if size >= crate::huge::THRESHOLD {
+                if let Some(ptr) = crate::huge::map(size) {
+                    return ptr;
+                }
+            }
             unsafe { libc::malloc(size) as *mut u8 }
         }
         fn deallocate(ptr: *mut u8) {
+            #[cfg(all(unix, feature = "huge-pages"))]
+            if crate::huge::unmap(ptr) {
+                return;
+            }
             unsafe { libc::free(ptr as _) };
         }
+        fn allocate_zeroed(size: usize) -> *mut u8 {
+            // fresh mappings are zeroed
+            #[cfg(all(unix, feature = "huge-pages"))]
+            if size >= crate::huge::THRESHOLD {
+                if let Some(ptr) = crate::huge::map(size) {
+                    return ptr;
+                }
+            }
+            unsafe { libc::calloc(1, size) as *mut u8 }
+        }
     }
 
     #[cfg(not(any(unix, windows)))]
@@ -89,12 +507,22 @@ mod private {
     #[cfg(not(any(unix, windows)))]
     pub struct RAllocator;
 
+    #[cfg(not(any(unix, windows)))]
+    impl RAllocator {
+        /// the size header is a usize, so blocks are usize aligned.
+        fn layout(size: usize) -> std::alloc::Layout {
+            std::alloc::Layout::from_size_align(size, core::mem::align_of::<usize>()).unwrap()
+        }
+    }
+
     #[cfg(not(any(unix, windows)))]
     impl super::Allocator for RAllocator {
+        const HEADER: usize = core::mem::size_of::<usize>();
+
         fn allocate(size: usize) -> *mut u8 {
             let new_size = size + core::mem::size_of::<usize>();
             unsafe {
-                let ptr = std::alloc::alloc(std::alloc::Layout::array::<u8>(new_size).unwrap());
+                let ptr = std::alloc::alloc(Self::layout(new_size));
 
                 let ptr = ptr as *mut usize;
                 ptr.write(new_size);
@@ -108,291 +536,4986 @@ mod private {
                 let ptr = (ptr as *mut usize).sub(1);
                 let len = ptr.read();
 
-                std::alloc::dealloc(ptr as _, std::alloc::Layout::array::<u8>(len).unwrap());
+                std::alloc::dealloc(ptr as _, Self::layout(len));
             }
         }
     }
 }
 
+/// the platform key type, see `ThreadLocal::from_raw_key`.
 #[cfg(target_family = "unix")]
-type Key = libc::pthread_key_t;
+pub type Key = libc::pthread_key_t;
 
+/// the platform key type, see `ThreadLocal::from_raw_key`.
 #[cfg(windows)]
-type Key = winapi::shared::minwindef::DWORD;
+pub type Key = winapi::shared::minwindef::DWORD;
 
+/// the platform key type, see `ThreadLocal::from_raw_key`.
 #[cfg(target_family = "wasm")]
-type Key = usize;
+pub type Key = usize;
 
-pub struct ThreadLocal<T, A: Allocator = private::DefaultAllocator> {
-    key_created: AtomicBool,
-    key: Key,
-    initiatiser: *mut u8,
-    initialiser_drop: fn(*mut u8),
-    initialiser_init: fn(*mut u8) -> T,
-    const_init: Option<T>,
-    _mark: PhantomData<A>,
+/// marks a key slot holding an inline value rather than a heap pointer.
+/// values that fit inline get allocations of at least a `usize`, which
+/// allocators align to a `usize`, so the lowest bit is free.
+const INLINE_TAG: usize = 1;
+
+/// the key of a const instance has not been created yet.
+const KEY_NONE: u8 = 0;
+/// a thread is creating the key of a const instance.
+const KEY_CREATING: u8 = 1;
+/// the key is ready to use.
+const KEY_READY: u8 = 2;
+
+/// room for an initialiser stored in place, two words so that shared
+/// trait objects such as `Arc<dyn Fn() -> T>` fit.
+type InitialiserSlot = [*mut u8; 2];
+
+/// whether an initialiser can be stored in place of the pointer to it.
+const fn initialiser_fits<I>() -> bool {
+    core::mem::size_of::<I>() <= core::mem::size_of::<InitialiserSlot>()
+        && core::mem::align_of::<I>() <= core::mem::align_of::<InitialiserSlot>()
 }
 
-#[cfg(target_family = "unix")]
-impl<T, A: Allocator> ThreadLocal<T, A> {
-    unsafe fn create_key() -> Key {
-        unsafe extern "C" fn dtor<T, A: Allocator>(ptr: *mut libc::c_void) {
-            if ptr.is_null() {
-                return;
-            }
-            let ptr = ptr as *mut T;
-            core::ptr::drop_in_place(ptr);
-            A::deallocate(ptr as _);
+/// drop function wrapper, `slot` points to the `initiatiser` field
+fn initialiser_drop<I: ThreadLocalInitialiser<T>, T, A: Allocator>(slot: *mut u8) {
+    unsafe {
+        if initialiser_fits::<I>() {
+            core::ptr::drop_in_place(slot as *mut I);
+            return;
         }
 
-        let mut key: libc::pthread_key_t = 0;
-        let re = libc::pthread_key_create(&mut key, Some(dtor::<T, A>));
-
-        assert_eq!(re, 0);
+        let ptr = *(slot as *mut *mut I);
+        if ptr.is_null() {
+            return;
+        }
+        core::ptr::drop_in_place(ptr);
+        A::deallocate(ptr as _);
+    };
+}
 
-        return key;
+/// init function wrapper, `slot` points to the `initiatiser` field
+fn initialiser_init<I: ThreadLocalInitialiser<T>, T>(slot: *mut u8) -> T {
+    unsafe {
+        if initialiser_fits::<I>() {
+            return (*(slot as *const I)).init();
+        }
+        return (**(slot as *const *const I)).init();
     }
+}
 
-    unsafe fn get_key(key: Key) -> *mut T {
-        libc::pthread_getspecific(key) as *mut T
-    }
+/// stores `init` in place if it fits, otherwise behind a pointer allocated
+/// with `A`. returns `None` if the allocation fails.
+unsafe fn store_initialiser<I, A: Allocator>(init: I) -> Option<MaybeUninit<InitialiserSlot>> {
+    let mut slot = MaybeUninit::<InitialiserSlot>::uninit();
 
-    unsafe fn set_key(key: Key, value: *mut T) {
-        libc::pthread_setspecific(key, value as _);
+    if initialiser_fits::<I>() {
+        (slot.as_mut_ptr() as *mut I).write(init);
+        return Some(slot);
     }
 
-    unsafe fn delete_key(key: Key) {
-        libc::pthread_key_delete(key);
+    let init_ptr = A::allocate(core::mem::size_of::<I>()) as *mut I;
+    if init_ptr.is_null() {
+        return None;
     }
+    init_ptr.write(init);
+    (slot.as_mut_ptr() as *mut *mut I).write(init_ptr);
+    return Some(slot);
 }
 
-#[cfg(target_os = "windows")]
-impl<T, A: Allocator> ThreadLocal<T, A> {
-    unsafe fn create_key() -> Key {
-        unsafe extern "system" fn dtor<T, A: Allocator>(ptr: winapi::um::winnt::PVOID) {
-            if ptr.is_null() {
-                return;
-            }
-            let ptr = ptr as *mut T;
-            core::ptr::drop_in_place(ptr);
-            A::deallocate(ptr as _);
-        }
-        winapi::um::fibersapi::FlsAlloc(Some(dtor::<T, A>))
-    }
+/// whether `T` can be packed into a key slot alongside the inline tag.
+///
+/// the value sits at an unaligned offset inside the word, so values more
+/// aligned than a pointer go to the heap, where `A` provides the alignment.
+const fn inline_fits<T>() -> bool {
+    core::mem::size_of::<T>() < core::mem::size_of::<usize>()
+        && core::mem::align_of::<T>() <= core::mem::align_of::<usize>()
+}
+
+/// byte offset of the value inside the slot word, chosen so that the most
+/// significant byte stays free for the tag shift.
+const INLINE_OFFSET: usize = if cfg!(target_endian = "big") { 1 } else { 0 };
+
+/// packs a small value into a tagged slot word.
+unsafe fn pack_inline<T>(value: T) -> usize {
+    let mut word: usize = 0;
+    // padding bytes of `T` are left zeroed in the word
+    ((&mut word as *mut usize as *mut u8).add(INLINE_OFFSET) as *mut T).write_unaligned(value);
+    return (word << 1) | INLINE_TAG;
+}
 
-    unsafe fn get_key(key: Key) -> *mut T {
-        winapi::um::fibersapi::FlsGetValue(key) as *mut T
+/// reads a value back out of a tagged slot word.
+unsafe fn unpack_inline<T>(word: usize) -> T {
+    let word = word >> 1;
+    // read into an aligned local, the offset may not suit `T`
+    return ((&word as *const usize as *const u8).add(INLINE_OFFSET) as *const T).read_unaligned();
+}
+
+/// the size requested from the allocator for a value.
+const fn value_size<T>() -> usize {
+    #[cfg(feature = "debug-guards")]
+    let size = guards::size::<T>();
+    #[cfg(not(feature = "debug-guards"))]
+    let size = core::mem::size_of::<T>();
+
+    // C allocators may align smaller blocks to their size only
+    if inline_fits::<T>() && size < core::mem::size_of::<usize>() {
+        return core::mem::size_of::<usize>();
     }
+    return size;
+}
 
-    unsafe fn set_key(key: Key, value: *mut T) {
-        winapi::um::fibersapi::FlsSetValue(key, value as _);
+/// the per-thread storage of a value.
+///
+/// references to a value are always derived from its cell, never from one
+/// another, so shared and exclusive borrows of one value may alternate
+/// under stacked and tree borrows. the value pointers kept in slots and
+/// in the registry point at the cell, which has the layout of `T`.
+pub(crate) type Storage<T> = UnsafeCell<T>;
+
+/// returns the value behind a value pointer, through its storage cell.
+pub(crate) unsafe fn cell_value<T>(ptr: *mut T) -> *mut T {
+    return (*(ptr as *const Storage<T>)).get();
+}
+
+/// allocates the storage of a value with `A`, set to zero if `zeroed`,
+/// returning a pointer to the value.
+///
+/// with `debug-guards`, the storage is surrounded by canaries.
+unsafe fn allocate_value<T, A: Allocator>(zeroed: bool) -> *mut T {
+    let size = value_size::<T>();
+    let ptr = if zeroed {
+        A::allocate_zeroed(size)
+    } else {
+        A::allocate(size)
+    };
+    if ptr.is_null() {
+        return core::ptr::null_mut();
     }
 
-    unsafe fn delete_key(key: Key) {
-        winapi::um::fibersapi::FlsFree(key);
+    #[cfg(feature = "debug-guards")]
+    let storage: *mut Storage<T> = guards::arm(ptr);
+    #[cfg(not(feature = "debug-guards"))]
+    let storage = ptr as *mut Storage<T>;
+    return UnsafeCell::raw_get(storage);
+}
+
+/// drops and frees a value of the current thread taken out of its slot,
+/// unless forgotten, e.g. when an initialiser replacing it panics.
+struct FreeOnPanic<T, A: Allocator>(*mut T, PhantomData<A>);
+
+impl<T, A: Allocator> Drop for FreeOnPanic<T, A> {
+    fn drop(&mut self) {
+        unsafe { ThreadLocal::<T, A>::free_value(self.0, ThreadId::current()) };
     }
 }
 
+/// frees a value allocated by `allocate_value` with `A`, without dropping it.
+unsafe fn deallocate_value<T, A: Allocator>(ptr: *mut T) {
+    #[cfg(feature = "debug-guards")]
+    A::deallocate(guards::base(ptr));
+    #[cfg(not(feature = "debug-guards"))]
+    A::deallocate(ptr as _);
+}
+
+/// the limits on new values of a `ThreadLocal`.
+struct Limits {
+    /// the number of threads allowed to hold a value.
+    max_threads: AtomicUsize,
+    /// the bytes `allocated_bytes` may reach, see `set_memory_budget`.
+    memory_budget: AtomicUsize,
+    /// set by `freeze`, threads without a value may not initialise one.
+    frozen: AtomicBool,
+}
+
+/// Per-object thread-local storage.
+///
+/// # Drop order
+///
+/// When a `ThreadLocal` is dropped, the values of all threads are dropped
+/// first, then the initialiser, and finally the platform key is deleted.
+/// Values may therefore rely on resources captured by the initialiser.
+///
+/// A value may itself be a `ThreadLocal`, as in `ThreadLocal<ThreadLocal<T>>`.
+/// The inner instances are then dropped with the outer one, each deleting
+/// its own key and dropping the values every thread stored in it, so no
+/// key or value outlives the outer `ThreadLocal`. Inner instances are
+/// dropped on the thread dropping the outer one, which on wasm must be the
+/// thread that created them.
+pub struct ThreadLocal<
+    T,
+    A: Allocator = private::DefaultAllocator,
+    B: Backend = private::DefaultBackend,
+> {
+    key_state: AtomicU8,
+    /// only initialised once `key_state` is `KEY_READY`. const instances
+    /// write it through `&self`, hence the cell.
+    key: UnsafeCell<MaybeUninit<B::Key>>,
+    /// makes sure the key is ready, picked at construction so that
+    /// instances whose key was created eagerly, as for `new` without
+    /// `strictly-lazy`, never check `key_state`.
+    check_key: fn(&Self) -> Result<(), ThreadLocalError>,
+    /// the initialiser itself if it fits, otherwise a pointer to it.
+    initiatiser: MaybeUninit<InitialiserSlot>,
+    initialiser_drop: fn(*mut u8),
+    /// set once the initialiser is dropped, so it is dropped exactly once.
+    initialiser_dropped: AtomicBool,
+    initialiser_init: fn(*mut u8) -> T,
+    const_init: Option<T>,
+    /// clones `const_init` for a new thread, `None` if it is copied bitwise.
+    seed_clone: Option<fn(&T) -> T>,
+    /// returned by `get` when a value fails to allocate, null to panic.
+    fallback: *const T,
+    /// values older than this are reinitialised on access, see `new_with_ttl`.
+    ttl: Option<core::time::Duration>,
+    /// values are zeroed allocations rather than produced by the initialiser.
+    zeroed: bool,
+    /// the key was created by the `ThreadLocal`, rather than adopted.
+    owns_key: bool,
+    registry: Registry<T>,
+    /// bumped by `invalidate`, values of older generations are stale.
+    generation: AtomicUsize,
+    /// bumped whenever a thread gives up its value, see `Cached`.
+    releases: AtomicUsize,
+    /// set by `prime_all` once the key is known to exist.
+    assume_primed: AtomicBool,
+    /// allocated by the first `set_max_threads`, `set_memory_budget` or
+    /// `freeze`, so instances without limits do not carry them.
+    limits: std::sync::OnceLock<Box<Limits>>,
+    /// the value produced by `get_or_init_shared`, cloned by every thread.
+    shared: std::sync::OnceLock<Box<T>>,
+    #[cfg(feature = "registry-backend")]
+    table: table::Table,
+    /// the id given by `enable_cleanup`, 0 while cleanup is off.
+    cleanup_id: AtomicU64,
+    /// exiting threads currently dropping their value.
+    cleanup_users: AtomicUsize,
+    /// the thread that constructed the instance at runtime, 0 for const
+    /// instances. the emulated keys must be deleted from that thread.
+    #[cfg(all(target_family = "wasm", not(feature = "registry-backend")))]
+    owner: AtomicU64,
+    _mark: PhantomData<(A, B)>,
+    /// `enable_cleanup` relies on the address staying fixed once pinned.
+    _pinned: PhantomPinned,
+}
+
 impl<T: Copy> ThreadLocal<T> {
     /// initialise the thread local with a copyable value.
+    ///
+    /// each thread copies the value on first access. with the
+    /// `large-copy-check` feature, values larger than a page are rejected at
+    /// compile time in favour of `new`.
     pub const fn const_new(value: T) -> Self {
         Self::const_new_in(value)
     }
 }
 
-impl<T: Copy, A: Allocator> ThreadLocal<T, A> {
+/// the largest value `const_new` accepts with `large-copy-check`, since
+/// every thread copies it from the `ThreadLocal`.
+#[cfg(feature = "large-copy-check")]
+const CONST_COPY_LIMIT: usize = 4096;
+
+impl<T: Copy, A: Allocator, B: Backend> ThreadLocal<T, A, B> {
     pub const fn const_new_in(value: T) -> Self {
-        // a placeholder function
-        fn dummy_drop(_: *mut u8) {
-            // does nothing
-        }
+        #[cfg(feature = "large-copy-check")]
+        const {
+            assert!(
+                core::mem::size_of::<T>() <= CONST_COPY_LIMIT,
+                "const_new copies its value into every thread, use `new` for values larger than a page"
+            )
+        };
+
         // should never be called
         fn dummy_init<T>(_: *mut u8) -> T {
             unreachable!()
         }
 
-        Self {
-            key: 0,
-            key_created: AtomicBool::new(false),
-            initiatiser: 0 as _,
-            initialiser_drop: dummy_drop,
-            initialiser_init: dummy_init::<T>,
-            const_init: Some(value),
-            _mark: PhantomData,
-        }
+        Self::const_with(Some(value), None, dummy_init::<T>)
     }
 }
 
-impl<T> ThreadLocal<T> {
-    pub fn new<I: ThreadLocalInitialiser<T>>(init: I) -> Self {
-        Self::new_in(init)
+impl<T: Clone> ThreadLocal<T> {
+    /// like `const_new`, but each thread clones the value on first access.
+    ///
+    /// the value itself is dropped once, with the thread local, and the
+    /// clones of each thread are dropped like any other value.
+    pub const fn const_new_cloned(value: T) -> Self {
+        Self::const_new_cloned_in(value)
     }
 }
 
-impl<T, A: Allocator> ThreadLocal<T, A> {
-    pub fn new_in<I: ThreadLocalInitialiser<T>>(init: I) -> Self {
-        // drop function wrapper
-        fn initialiser_drop<I: ThreadLocalInitialiser<T>, T, A: Allocator>(ptr: *mut u8) {
-            if ptr.is_null() {
-                return;
-            }
-            let ptr = ptr as *mut I;
-            unsafe {
-                core::ptr::drop_in_place(ptr);
-                A::deallocate(ptr as _);
-            };
+impl<T: Clone, A: Allocator, B: Backend> ThreadLocal<T, A, B> {
+    pub const fn const_new_cloned_in(value: T) -> Self {
+        // should never be called
+        fn dummy_init<T>(_: *mut u8) -> T {
+            unreachable!()
         }
 
-        // init function wrapper
-        fn initialiser_init<I: ThreadLocalInitialiser<T>, T>(ptr: *mut u8) -> T {
-            let ptr = ptr as *mut I;
-            unsafe { ptr.as_mut().unwrap_unchecked().init() }
-        }
+        Self::const_with(Some(value), Some(T::clone), dummy_init::<T>)
+    }
+}
 
-        unsafe {
-            let key = Self::create_key();
+impl<T, A: Allocator, B: Backend> ThreadLocal<T, A, B> {
+    /// the common const constructor. values are copied from `const_init` if
+    /// it is set, with `seed_clone` or bitwise, otherwise produced by `init`,
+    /// which is given no initialiser.
+    pub(crate) const fn const_with(
+        const_init: Option<T>,
+        seed_clone: Option<fn(&T) -> T>,
+        init: fn(*mut u8) -> T,
+    ) -> Self {
+        // a placeholder function
+        fn dummy_drop(_: *mut u8) {
+            // does nothing
+        }
 
-            let ptr = A::allocate(core::mem::size_of::<T>()) as *mut T;
-            ptr.write(init.init());
+        // bitwise copies of a seed with drop glue would each drop what it owns
+        assert!(
+            const_init.is_none() || seed_clone.is_some() || !core::mem::needs_drop::<T>(),
+            "const seeds with drop glue must be cloned"
+        );
 
-            Self::set_key(key, ptr);
+        Self {
+            key: UnsafeCell::new(MaybeUninit::uninit()),
+            key_state: AtomicU8::new(KEY_NONE),
+            check_key: Self::key_check(false),
+            initiatiser: MaybeUninit::new([core::ptr::null_mut(); 2]),
+            initialiser_drop: dummy_drop,
+            initialiser_dropped: AtomicBool::new(false),
+            initialiser_init: init,
+            const_init,
+            seed_clone,
+            fallback: core::ptr::null(),
+            ttl: None,
+            zeroed: false,
+            owns_key: true,
+            registry: Registry::new(),
+            generation: AtomicUsize::new(0),
+            releases: AtomicUsize::new(0),
+            assume_primed: AtomicBool::new(false),
+            limits: std::sync::OnceLock::new(),
+            shared: std::sync::OnceLock::new(),
+            #[cfg(feature = "registry-backend")]
+            table: table::Table::new(),
+            cleanup_id: AtomicU64::new(0),
+            cleanup_users: AtomicUsize::new(0),
+            #[cfg(all(target_family = "wasm", not(feature = "registry-backend")))]
+            owner: AtomicU64::new(0),
+            _mark: PhantomData,
+            _pinned: PhantomPinned,
+        }
+    }
+}
 
-            let init_ptr = A::allocate(core::mem::size_of::<I>()) as *mut I;
-            init_ptr.write(init);
+impl<T> ThreadLocal<T> {
+    /// creates a thread local whose values are produced by `init`.
+    ///
+    /// the value of the constructing thread is initialised eagerly, unless
+    /// the `strictly-lazy` feature is enabled, in which case construction
+    /// runs no code and performs no allocation at all.
+    pub fn new<I: ThreadLocalInitialiser<T> + 'static>(init: I) -> Self {
+        Self::new_in(init)
+    }
 
-            return Self {
-                key,
-                key_created: AtomicBool::new(true),
-                initiatiser: init_ptr as _,
-                initialiser_drop: initialiser_drop::<I, T, A>,
-                initialiser_init: initialiser_init::<I, T>,
-                const_init: None,
-                _mark: PhantomData,
-            };
-        }
+    /// creates a thread local running a factory shared with other instances.
+    ///
+    /// the `Arc` is stored in place of the initialiser, so instances built
+    /// from clones of one factory allocate nothing for their initialiser.
+    pub fn new_from_factory(factory: std::sync::Arc<dyn Fn() -> T + Send + Sync>) -> Self
+    where
+        T: 'static,
+    {
+        Self::new(move || factory())
     }
 
-    #[allow(invalid_reference_casting)]
-    fn check_init(&self) {
-        if self.const_init.is_some() {
-            if !self.key_created.swap(true, Ordering::SeqCst) {
-                unsafe {
-                    let key = Self::create_key();
-                    *(&self.key as *const Key as *mut Key) = key;
-                }
-            }
-        }
+    /// creates a thread local whose initialiser borrows from the enclosing
+    /// scope, for use with `std::thread::scope`.
+    pub fn new_scoped<'env, I: ThreadLocalInitialiser<T> + 'env>(
+        init: I,
+    ) -> ScopedThreadLocal<'env, T> {
+        ScopedThreadLocal::new_in(init)
     }
 
-    unsafe fn init_value(&self) -> &mut T {
-        let ptr = A::allocate(core::mem::size_of::<T>()) as *mut T;
+    /// creates a thread local split into `shards` shards with their own
+    /// registry, so threads of different shards never contend on a lock.
+    pub fn sharded<I>(init: I, shards: usize) -> ShardedThreadLocal<T>
+    where
+        I: ThreadLocalInitialiser<T> + Send + Sync + 'static,
+    {
+        ShardedThreadLocal::new_in(init, shards)
+    }
 
-        if let Some(v) = &self.const_init {
-            // it is guarantined T is copy
-            ptr.write(core::ptr::read(v));
-        } else {
-            ptr.write((self.initialiser_init)(self.initiatiser));
-        }
+    /// creates a thread local handing out `values[i]` to the `i`-th thread.
+    ///
+    /// threads are numbered in the order they initialise their value, which
+    /// with a fixed pool of workers is the order they first access it. the
+    /// constructing thread comes first unless `strictly-lazy` is enabled.
+    /// threads beyond the end of `values` get a clone of its last element,
+    /// as do threads that take or reinitialise their value.
+    ///
+    /// panics if `values` is empty.
+    pub fn new_indexed(values: Vec<T>) -> Self
+    where
+        T: Clone + Send + Sync,
+    {
+        assert!(!values.is_empty(), "new_indexed needs at least one value");
 
-        Self::set_key(self.key, ptr as _);
+        // boxed so the initialiser is stored in place
+        let indexed = Box::new((values, AtomicUsize::new(0)));
+        let init = move || {
+            let (values, next) = &*indexed;
+            let idx = next.fetch_add(1, Ordering::Relaxed).min(values.len() - 1);
+            return values[idx].clone();
+        };
 
-        return ptr.as_mut().unwrap_unchecked();
+        // the initialiser only borrows what `T` does, which outlives `Self`
+        Self::try_new_with(init, false, None).unwrap_or_else(|e| panic!("{}", e))
     }
 
-    pub fn get(&self) -> &T {
-        self.check_init();
+    /// creates a thread local whose initialiser sees the value last produced
+    /// for another thread, `None` for the first one.
+    ///
+    /// a clone of each new value is kept as the snapshot passed to the next
+    /// initialiser, which runs with the snapshot locked. which thread comes
+    /// next depends on when threads first access their value, so when
+    /// several threads initialise at once their order is unspecified.
+    /// changes made to a value after its initialisation are not seen.
+    pub fn new_chained<F>(init: F) -> Self
+    where
+        T: Clone + Send,
+        F: Fn(Option<&T>) -> T + Send + Sync + 'static,
+    {
+        // boxed so the initialiser is stored in place
+        let chained = Box::new((std::sync::Mutex::new(None::<T>), init));
+        let init = move || {
+            let (last, init) = &*chained;
+            let mut last = last.lock().unwrap_or_else(|e| e.into_inner());
 
-        unsafe {
-            let ptr = Self::get_key(self.key);
+            let value = init(last.as_ref());
+            *last = Some(value.clone());
+            return value;
+        };
 
-            if ptr.is_null() {
-                return self.init_value();
-            };
+        // the snapshot only holds what `T` does, which outlives `Self`
+        Self::try_new_with(init, false, None).unwrap_or_else(|e| panic!("{}", e))
+    }
 
-            return (ptr as *mut T).as_ref().unwrap_unchecked();
-        }
+    /// creates a thread local whose initialiser is given the name of the
+    /// thread it runs on, `None` for unnamed threads.
+    pub fn new_with_name<F: Fn(Option<&str>) -> T + Send + Sync + 'static>(init: F) -> Self {
+        Self::new(move || init(std::thread::current().name()))
     }
 
-    pub fn get_mut(&mut self) -> &mut T {
-        self.check_init();
+    /// creates a thread local whose values are derived from `config` by
+    /// `init`.
+    ///
+    /// the config is shared by every thread behind an `Arc`, so the
+    /// initialiser stays two words however large the config is.
+    pub fn new_from_config<C, F>(config: C, init: F) -> Self
+    where
+        C: Clone + Send + Sync + 'static,
+        F: Fn(&C) -> T + Send + Sync + 'static,
+    {
+        let config = std::sync::Arc::new(config);
+        Self::new(move || init(&config))
+    }
 
-        unsafe {
-            let ptr = Self::get_key(self.key);
+    /// creates a thread local whose values are reinitialised on access once
+    /// they are older than `ttl`.
+    ///
+    /// a value is dated when its thread first accesses it, and replaced by a
+    /// new one on the first access after it expired, without a background
+    /// thread. references from `get` may still point to the expired value,
+    /// so it is kept, as after `invalidate`, until the `ThreadLocal` is
+    /// dropped or borrowed mutably.
+    pub fn new_with_ttl<I: ThreadLocalInitialiser<T> + 'static>(
+        ttl: core::time::Duration,
+        init: I,
+    ) -> Self {
+        let mut this = Self::new(init);
+        this.ttl = Some(ttl);
+        return this;
+    }
 
-            if ptr.is_null() {
-                return self.init_value();
-            };
+    /// creates a thread local whose values are empty collections with room
+    /// for `capacity` elements, so threads do not reallocate while warming up.
+    pub fn with_capacity_init(capacity: usize) -> Self
+    where
+        T: WithCapacity,
+    {
+        Self::new(move || T::with_capacity(capacity))
+    }
 
-            return (ptr as *mut T).as_mut().unwrap_unchecked();
-        }
+    /// creates a thread local whose values start out as zero bytes.
+    pub fn new_zeroed() -> Self
+    where
+        T: Zeroable,
+    {
+        Self::new_zeroed_in()
     }
-}
 
-impl<T, A: Allocator> Drop for ThreadLocal<T, A> {
-    fn drop(&mut self) {
-        (self.initialiser_drop)(self.initiatiser);
+    /// creates a thread local whose `get` returns `fallback` rather than
+    /// panicking when a value fails to allocate.
+    ///
+    /// see `new_with_fallback_in`.
+    pub fn new_with_fallback<I: ThreadLocalInitialiser<T> + 'static>(
+        init: I,
+        fallback: &'static T,
+    ) -> Self {
+        Self::new_with_fallback_in(init, fallback)
+    }
 
-        unsafe {
-            Self::delete_key(self.key);
-        }
+    /// like `new`, but reports failures instead of panicking.
+    pub fn try_new<I: ThreadLocalInitialiser<T> + 'static>(
+        init: I,
+    ) -> Result<Self, ThreadLocalError> {
+        Self::try_new_in(init)
     }
 }
 
-unsafe impl<T> Sync for ThreadLocal<T> {}
-unsafe impl<T> Send for ThreadLocal<T> {}
-
-impl<T> AsRef<T> for ThreadLocal<T> {
-    fn as_ref(&self) -> &T {
-        self.get()
+impl<T, B: Backend> ThreadLocal<T, private::DefaultAllocator, B> {
+    /// like `new`, but keeps slots in the keys of `B` rather than the
+    /// platform's.
+    ///
+    /// with the `registry-backend` feature no keys are created at all.
+    pub fn with_backend<I: ThreadLocalInitialiser<T> + 'static>(init: I) -> Self {
+        Self::new_in(init)
     }
 }
 
-impl<T> AsMut<T> for ThreadLocal<T> {
-    fn as_mut(&mut self) -> &mut T {
-        self.get_mut()
+impl<T, A: Allocator, B: Backend> ThreadLocal<T, A, B> {
+    pub fn new_in<I: ThreadLocalInitialiser<T> + 'static>(init: I) -> Self {
+        Self::try_new_in(init).unwrap_or_else(|e| panic!("{}", e))
     }
-}
 
-impl<T> core::ops::Deref for ThreadLocal<T> {
-    type Target = T;
-    fn deref(&self) -> &Self::Target {
-        self.get()
+    /// like `new_in`, but reports failures instead of panicking.
+    ///
+    /// construction either returns a complete `ThreadLocal` or nothing: when
+    /// `A` fails to allocate the initialiser or the value of the constructing
+    /// thread, the key created for it is deleted again before the error is
+    /// returned. with `strictly-lazy`, neither a key nor a value exists yet,
+    /// so only the initialiser is allocated.
+    pub fn try_new_in<I: ThreadLocalInitialiser<T> + 'static>(
+        init: I,
+    ) -> Result<Self, ThreadLocalError> {
+        Self::try_new_with(init, false, None)
     }
-}
 
-impl<T> core::ops::DerefMut for ThreadLocal<T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        self.get_mut()
+    /// creates a thread local whose `get` returns `fallback` rather than
+    /// panicking when `A` fails to allocate the value of a thread.
+    ///
+    /// the fallback is shared by every thread and stays read only: a thread
+    /// getting it holds no value, and tries to allocate again on its next
+    /// access. other accessors such as `get_mut` or `try_get` still report
+    /// the failure. no value is initialised during construction, so that a
+    /// failing allocator cannot make it panic either.
+    pub fn new_with_fallback_in<I: ThreadLocalInitialiser<T> + 'static>(
+        init: I,
+        fallback: &'static T,
+    ) -> Self {
+        let mut tls = Self::try_new_uninit(init, false, None).unwrap_or_else(|e| panic!("{}", e));
+        tls.fallback = fallback;
+        return tls;
     }
+
+    /// creates a thread local on an existing platform key instead of
+    /// creating its own, so code outside Rust can share the slot.
+    ///
+    /// the value of the constructing thread is initialised as in `new`.
+    /// the key is not deleted when the `ThreadLocal` is dropped.
+    ///
+    /// # Safety
+    ///
+    /// - `key` must be a valid key, and stay valid until the `ThreadLocal`
+    ///   is dropped. deleting it remains the job of its creator.
+    /// - while the `ThreadLocal` is alive, the slot of every thread must be
+    ///   null or hold what the `ThreadLocal` stored in it. other code may
+    ///   read it, but must not overwrite or free it.
+    /// - a slot holds a pointer to a `T` allocated with `A`, which is freed
+    ///   when the `ThreadLocal` is dropped, after which the slots dangle.
+    /// - destructors registered with the key must not free the values,
+    ///   they are owned by the `ThreadLocal`.
+    #[cfg(not(feature = "registry-backend"))]
+    pub unsafe fn from_raw_key<I: ThreadLocalInitialiser<T> + 'static>(
+        key: B::Key,
+        init: I,
+    ) -> Self {
+        Self::try_new_with(init, false, Some(key)).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// the common constructor. `init` is stored without its lifetime, so
+    /// callers must keep the `ThreadLocal` from outliving it.
+    pub(crate) fn try_new_with<I: ThreadLocalInitialiser<T>>(
+        init: I,
+        zeroed: bool,
+        raw_key: Option<B::Key>,
+    ) -> Result<Self, ThreadLocalError> {
+        let tls = Self::try_new_uninit(init, zeroed, raw_key)?;
+
+        // the constructing thread is initialised eagerly
+        #[cfg(not(feature = "strictly-lazy"))]
+        unsafe {
+            tls.init_value(0)?
+        };
+
+        return Ok(tls);
+    }
+
+    /// like `try_new_with`, but leaves the constructing thread uninitialised.
+    pub(crate) fn try_new_uninit<I: ThreadLocalInitialiser<T>>(
+        init: I,
+        zeroed: bool,
+        raw_key: Option<B::Key>,
+    ) -> Result<Self, ThreadLocalError> {
+        #[cfg(feature = "strictly-lazy")]
+        const {
+            assert!(
+                initialiser_fits::<I>(),
+                "strictly-lazy requires initialisers no larger than two pointers"
+            )
+        };
+
+        unsafe {
+            // with strictly-lazy, the key is created on first access
+            let (key, key_state) = if let Some(key) = raw_key {
+                (MaybeUninit::new(key), KEY_READY)
+            } else if cfg!(any(feature = "strictly-lazy", feature = "registry-backend")) {
+                (MaybeUninit::uninit(), KEY_NONE)
+            } else {
+                (MaybeUninit::new(B::create()?), KEY_READY)
+            };
+
+            let Some(initiatiser) = store_initialiser::<I, A>(init) else {
+                if raw_key.is_none() && key_state == KEY_READY {
+                    B::delete(key.assume_init());
+                }
+                return Err(ThreadLocalError::AllocFailed);
+            };
+
+            let tls = Self {
+                key: UnsafeCell::new(key),
+                key_state: AtomicU8::new(key_state),
+                check_key: Self::key_check(key_state == KEY_READY),
+                initiatiser,
+                initialiser_drop: initialiser_drop::<I, T, A>,
+                initialiser_dropped: AtomicBool::new(false),
+                initialiser_init: initialiser_init::<I, T>,
+                const_init: None,
+                seed_clone: None,
+                fallback: core::ptr::null(),
+                ttl: None,
+                zeroed,
+                owns_key: raw_key.is_none(),
+                registry: Registry::new(),
+                generation: AtomicUsize::new(0),
+                releases: AtomicUsize::new(0),
+                assume_primed: AtomicBool::new(false),
+                limits: std::sync::OnceLock::new(),
+                shared: std::sync::OnceLock::new(),
+                #[cfg(feature = "registry-backend")]
+                table: table::Table::new(),
+                cleanup_id: AtomicU64::new(0),
+                cleanup_users: AtomicUsize::new(0),
+                #[cfg(all(target_family = "wasm", not(feature = "registry-backend")))]
+                owner: AtomicU64::new(ThreadId::current().as_u64()),
+                _mark: PhantomData,
+                _pinned: PhantomPinned,
+            };
+
+            return Ok(tls);
+        }
+    }
+
+    /// creates a thread local whose values start out as zero bytes.
+    ///
+    /// values are allocated with `Allocator::allocate_zeroed`, so large
+    /// values are never written through the stack.
+    pub fn new_zeroed_in() -> Self
+    where
+        T: Zeroable,
+    {
+        // never called, values are produced by the allocator
+        let init = || unsafe { core::mem::zeroed() };
+        Self::try_new_with(init, true, None).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// creates the key of a lazily keyed instance on first use.
+    #[inline]
+    fn check_init(&self) -> Result<(), ThreadLocalError> {
+        return (self.check_key)(self);
+    }
+
+    /// returns the `check_key` of an instance, whose key is ready already
+    /// if `key_ready`.
+    const fn key_check(key_ready: bool) -> fn(&Self) -> Result<(), ThreadLocalError> {
+        /// the `check_key` of instances that need no key or have one.
+        fn ready<T, A: Allocator, B: Backend>(
+            _: &ThreadLocal<T, A, B>,
+        ) -> Result<(), ThreadLocalError> {
+            return Ok(());
+        }
+
+        if cfg!(feature = "registry-backend") || key_ready {
+            return ready::<T, A, B>;
+        }
+        return Self::check_lazy_key;
+    }
+
+    /// the `check_key` of const and strictly lazy instances.
+    fn check_lazy_key(&self) -> Result<(), ThreadLocalError> {
+        if self.assume_primed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        if self.key_state.load(Ordering::Acquire) == KEY_READY {
+            return Ok(());
+        }
+
+        loop {
+            match self.key_state.compare_exchange_weak(
+                KEY_NONE,
+                KEY_CREATING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => unsafe {
+                    match B::create() {
+                        Ok(key) => {
+                            (*self.key.get()).write(key);
+                            self.key_state.store(KEY_READY, Ordering::Release);
+                            return Ok(());
+                        }
+                        Err(e) => {
+                            self.key_state.store(KEY_NONE, Ordering::Release);
+                            return Err(e);
+                        }
+                    }
+                },
+                Err(KEY_READY) => return Ok(()),
+                // another thread is creating the key
+                Err(_) => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    fn lock_registry(&self) -> registry::RegistryGuard<'_, T> {
+        self.try_lock_registry().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// locks the registry, failing if the current thread holds it already.
+    fn try_lock_registry(&self) -> Result<registry::RegistryGuard<'_, T>, ThreadLocalError> {
+        #[cfg(all(unix, feature = "fork"))]
+        {
+            let (mut guard, forked) = self.registry.try_lock_checked()?;
+
+            if forked {
+                // only the forking thread survives in the child
+                let survivor = fork::survivor();
+                let dead: Vec<_> = guard
+                    .extract_if(.., |entry| entry.thread_id != survivor)
+                    .collect();
+                // the entries are out of the registry before any value drops
+                unsafe { Self::free_entries(dead) };
+            }
+
+            return Ok(guard);
+        }
+
+        #[cfg(not(all(unix, feature = "fork")))]
+        return self.registry.try_lock();
+    }
+
+    /// whether values of this instance may be kept inline in the key slot.
+    fn is_inline(&self) -> bool {
+        // table slots may be unavailable while a thread tears down
+        !cfg!(feature = "registry-backend")
+            && inline_fits::<T>()
+            && self.const_init.is_some()
+            && self.seed_clone.is_none()
+    }
+
+    /// whether a slot word holds an inline value rather than a pointer.
+    ///
+    /// only slots of inline instances are tagged, since allocators may hand
+    /// out odd addresses for values aligned to a single byte.
+    fn is_inline_word(&self, word: usize) -> bool {
+        self.is_inline() && word & INLINE_TAG != 0
+    }
+
+    /// returns the value of a new thread copied from the const seed.
+    unsafe fn copy_seed(&self, seed: &T) -> T {
+        match self.seed_clone {
+            Some(clone) => return clone(seed),
+            // it is guarantined T is copy
+            None => return core::ptr::read(seed),
+        }
+    }
+
+    /// whether the slots of the current thread can be used.
+    fn slot_ready(&self) -> bool {
+        cfg!(feature = "registry-backend") || self.key_state.load(Ordering::Acquire) == KEY_READY
+    }
+
+    /// reads the slot word of the current thread.
+    unsafe fn load_slot(&self) -> *mut T {
+        #[cfg(feature = "registry-backend")]
+        match self.table.get() {
+            Some(word) => return word as *mut T,
+            // the thread released its table index, or its slot was written by
+            // another thread, fall back to the registry
+            None => return self.lock_registry().current_value(),
+        }
+
+        #[cfg(not(feature = "registry-backend"))]
+        return B::get(self.key()) as *mut T;
+    }
+
+    /// writes the slot word of the current thread.
+    unsafe fn store_slot(&self, value: *mut T) {
+        #[cfg(feature = "registry-backend")]
+        self.table.set(value as usize);
+
+        #[cfg(not(feature = "registry-backend"))]
+        B::set(self.key(), value as *mut u8);
+    }
+
+    /// returns the key, which must be `KEY_READY`.
+    unsafe fn key(&self) -> B::Key {
+        (*self.key.get()).assume_init()
+    }
+
+    /// drops and frees a value of `owner` taken out of the registry.
+    ///
+    /// the allocation is freed even if dropping the value panics.
+    unsafe fn free_value(ptr: *mut T, owner: ThreadId) {
+        struct Deallocate<T, A: Allocator>(*mut T, PhantomData<A>);
+
+        impl<T, A: Allocator> Drop for Deallocate<T, A> {
+            fn drop(&mut self) {
+                unsafe { deallocate_value::<T, A>(self.0) };
+            }
+        }
+
+        if ptr.is_null() {
+            return;
+        }
+        trace::dropped::<T>(owner);
+
+        let _deallocate = Deallocate::<T, A>(ptr, PhantomData);
+        core::ptr::drop_in_place(ptr);
+    }
+
+    /// drops and frees the values of `entries`, carrying on past values
+    /// whose destructor panics.
+    ///
+    /// once every value is freed, the first panic is resumed, unless the
+    /// thread is already unwinding, where resuming would abort the process
+    /// and the panics are dropped instead.
+    unsafe fn free_entries<E: IntoIterator<Item = registry::Entry<T>>>(entries: E) {
+        let mut panic = None;
+
+        for entry in entries {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                Self::free_value(entry.value, entry.thread_id)
+            }));
+            if let Err(payload) = result {
+                panic.get_or_insert(payload);
+            }
+        }
+
+        if let Some(payload) = panic {
+            if !std::thread::panicking() {
+                std::panic::resume_unwind(payload);
+            }
+        }
+    }
+
+    /// runs an initialiser for `thread_id`, poisoning it if `init` panics.
+    ///
+    /// the current thread must have been marked as initialising by
+    /// `reserve_init`.
+    unsafe fn guarded_init<R, F: FnOnce() -> R>(&self, thread_id: ThreadId, init: F) -> R {
+        struct PoisonOnPanic<'a, T, A: Allocator, B: Backend>(&'a ThreadLocal<T, A, B>, ThreadId);
+
+        impl<T, A: Allocator, B: Backend> Drop for PoisonOnPanic<'_, T, A, B> {
+            fn drop(&mut self) {
+                self.0.lock_registry().poison(self.1);
+            }
+        }
+
+        let guard = PoisonOnPanic(self, thread_id);
+        let value = init();
+        core::mem::forget(guard);
+
+        return value;
+    }
+
+    /// moves `value`, created in `generation`, onto the heap as the value of
+    /// the current thread.
+    unsafe fn install(&self, value: T, generation: usize) -> Result<*mut T, ThreadLocalError> {
+        let ptr = allocate_value::<T, A>(false);
+        if ptr.is_null() {
+            self.lock_registry().remove_current();
+            return Err(ThreadLocalError::AllocFailed);
+        }
+        ptr.write(value);
+
+        self.store_slot(ptr as _);
+        self.lock_registry().set_present(ptr, generation);
+        self.register_cleanup();
+
+        return Ok(ptr);
+    }
+
+    /// moves the value for the current thread onto the heap, reading
+    /// it from an inline slot word if present.
+    unsafe fn init_value(&self, word: usize) -> Result<*mut T, ThreadLocalError> {
+        if word == 0 {
+            let ptr = self.adopt()?;
+            if !ptr.is_null() {
+                return Ok(ptr);
+            }
+        }
+        // a thread holding an inline value keeps it under `freeze`
+        self.reserve_init(word == 0)?;
+        return self.create_value(word);
+    }
+
+    /// creates the value of the current thread on the heap, reading it from
+    /// an inline slot word if present, once `reserve_init` succeeded.
+    unsafe fn create_value(&self, word: usize) -> Result<*mut T, ThreadLocalError> {
+        let generation = self.generation.load(Ordering::Acquire);
+
+        if self.zeroed {
+            let ptr = allocate_value::<T, A>(true);
+            if ptr.is_null() {
+                self.lock_registry().remove_current();
+                return Err(ThreadLocalError::AllocFailed);
+            }
+
+            self.store_slot(ptr);
+            self.lock_registry().set_present(ptr, generation);
+            self.register_cleanup();
+            return Ok(ptr);
+        }
+
+        let value = if word != 0 {
+            // only const instances store inline words, so T is copy
+            unpack_inline(word)
+        } else if let Some(v) = &self.const_init {
+            self.copy_seed(v)
+        } else {
+            let init = || (self.initialiser_init)(self.initiatiser.as_ptr() as *mut u8);
+            self.guarded_init(ThreadId::current(), init)
+        };
+
+        return self.install(value, generation);
+    }
+
+    /// gives the current thread a new value in place of its stale one.
+    ///
+    /// references from `get` may still point to the old value, so it is
+    /// retired rather than dropped, and freed once the `ThreadLocal` is
+    /// borrowed mutably or dropped.
+    unsafe fn renew(&self) -> Result<*mut T, ThreadLocalError> {
+        self.retire_value();
+        self.reserve_init(false)?;
+        return self.create_value(0);
+    }
+
+    /// empties the slot of the current thread and retires its value, as
+    /// `renew` does before creating the new one.
+    unsafe fn retire_value(&self) {
+        self.store_slot(core::ptr::null_mut());
+        self.releases.fetch_add(1, Ordering::Release);
+        self.lock_registry().retire_current();
+    }
+
+    /// whether the heap value in the slot of the current thread was made
+    /// stale by `invalidate` or `clear`, or has outlived `ttl`.
+    fn is_stale(&self) -> Result<bool, ThreadLocalError> {
+        let generation = self.generation.load(Ordering::Acquire);
+        if generation == 0 && self.ttl.is_none() {
+            return Ok(false);
+        }
+
+        let registry = self.try_lock_registry()?;
+        if generation != 0 && registry.current_generation() != Some(generation) {
+            return Ok(true);
+        }
+        if let Some(ttl) = self.ttl {
+            return Ok(registry.is_expired(std::time::Instant::now(), ttl));
+        }
+        return Ok(false);
+    }
+
+    /// installs the value `preallocate` created for the current thread, if any.
+    ///
+    /// as the first lock taken to initialise a value, it fails rather than
+    /// deadlocks on a thread iterating the values with `for_each`.
+    unsafe fn adopt(&self) -> Result<*mut T, ThreadLocalError> {
+        let ptr = self.try_lock_registry()?.adopt_current();
+        if !ptr.is_null() {
+            self.store_slot(ptr);
+            self.register_cleanup();
+        }
+        return Ok(ptr);
+    }
+
+    /// marks the current thread as initialising its value, failing if it
+    /// may not get one under `freeze`, `set_max_threads` or
+    /// `set_memory_budget`.
+    ///
+    /// threads are counted under the lock that marks them, including those
+    /// still initialising, so threads initialising at once cannot exceed
+    /// `set_max_threads` or `set_memory_budget` together, and a failed
+    /// initialisation releases its reservation with its entry. threads
+    /// replacing a value they held pass `check_frozen` as false, since
+    /// `freeze` lets them keep one.
+    fn reserve_init(&self, check_frozen: bool) -> Result<(), ThreadLocalError> {
+        let Some(limits) = self.limits.get() else {
+            return self.try_lock_registry()?.begin_init();
+        };
+        if check_frozen && limits.frozen.load(Ordering::Acquire) {
+            return Err(ThreadLocalError::Frozen);
+        }
+
+        let mut registry = self.try_lock_registry()?;
+        let thread_id = ThreadId::current();
+
+        let max = limits.max_threads.load(Ordering::Relaxed);
+        if max != usize::MAX {
+            let holders = registry
+                .iter()
+                .filter(|e| e.state != State::Poisoned && e.thread_id != thread_id)
+                .count();
+
+            if holders >= max {
+                return Err(ThreadLocalError::ThreadLimit);
+            }
+        }
+
+        let budget = limits.memory_budget.load(Ordering::Relaxed);
+        if budget != usize::MAX {
+            let mut needed = self.reserved_bytes(&mut registry) + self.storage_layout().size();
+            if registry.current_state().is_none() {
+                needed += core::mem::size_of::<registry::Entry<T>>();
+            }
+
+            if needed > budget {
+                return Err(ThreadLocalError::MemoryBudget);
+            }
+        }
+
+        return registry.begin_init();
+    }
+
+    /// makes the current thread drop its value on exit if cleanup is enabled.
+    fn register_cleanup(&self) {
+        /// drops the value of the exiting thread, if the instance is alive.
+        unsafe fn run<T, A: Allocator, B: Backend>(addr: usize, id: u64) -> bool {
+            let this = &*(addr as *const ThreadLocal<T, A, B>);
+
+            return cleanup::with_live(
+                addr,
+                id,
+                || {
+                    this.cleanup_users.fetch_add(1, Ordering::AcqRel);
+                },
+                || {
+                    let value = this.clear_slot();
+                    if !this.is_inline_word(value) {
+                        ThreadLocal::<T, A>::free_value(value as *mut T, ThreadId::current());
+                    }
+                    this.cleanup_users.fetch_sub(1, Ordering::AcqRel);
+                },
+            );
+        }
+
+        let id = self.cleanup_id.load(Ordering::Acquire);
+        if id != 0 {
+            cleanup::register(self as *const Self as usize, id, run::<T, A, B>);
+        }
+    }
+
+    /// returns the heap pointer for the current thread, initialising it if needed.
+    pub(crate) fn try_value_ptr(&self) -> Result<*mut T, ThreadLocalError> {
+        let ptr = self.try_current_ptr()?;
+
+        if let Some(ttl) = self.ttl {
+            let now = std::time::Instant::now();
+            let mut registry = self.try_lock_registry()?;
+
+            if registry.is_expired(now, ttl) {
+                drop(registry);
+                let ptr = unsafe { self.renew() }?;
+                self.lock_registry().mark_created(now);
+                return Ok(ptr);
+            }
+            // values are dated when first seen
+            registry.mark_created(now);
+        }
+        return Ok(ptr);
+    }
+
+    /// returns the heap pointer for the current thread, ignoring `ttl`.
+    fn try_current_ptr(&self) -> Result<*mut T, ThreadLocalError> {
+        self.check_init()?;
+
+        unsafe {
+            let ptr = self.load_slot();
+
+            if ptr.is_null() || self.is_inline_word(ptr as usize) {
+                return self.init_value(ptr as usize);
+            };
+
+            // instances never invalidated or cleared skip the registry
+            let generation = self.generation.load(Ordering::Acquire);
+            if generation != 0 {
+                let (current, value) = {
+                    let registry = self.try_lock_registry()?;
+                    (registry.current_generation(), registry.current_value())
+                };
+
+                if current.is_none() {
+                    // the value was freed by `clear`
+                    self.store_slot(core::ptr::null_mut());
+                    return self.init_value(0);
+                }
+                if current != Some(generation) {
+                    return self.renew();
+                }
+                if value != ptr && !value.is_null() {
+                    // the value was moved by `migrate_allocator`
+                    self.store_slot(value);
+                    return Ok(value);
+                }
+            }
+
+            return Ok(ptr);
+        }
+    }
+
+    pub(crate) fn value_ptr(&self) -> *mut T {
+        self.try_value_ptr().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// returns a pointer to the value of the current thread, initialising
+    /// it if needed, e.g. for C code to keep across calls.
+    ///
+    /// values live on the heap, so the pointer stays the same across `get`s,
+    /// `set`, `replace` and `reinit`, which write in place. small values kept
+    /// inline in the key slot (see `const_new`) are moved to the heap first.
+    /// after `invalidate`, the next access creates a value elsewhere, while
+    /// the old pointer stays valid until the old value is freed.
+    ///
+    /// the pointer dangles once the value is freed or moved, by `take`,
+    /// `deinitialize`, `detach`, `clear`, `drain`, `migrate_allocator`,
+    /// `shrink_to_fit` or the cleanup of an exited thread, and when the
+    /// `ThreadLocal` is dropped. writing through it is subject to the same
+    /// rules as `get_mut_shared`.
+    pub fn current_ptr(&self) -> *mut T {
+        self.value_ptr()
+    }
+
+    pub fn get(&self) -> &T {
+        let ptr = match self.try_value_ptr() {
+            Ok(ptr) => ptr,
+            Err(ThreadLocalError::AllocFailed) if !self.fallback.is_null() => {
+                // the fallback is never handed out mutably
+                return unsafe { &*self.fallback };
+            }
+            Err(e) => panic!("{}", e),
+        };
+
+        #[cfg(feature = "debug-guards")]
+        unsafe {
+            guards::check(ptr)
+        };
+
+        unsafe { &*cell_value(ptr) }
+    }
+
+    /// returns the value of the current thread mutably.
+    ///
+    /// this needs the `ThreadLocal` to be owned or uniquely borrowed, so
+    /// shared instances such as statics use `get_mut_shared` instead.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { self.get_mut_shared() }
+    }
+
+    /// returns the value of the current thread mutably through a shared
+    /// reference.
+    ///
+    /// each thread owns its own value, which other threads only observe
+    /// through `get_for`, `for_each` and `iter`. values are kept in an
+    /// `UnsafeCell` and every reference is derived from the cell, never by
+    /// casting a previously returned reference, so alternating with `get`
+    /// is sound under stacked and tree borrows as long as the references
+    /// do not overlap.
+    ///
+    /// # Safety
+    ///
+    /// the returned reference must be the only reference to the value of
+    /// the current thread while it is alive: no other reference from `get`,
+    /// `get_mut_shared` or the like may be used until it is dropped, nor may
+    /// other threads read the value through `get_for`, `for_each` or `iter`,
+    /// and the value must not be replaced or dropped through `take`,
+    /// `reinit` or the like in the meantime.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get_mut_shared(&self) -> &mut T {
+        &mut *cell_value(self.value_ptr())
+    }
+
+    /// returns the value of the current thread behind a borrow guard.
+    ///
+    /// with the `borrow-tracking` feature the guard is counted, so that a
+    /// `mutate` of the same value panics while it is alive. otherwise it is
+    /// a plain reference.
+    pub fn get_ref(&self) -> Ref<'_, T> {
+        Ref::new(self.get())
+    }
+
+    /// returns the value of the current thread mutably through a shared
+    /// reference, behind a borrow guard.
+    ///
+    /// # Safety
+    ///
+    /// as for `get_mut_shared`. with the `borrow-tracking` feature, a guard
+    /// overlapping another from `get_ref` or `mutate` panics instead, but
+    /// plain references from `get` and the like are not tracked.
+    pub unsafe fn mutate(&self) -> RefMut<'_, T> {
+        RefMut::new(self.get_mut_shared())
+    }
+
+    /// runs `f` with the value of the current thread.
+    ///
+    /// this matches `LocalKey::with`, so a `ThreadLocal` static can replace
+    /// a `thread_local!` one without changing its uses. unlike `LocalKey`,
+    /// it never panics because the thread is being destroyed.
+    pub fn with<R, F: FnOnce(&T) -> R>(&self, f: F) -> R {
+        f(self.get())
+    }
+
+    /// runs `f` with the value of the current thread if it already has one.
+    ///
+    /// unlike `with`, nothing is initialised or allocated, so this is usable
+    /// in drop paths. a value made stale by `invalidate` counts as missing.
+    pub fn try_with<R, F: FnOnce(&T) -> R>(&self, f: F) -> Option<R> {
+        unsafe {
+            match self.existing_value()? {
+                Ok(ptr) => return Some(f(&*cell_value(ptr))),
+                Err(word) => {
+                    let value: T = unpack_inline(word);
+                    return Some(f(&value));
+                }
+            }
+        }
+    }
+
+    /// returns the value of the current thread if it already has one.
+    ///
+    /// as with `try_with`, nothing is initialised or allocated, so this is
+    /// usable in `Drop` impls. small values created by `const_new` and kept
+    /// in the key slot have no address to borrow; `peek` returns `None` for
+    /// them, while `try_with` still reaches them. after `invalidate`, the
+    /// value is also `None` while the current thread holds the registry,
+    /// e.g. inside `for_each` or a value's `Drop` during `clear`, since its
+    /// generation cannot be checked then.
+    pub fn peek(&self) -> Option<&T> {
+        match self.existing_value()? {
+            Ok(ptr) => return Some(unsafe { &*cell_value(ptr) }),
+            Err(_) => return None,
+        }
+    }
+
+    /// returns the value of the current thread without initialising it, or
+    /// the slot word if the value is kept inline.
+    fn existing_value(&self) -> Option<Result<*mut T, usize>> {
+        if !self.slot_ready() {
+            return None;
+        }
+
+        unsafe {
+            let word = self.load_slot() as usize;
+            if word == 0 {
+                return None;
+            }
+            if self.is_inline_word(word) {
+                return Some(Err(word));
+            }
+
+            let mut ptr = word as *mut T;
+            let generation = self.generation.load(Ordering::Acquire);
+            if generation != 0 {
+                let Ok(registry) = self.try_lock_registry() else {
+                    return None;
+                };
+                if registry.current_generation() != Some(generation) {
+                    return None;
+                }
+                // the slot may predate `migrate_allocator`
+                ptr = registry.current_value();
+                if ptr.is_null() {
+                    return None;
+                }
+            }
+
+            return Some(Ok(ptr));
+        }
+    }
+
+    /// creates the key if needed and initialises the value of the current thread.
+    pub fn prime(&self) {
+        self.get();
+    }
+
+    /// primes the current thread, then waits for the other threads sharing
+    /// `barrier` to do the same.
+    ///
+    /// once every thread has passed the barrier, accesses skip the check
+    /// that the key exists, leaving only the raw lookup on the hot path.
+    /// threads that did not take part still initialise lazily.
+    pub fn prime_all(&self, barrier: &std::sync::Barrier) {
+        self.prime();
+
+        if barrier.wait().is_leader() {
+            self.assume_primed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// creates the values of the threads `ids` ahead of time, so that they
+    /// allocate nothing on their first access.
+    ///
+    /// the values are produced on the calling thread, and each thread takes
+    /// its value over when it first accesses the `ThreadLocal`. threads that
+    /// already have a value are skipped.
+    pub fn preallocate(&self, ids: &[ThreadId]) {
+        let current = ThreadId::current();
+
+        for &id in ids {
+            if id == current {
+                self.prime();
+                continue;
+            }
+            if self
+                .lock_registry()
+                .binary_search_by_key(&id, |e| e.thread_id)
+                .is_ok()
+            {
+                continue;
+            }
+
+            unsafe {
+                let generation = self.generation.load(Ordering::Acquire);
+                let value = match &self.const_init {
+                    _ if self.zeroed => None,
+                    Some(v) => Some(self.copy_seed(v)),
+                    None => {
+                        // a panic poisons the thread, as on its own first access
+                        let init = || (self.initialiser_init)(self.initiatiser.as_ptr() as *mut u8);
+                        Some(self.guarded_init(id, init))
+                    }
+                };
+
+                let ptr = allocate_value::<T, A>(self.zeroed);
+                if ptr.is_null() {
+                    panic!("{}", ThreadLocalError::AllocFailed);
+                }
+                if let Some(value) = value {
+                    ptr.write(value);
+                }
+
+                if !self.lock_registry().insert_for(id, ptr, generation) {
+                    // another thread got there first
+                    Self::free_value(ptr, id);
+                }
+            }
+        }
+    }
+
+    /// returns the value of the current thread, initialising it with `init`
+    /// instead of the stored initialiser if it has none yet.
+    pub fn get_or_init<F: FnOnce() -> T>(&self, init: F) -> &T {
+        let init = || Ok::<T, core::convert::Infallible>(init());
+        match self.try_init_with(init).unwrap_or_else(|e| panic!("{}", e)) {
+            Ok(value) => value,
+            Err(e) => match e {},
+        }
+    }
+
+    /// returns the value of the current thread, initialising it with a clone
+    /// of a value shared by all threads if it has none yet.
+    ///
+    /// the first thread to get here runs `init` to produce the shared value,
+    /// once for the whole `ThreadLocal`, while other threads block until it
+    /// is ready. the shared value is kept until the `ThreadLocal` is dropped.
+    pub fn get_or_init_shared<F: FnOnce() -> T>(&self, init: F) -> &T
+    where
+        T: Clone,
+    {
+        self.get_or_init(|| {
+            let shared = self.shared.get_or_init(|| Box::new(init()));
+            return T::clone(shared);
+        })
+    }
+
+    /// returns the value of the current thread, installing `value` if it has
+    /// none yet. an unused `value` is dropped.
+    pub fn get_or_insert(&self, value: T) -> &T {
+        self.get_or_init(move || value)
+    }
+
+    /// like `get_or_init`, but the initialiser may fail.
+    ///
+    /// if `init` returns an error, the current thread is left uninitialised
+    /// and nothing is allocated, so a later call runs an initialiser again.
+    /// failures of the `ThreadLocal` itself, such as `Frozen` or
+    /// `ThreadLimit`, are returned through `E` as well.
+    pub fn get_or_try_init<E, F>(&self, init: F) -> Result<&T, E>
+    where
+        E: From<ThreadLocalError>,
+        F: FnOnce() -> Result<T, E>,
+    {
+        return self.try_init_with(init)?;
+    }
+
+    /// returns the value of the current thread, initialising it with `init`
+    /// if it has none or only a stale one, keeping the errors of `init`
+    /// apart from those of the `ThreadLocal`.
+    fn try_init_with<E, F: FnOnce() -> Result<T, E>>(
+        &self,
+        init: F,
+    ) -> Result<Result<&T, E>, ThreadLocalError> {
+        self.check_init()?;
+
+        unsafe {
+            let word = self.load_slot() as usize;
+            let held = if word == 0 {
+                !self.adopt()?.is_null()
+            } else {
+                self.is_inline_word(word) || !self.is_stale()?
+            };
+            if held {
+                return Ok(Ok(&*cell_value(self.try_value_ptr()?)));
+            }
+
+            // a stale value is replaced by the one of `init`
+            let mut check_frozen = true;
+            if word != 0 {
+                check_frozen = self.try_lock_registry()?.current_state().is_none();
+                self.retire_value();
+            }
+
+            self.reserve_init(check_frozen)?;
+            let generation = self.generation.load(Ordering::Acquire);
+            let value = match self.guarded_init(ThreadId::current(), init) {
+                Ok(value) => value,
+                Err(e) => {
+                    self.lock_registry().remove_current();
+                    return Ok(Err(e));
+                }
+            };
+
+            let ptr = self.install(value, generation)?;
+            return Ok(Ok(&*cell_value(ptr)));
+        }
+    }
+
+    /// borrows the thread local behind a handle that hides the allocator type.
+    pub fn as_dyn(&self) -> ThreadLocalRef<'_, T> {
+        ThreadLocalRef::new(self)
+    }
+
+    /// mutably borrows the thread local behind a handle that hides the allocator type.
+    pub fn as_dyn_mut(&mut self) -> ThreadLocalMut<'_, T> {
+        ThreadLocalMut::new(self)
+    }
+
+    /// like `get`, but reports failures instead of panicking.
+    pub fn try_get(&self) -> Result<&T, ThreadLocalError> {
+        unsafe { Ok(&*cell_value(self.try_value_ptr()?)) }
+    }
+
+    /// like `get_mut`, but reports failures instead of panicking.
+    pub fn try_get_mut(&mut self) -> Result<&mut T, ThreadLocalError> {
+        unsafe { Ok(&mut *cell_value(self.try_value_ptr()?)) }
+    }
+
+    /// detaches the slot word of the current thread, leaving it uninitialised.
+    unsafe fn clear_slot(&self) -> usize {
+        if !self.slot_ready() {
+            return 0;
+        }
+
+        let word = self.load_slot() as usize;
+        if word == 0 {
+            return 0;
+        }
+
+        self.store_slot(core::ptr::null_mut());
+        self.releases.fetch_add(1, Ordering::Release);
+        if !self.is_inline_word(word) {
+            let mut registry = self.lock_registry();
+
+            if registry.current_generation().is_none() {
+                // the value was freed by `clear`
+                return 0;
+            }
+            // the slot may predate `migrate_allocator`
+            let value = registry.current_value();
+            registry.remove_current();
+            return value as usize;
+        }
+
+        return word;
+    }
+
+    /// moves the value of the current thread out, leaving it uninitialised.
+    ///
+    /// the next access runs the initialiser again.
+    ///
+    /// # Safety
+    ///
+    /// the value is freed, so no reference to the value of the current
+    /// thread, from `get`, `peek`, `with`, `Cached` or the like, may be used
+    /// afterwards.
+    pub unsafe fn take(&self) -> Option<T> {
+        unsafe {
+            let word = self.clear_slot();
+
+            if word == 0 {
+                return None;
+            }
+            if self.is_inline_word(word) {
+                return Some(unpack_inline(word));
+            }
+
+            let ptr = word as *mut T;
+            let value = ptr.read();
+            deallocate_value::<T, A>(ptr);
+
+            return Some(value);
+        }
+    }
+
+    /// drops the value of the current thread, leaving it uninitialised.
+    ///
+    /// unlike `take`, the value is destroyed in place. the next access runs
+    /// the initialiser again.
+    ///
+    /// # Safety
+    ///
+    /// as for `take`.
+    pub unsafe fn deinitialize(&self) {
+        unsafe {
+            let word = self.clear_slot();
+
+            // inline values are copy and need no drop
+            if !self.is_inline_word(word) {
+                Self::free_value(word as *mut T, ThreadId::current());
+            }
+        }
+    }
+
+    /// replaces the value of the current thread, returning the previous one.
+    ///
+    /// the value is initialised first if needed, and is swapped in place
+    /// without reallocating. small values created by `const_new` are
+    /// swapped in the key slot and never allocate.
+    ///
+    /// # Safety
+    ///
+    /// the old value is moved out from under any reference to it, so no
+    /// reference to the value of the current thread, from `get`, `peek`,
+    /// `with`, `Cached` or the like, may be used afterwards.
+    pub unsafe fn replace(&self, value: T) -> T {
+        if self.is_inline() {
+            self.check_init().unwrap_or_else(|e| panic!("{}", e));
+
+            unsafe {
+                let word = self.load_slot() as usize;
+
+                if word == 0 || self.is_inline_word(word) {
+                    let old = if word == 0 {
+                        // it is guarantined T is copy
+                        core::ptr::read(self.const_init.as_ref().unwrap_unchecked())
+                    } else {
+                        unpack_inline(word)
+                    };
+                    self.store_slot(pack_inline(value) as *mut T);
+                    return old;
+                }
+            }
+        }
+
+        // the old value is moved out and the new one written in its place,
+        // under the lock that readers of `get_for` and `for_each` hold
+        let ptr = self.value_ptr();
+        let _registry = self.lock_registry();
+        unsafe { ptr.replace(value) }
+    }
+
+    /// reinitialises the value of the current thread if `pred` holds for it.
+    ///
+    /// a thread without a value is left to initialise on its next access,
+    /// without calling `pred`. see `reinit`.
+    ///
+    /// # Safety
+    ///
+    /// as for `reinit`, whenever `pred` holds.
+    pub unsafe fn reinit_if<P: FnOnce(&T) -> bool>(&self, pred: P) {
+        if self.try_with(pred) == Some(true) {
+            unsafe { self.reinit() };
+        }
+    }
+
+    /// drops the value of the current thread and runs the initialiser again,
+    /// reusing the allocation of the old value.
+    ///
+    /// the old value is dropped once the new one is ready. a thread without
+    /// a heap value is left to initialise on its next access.
+    ///
+    /// # Safety
+    ///
+    /// the old value is dropped in place, so no reference to the value of
+    /// the current thread, from `get`, `peek`, `with`, `Cached` or the like,
+    /// may be used afterwards.
+    pub unsafe fn reinit(&self) {
+        unsafe {
+            let word = self.clear_slot();
+
+            if word == 0 || self.is_inline_word(word) {
+                return;
+            }
+
+            let ptr = word as *mut T;
+            let generation = self.generation.load(Ordering::Acquire);
+
+            if self.zeroed {
+                trace::dropped::<T>(ThreadId::current());
+                core::ptr::drop_in_place(ptr);
+                ptr.write_bytes(0, 1);
+
+                self.store_slot(ptr);
+                self.lock_registry().set_present(ptr, generation);
+                return;
+            }
+
+            let guard = FreeOnPanic::<T, A>(ptr, PhantomData);
+
+            let value = self.fresh_value();
+            core::mem::forget(guard);
+
+            trace::dropped::<T>(ThreadId::current());
+            core::ptr::drop_in_place(ptr);
+            ptr.write(value);
+
+            self.store_slot(ptr);
+            self.lock_registry().set_present(ptr, generation);
+        }
+    }
+
+    /// moves the value of the current thread out and runs the initialiser
+    /// again in its allocation, so the thread has a fresh value right away.
+    ///
+    /// a thread without a heap value is left to initialise on its next
+    /// access, as in `reinit`.
+    ///
+    /// # Safety
+    ///
+    /// the old value is moved out from under any reference to it, so no
+    /// reference to the value of the current thread, from `get`, `peek`,
+    /// `with`, `Cached` or the like, may be used afterwards.
+    pub unsafe fn reset_take(&self) -> Option<T> {
+        unsafe {
+            let word = self.clear_slot();
+
+            if word == 0 {
+                return None;
+            }
+            if self.is_inline_word(word) {
+                return Some(unpack_inline(word));
+            }
+
+            let ptr = word as *mut T;
+            let generation = self.generation.load(Ordering::Acquire);
+
+            let old = if self.zeroed {
+                let old = ptr.read();
+                ptr.write_bytes(0, 1);
+                old
+            } else {
+                let guard = FreeOnPanic::<T, A>(ptr, PhantomData);
+                let value = self.fresh_value();
+                core::mem::forget(guard);
+                ptr.replace(value)
+            };
+
+            self.store_slot(ptr);
+            self.lock_registry().set_present(ptr, generation);
+            return Some(old);
+        }
+    }
+
+    /// produces a new value for the current thread, from the seed or the
+    /// initialiser.
+    unsafe fn fresh_value(&self) -> T {
+        self.reserve_init(false).unwrap_or_else(|e| panic!("{}", e));
+
+        if let Some(v) = &self.const_init {
+            return self.copy_seed(v);
+        }
+        let init = || (self.initialiser_init)(self.initiatiser.as_ptr() as *mut u8);
+        return self.guarded_init(ThreadId::current(), init);
+    }
+
+    /// drops the values of every thread, leaving them uninitialised.
+    ///
+    /// every thread runs the initialiser again on its next access. like
+    /// `invalidate`, every access afterwards also checks the registry.
+    pub fn clear(&mut self) {
+        self.release_values();
+    }
+
+    /// moves the values of every thread out, leaving the thread local empty.
+    ///
+    /// the slots are cleared up front, so the thread local can be used again
+    /// once the iterator is gone, and values it did not yield are dropped
+    /// with it. small values created by `const_new` and kept in the key slot
+    /// are not yielded, as with `clear`.
+    pub fn drain(&mut self) -> impl Iterator<Item = T>
+    where
+        T: Send,
+    {
+        /// moves each value out of its allocation, freeing the rest on drop.
+        struct Drain<T, A: Allocator>(std::vec::IntoIter<registry::Entry<T>>, PhantomData<A>);
+
+        impl<T, A: Allocator> Iterator for Drain<T, A> {
+            type Item = T;
+
+            fn next(&mut self) -> Option<T> {
+                for entry in self.0.by_ref() {
+                    if entry.state == State::Present {
+                        unsafe {
+                            let value = entry.value.read();
+                            deallocate_value::<T, A>(entry.value);
+                            return Some(value);
+                        }
+                    }
+                }
+                return None;
+            }
+        }
+
+        impl<T, A: Allocator> Drop for Drain<T, A> {
+            fn drop(&mut self) {
+                unsafe { ThreadLocal::<T, A>::free_entries(self.0.by_ref()) };
+            }
+        }
+
+        self.free_retired();
+        let entries = core::mem::take(&mut *self.lock_registry());
+
+        if self.slot_ready() {
+            unsafe { self.store_slot(core::ptr::null_mut()) };
+        }
+        // the slots of other threads still point to the drained values
+        self.generation.fetch_add(1, Ordering::AcqRel);
+
+        return Drain::<T, A>(entries.into_iter(), PhantomData);
+    }
+
+    /// drops the values of every thread and forgets the slots pointing to them.
+    fn release_values(&self) {
+        let (entries, retired) = {
+            let mut registry = self.lock_registry();
+            (
+                core::mem::take(&mut *registry),
+                core::mem::take(registry.retired()),
+            )
+        };
+
+        if self.slot_ready() {
+            unsafe { self.store_slot(core::ptr::null_mut()) };
+        }
+        // the slots of other threads still point to freed values
+        self.generation.fetch_add(1, Ordering::AcqRel);
+
+        unsafe { Self::free_entries(entries.into_iter().chain(retired)) };
+    }
+
+    /// frees the values retired by `renew`, which `&mut self` no longer
+    /// lets anyone borrow.
+    fn free_retired(&mut self) {
+        let retired = core::mem::take(self.lock_registry().retired());
+        unsafe { Self::free_entries(retired) };
+    }
+
+    /// consumes the `ThreadLocal`, returning the value of the current thread.
+    ///
+    /// the values of other threads and the initialiser are dropped.
+    pub fn into_inner(self) -> Option<T> {
+        // no reference outlives `self`
+        let value = unsafe { self.take() };
+        drop(self);
+        return value;
+    }
+
+    /// moves the values of every thread into allocations of `N`, freeing
+    /// the old ones with `A`.
+    ///
+    /// the key, the initialiser and the values themselves are carried over,
+    /// so every thread finds its value again on its next access. values kept
+    /// inline in the key slot are not allocated and stay where they are.
+    /// panics if `N` fails to allocate, leaving `self` to be dropped.
+    pub fn migrate_allocator<N: Allocator>(mut self) -> ThreadLocal<T, N, B> {
+        // pinned instances cannot be moved, so cleanup is never enabled here
+        debug_assert_eq!(self.cleanup_id.load(Ordering::Relaxed), 0);
+
+        // retired values were allocated with `A`
+        self.free_retired();
+
+        let generation = self.generation.load(Ordering::Acquire);
+
+        unsafe {
+            // allocate everything first, so a failure leaves `self` intact
+            let mut moved = Vec::new();
+            {
+                let mut registry = self.lock_registry();
+
+                for entry in registry.iter().filter(|e| !e.value.is_null()) {
+                    let ptr = allocate_value::<T, N>(false);
+                    if ptr.is_null() {
+                        for ptr in moved {
+                            deallocate_value::<T, N>(ptr);
+                        }
+                        drop(registry);
+                        panic!("{}", ThreadLocalError::AllocFailed);
+                    }
+                    ptr.copy_from_nonoverlapping(entry.value, 1);
+                    moved.push(ptr);
+                }
+
+                let entries = registry.iter_mut().filter(|e| !e.value.is_null());
+                for (entry, ptr) in entries.zip(moved) {
+                    deallocate_value::<T, A>(entry.value);
+                    entry.value = ptr;
+
+                    // slots still hold the old pointers, see `try_value_ptr`
+                    if entry.generation == generation {
+                        entry.generation = generation + 1;
+                    }
+                }
+            }
+
+            let this = core::mem::ManuallyDrop::new(self);
+
+            return ThreadLocal {
+                key_state: core::ptr::read(&this.key_state),
+                check_key: ThreadLocal::<T, N, B>::key_check(
+                    this.key_state.load(Ordering::Acquire) == KEY_READY,
+                ),
+                key: core::ptr::read(&this.key),
+                initiatiser: core::ptr::read(&this.initiatiser),
+                // a boxed initialiser is still freed with `A`
+                initialiser_drop: this.initialiser_drop,
+                initialiser_dropped: core::ptr::read(&this.initialiser_dropped),
+                initialiser_init: this.initialiser_init,
+                const_init: core::ptr::read(&this.const_init),
+                seed_clone: this.seed_clone,
+                fallback: this.fallback,
+                ttl: this.ttl,
+                zeroed: this.zeroed,
+                owns_key: this.owns_key,
+                registry: core::ptr::read(&this.registry),
+                generation: AtomicUsize::new(generation + 1),
+                releases: core::ptr::read(&this.releases),
+                assume_primed: core::ptr::read(&this.assume_primed),
+                limits: core::ptr::read(&this.limits),
+                shared: core::ptr::read(&this.shared),
+                #[cfg(feature = "registry-backend")]
+                table: core::ptr::read(&this.table),
+                cleanup_id: AtomicU64::new(0),
+                cleanup_users: AtomicUsize::new(0),
+                #[cfg(all(target_family = "wasm", not(feature = "registry-backend")))]
+                owner: core::ptr::read(&this.owner),
+                _mark: PhantomData,
+                _pinned: PhantomPinned,
+            };
+        }
+    }
+
+    /// drops the initialiser unless it was dropped already.
+    ///
+    /// no thread can be running the initialiser meanwhile: callers hold
+    /// `&mut self`, so no `get` is in flight, and `Drop` first waits for
+    /// exiting threads that reached the instance through `enable_cleanup`,
+    /// whose values may run it again while being dropped.
+    fn drop_initialiser(&mut self) {
+        if !self.initialiser_dropped.swap(true, Ordering::AcqRel) {
+            (self.initialiser_drop)(self.initiatiser.as_mut_ptr() as *mut u8);
+        }
+    }
+
+    /// keeps the platform key alive when the `ThreadLocal` is dropped.
+    ///
+    /// the values are still dropped with the `ThreadLocal`, but the key,
+    /// including one created after this call, is never deleted. platforms
+    /// only offer a limited number of keys, so every leaked key is lost to
+    /// the process for good, and the slots of threads that accessed their
+    /// value keep dangling pointers. meant for keys shared with code outside
+    /// Rust that outlives the `ThreadLocal`. keys adopted by `from_raw_key`
+    /// are never deleted anyway.
+    pub fn leak_key(&mut self) {
+        self.owns_key = false;
+    }
+
+    /// replaces the initialiser, dropping the old one.
+    ///
+    /// threads initialising their value afterwards use `init`, including
+    /// those of thread locals created by `new_zeroed` or from a seed by
+    /// `const_new_cloned`. values already initialised are kept until they
+    /// are reinitialised.
+    ///
+    /// panics for thread locals created by `const_new` with values small
+    /// enough to be kept in the key slot, since other threads may read the
+    /// seed without running an initialiser.
+    pub fn set_initializer<F: Fn() -> T + Send + Sync + 'static>(&mut self, init: F) {
+        assert!(
+            !self.is_inline(),
+            "the initialiser of inline const_new values cannot be replaced"
+        );
+
+        let initiatiser = unsafe { store_initialiser::<F, A>(init) }
+            .unwrap_or_else(|| panic!("{}", ThreadLocalError::AllocFailed));
+
+        self.drop_initialiser();
+        self.initiatiser = initiatiser;
+        self.initialiser_drop = initialiser_drop::<F, T, A>;
+        self.initialiser_init = initialiser_init::<F, T>;
+        *self.initialiser_dropped.get_mut() = false;
+
+        // values no longer come from the allocator or the seed
+        self.zeroed = false;
+        self.const_init = None;
+        self.seed_clone = None;
+    }
+
+    /// marks the values of every thread as stale.
+    ///
+    /// each thread runs the initialiser again on its next access, while
+    /// threads that do not access the `ThreadLocal` keep their old value.
+    /// references from `get` may still point to the old values, so they are
+    /// not dropped then, but kept until the `ThreadLocal` is dropped or
+    /// borrowed mutably by `clear`, `drain`, `shrink_to_fit` or
+    /// `migrate_allocator`.
+    ///
+    /// once invalidated, every access also checks the generation of the
+    /// current thread in the registry. values kept inline in the key slot
+    /// (see `const_new`) are not affected.
+    pub fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// drops the value of every thread when it exits, instead of keeping it
+    /// until the `ThreadLocal` is dropped.
+    ///
+    /// exiting threads reach the `ThreadLocal` by its address, which is why it
+    /// must be pinned, e.g. with `Box::pin`, `Arc::pin` or `Pin::static_ref`.
+    /// only threads that initialise their value afterwards are cleaned up,
+    /// and only where `supports_thread_exit_cleanup` returns true.
+    ///
+    /// the platform keys have no destructors, so an exiting thread drops its
+    /// value exactly once and never loops over `PTHREAD_DESTRUCTOR_ITERATIONS`
+    /// rounds. if the drop of the value accesses the `ThreadLocal` again, the
+    /// thread gets a new value, which is no longer cleaned up on exit and is
+    /// kept like the value of any exited thread.
+    pub fn enable_cleanup(self: Pin<&Self>) {
+        let addr = &*self as *const Self as usize;
+
+        if self.cleanup_id.load(Ordering::Acquire) == 0 {
+            let id = cleanup::enable(addr);
+            if self
+                .cleanup_id
+                .compare_exchange(0, id, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+            {
+                // another thread enabled it first
+                cleanup::disable(addr, id);
+            }
+        }
+    }
+
+    /// returns the layout of the memory each thread's value occupies.
+    ///
+    /// the size is what is asked of `A` for one value, plus the header `A`
+    /// keeps in front of it, and includes the canaries of `debug-guards`.
+    /// small values created by `const_new` and kept in the key slot take no
+    /// allocation at all.
+    pub fn storage_layout(&self) -> Layout {
+        let size = value_size::<T>() + A::HEADER;
+        return Layout::from_size_align(size, core::mem::align_of::<T>()).unwrap();
+    }
+
+    /// estimates the memory held by the values of every thread and the
+    /// registry keeping them, in bytes.
+    ///
+    /// each value counts as its `storage_layout` and each registry entry,
+    /// including those of exited and poisoned threads, as its own size.
+    /// values replaced after `invalidate` count until they are freed. the
+    /// spare capacity of the registry and allocator overheads beyond
+    /// `Allocator::HEADER` are not counted.
+    pub fn allocated_bytes(&self) -> usize {
+        let mut registry = self.lock_registry();
+        let values = registry
+            .iter()
+            .filter(|entry| entry.state == State::Present)
+            .count();
+        let retired = registry.retired().len();
+        return values * self.storage_layout().size()
+            + retired * self.bytes_per_value()
+            + registry.len() * core::mem::size_of::<registry::Entry<T>>();
+    }
+
+    /// `allocated_bytes`, also counting the values threads are initialising.
+    fn reserved_bytes(&self, registry: &mut registry::RegistryGuard<'_, T>) -> usize {
+        let values = registry
+            .iter()
+            .filter(|entry| entry.state != State::Poisoned)
+            .count();
+        let retired = registry.retired().len();
+        return values * self.storage_layout().size()
+            + retired * self.bytes_per_value()
+            + registry.len() * core::mem::size_of::<registry::Entry<T>>();
+    }
+
+    /// the bytes `allocated_bytes` grows by for the value of another thread.
+    fn bytes_per_value(&self) -> usize {
+        return self.storage_layout().size() + core::mem::size_of::<registry::Entry<T>>();
+    }
+
+    /// returns the number of threads holding a value.
+    pub fn len(&self) -> usize {
+        return self
+            .lock_registry()
+            .iter()
+            .filter(|entry| entry.state == State::Present)
+            .count();
+    }
+
+    /// panics in debug builds if more than `n` threads hold a value,
+    /// counting exited threads whose values are still kept.
+    ///
+    /// a tripwire for tests catching runaway thread creation, unlike
+    /// `set_max_threads` it limits nothing. release builds skip the check.
+    pub fn debug_assert_max_threads(&self, n: usize) {
+        if cfg!(debug_assertions) {
+            let len = self.len();
+            assert!(
+                len <= n,
+                "{} threads hold a value, at most {} expected",
+                len,
+                n
+            );
+        }
+    }
+
+    /// whether no thread holds a value.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// returns how often the registry lock was acquired, and how often
+    /// threads spun waiting for it.
+    ///
+    /// the registry is locked to initialise, iterate and look up values of
+    /// other threads, but not to read the value of the current thread.
+    #[cfg(feature = "metrics")]
+    pub fn contention_stats(&self) -> ContentionStats {
+        self.registry.stats()
+    }
+
+    /// returns the ids of threads that have exited but whose values are
+    /// still held, sorted by thread id.
+    ///
+    /// these values are only dropped by `shrink_to_fit` or when the
+    /// `ThreadLocal` is dropped.
+    pub fn leaked_thread_ids(&self) -> Vec<ThreadId> {
+        return self
+            .lock_registry()
+            .iter()
+            .filter(|entry| entry.state == State::Present && !entry.liveness.is_alive())
+            .map(|entry| entry.thread_id)
+            .collect();
+    }
+
+    /// whether the initialiser panicked on the current thread.
+    ///
+    /// like a poisoned `Mutex`, a poisoned thread does not retry: `get`
+    /// panics and `try_get` fails with `ThreadLocalError::Poisoned` until
+    /// `clear_poison` is called.
+    pub fn is_poisoned(&self) -> bool {
+        self.lock_registry().current_state() == Some(State::Poisoned)
+    }
+
+    /// lets the current thread run the initialiser again after it panicked.
+    pub fn clear_poison(&self) {
+        let mut registry = self.lock_registry();
+
+        if registry.current_state() == Some(State::Poisoned) {
+            registry.remove_current();
+        }
+    }
+
+    /// limits the number of threads holding a value to `n`.
+    ///
+    /// once reached, initialising the value of another thread fails with
+    /// `ThreadLocalError::ThreadLimit`, which `try_get` reports and `get`
+    /// panics with. threads already holding a value are not affected, and
+    /// values of exited threads count until `shrink_to_fit` drops them.
+    pub fn set_max_threads(&self, n: usize) {
+        self.limits().max_threads.store(n, Ordering::Relaxed);
+    }
+
+    /// limits the memory estimated by `allocated_bytes` to `bytes`.
+    ///
+    /// initialising the value of a thread fails with
+    /// `ThreadLocalError::MemoryBudget` once it would bring the estimate
+    /// over the budget, which `try_get` reports and `get` panics with.
+    /// values already held are not affected, values still being
+    /// initialised count as held, and values of exited threads count until
+    /// `shrink_to_fit` drops them.
+    pub fn set_memory_budget(&self, bytes: usize) {
+        self.limits().memory_budget.store(bytes, Ordering::Relaxed);
+    }
+
+    /// stops threads without a value from initialising one.
+    ///
+    /// their `get` panics and `try_get` fails with `ThreadLocalError::Frozen`
+    /// instead of running the initialiser, e.g. to stop new allocations
+    /// during shutdown. threads holding a value keep it, and small values
+    /// kept inline in the key slot (see `const_new`) stay readable through
+    /// `get_copied`.
+    pub fn freeze(&self) {
+        self.limits().frozen.store(true, Ordering::Release);
+    }
+
+    /// lets threads initialise their value again after `freeze`.
+    pub fn unfreeze(&self) {
+        if let Some(limits) = self.limits.get() {
+            limits.frozen.store(false, Ordering::Release);
+        }
+    }
+
+    /// returns the limits of the instance, allocating them on first use.
+    fn limits(&self) -> &Limits {
+        let limits = || Limits {
+            max_threads: AtomicUsize::new(usize::MAX),
+            memory_budget: AtomicUsize::new(usize::MAX),
+            frozen: AtomicBool::new(false),
+        };
+        return self.limits.get_or_init(|| Box::new(limits()));
+    }
+
+    /// drops the values of threads that have exited and releases the excess
+    /// capacity of the registry.
+    ///
+    /// values are otherwise kept until the `ThreadLocal` is dropped, so long
+    /// running processes spawning many short lived threads should call this
+    /// periodically.
+    pub fn shrink_to_fit(&mut self) {
+        self.free_retired();
+
+        let dead: Vec<_> = {
+            let mut registry = self.lock_registry();
+            let dead = registry
+                .extract_if(.., |entry| !entry.liveness.is_alive())
+                .collect();
+            registry.shrink_to_fit();
+            dead
+        };
+        // a panicking value cannot leave its freed entry in the registry
+        unsafe { Self::free_entries(dead) };
+    }
+
+    /// iterates the values of every thread that has initialised one.
+    ///
+    /// values kept inline in the key slot (see `const_new`) are only visible
+    /// once a reference to them has been requested on their thread.
+    ///
+    /// the references live as long as the borrow of `self`, which is why it
+    /// must be unique: no thread can free its value while they are alive.
+    /// `for_each` visits the values through a shared reference instead.
+    pub fn iter(&mut self) -> impl Iterator<Item = &T>
+    where
+        T: Sync,
+    {
+        self.iter_ordered().map(|(_, value)| value)
+    }
+
+    /// calls `f` with the id of every thread that has initialised a value,
+    /// whether the thread is still running, as in `iter_with_status`, and
+    /// the value, sorted by thread id.
+    ///
+    /// the registry stays locked while `f` runs, so no thread can free its
+    /// value meanwhile, and threads that initialise their value wait for
+    /// `for_each` to return. `f` must not access this `ThreadLocal` in ways
+    /// that need the lock: those panic, or fail with
+    /// `ThreadLocalError::RegistryLocked` in `try_get`, instead of
+    /// deadlocking.
+    pub fn for_each<F: FnMut(ThreadId, bool, &T)>(&self, mut f: F)
+    where
+        T: Sync,
+    {
+        let _ = self.try_for_each(|id, alive, value| {
+            f(id, alive, value);
+            return Ok::<(), ()>(());
+        });
+    }
+
+    /// like `for_each`, but stops at the first error returned by `f`.
+    pub(crate) fn try_for_each<E, F: FnMut(ThreadId, bool, &T) -> Result<(), E>>(
+        &self,
+        mut f: F,
+    ) -> Result<(), E>
+    where
+        T: Sync,
+    {
+        for (id, alive, value) in registry::Iter::new(self.lock_registry()) {
+            f(id, alive, value)?;
+        }
+        return Ok(());
+    }
+
+    /// iterates the values of all threads mutably.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T>
+    where
+        T: Send,
+    {
+        unsafe { self.iter_mut_unchecked() }
+    }
+
+    /// iterates the values of all threads mutably through a shared reference.
+    ///
+    /// meant for phases where a coordinator knows the workers are parked,
+    /// e.g. behind a barrier, to gather or scatter their values. the registry
+    /// stays locked until the iterator is dropped.
+    ///
+    /// # Safety
+    ///
+    /// while the iterator or any reference it returned is alive, no thread
+    /// may access its value, or create one, through this `ThreadLocal`.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn iter_mut_unchecked(&self) -> impl Iterator<Item = &mut T>
+    where
+        T: Send,
+    {
+        registry::IterMut::new(self.lock_registry())
+    }
+
+    /// returns the value of thread `id`, if it has one, keeping the registry
+    /// locked while the reference is alive.
+    ///
+    /// the owner cannot drop, take, replace or reinitialise the value while
+    /// the lock is held: `replace` writes under the lock, `set` needs
+    /// `&mut self`, and the other paths forget the value in the registry
+    /// under the lock before touching it. references from `get_mut_shared`
+    /// and the like must not overlap it, see their safety sections. locking
+    /// behaves as in `for_each`.
+    pub fn get_for(&self, id: ThreadId) -> Option<LockedRef<'_, T>>
+    where
+        T: Sync,
+    {
+        LockedRef::new(self.lock_registry(), id)
+    }
+
+    /// iterates `(thread id, value)` pairs sorted by thread id.
+    ///
+    /// the order is deterministic, which makes it suitable for reproducible
+    /// aggregation and tests. borrowing behaves as in `iter`.
+    pub fn iter_ordered(&mut self) -> impl Iterator<Item = (ThreadId, &T)>
+    where
+        T: Sync,
+    {
+        self.iter_with_status().map(|(id, _, value)| (id, value))
+    }
+
+    /// iterates `(thread id, alive, value)` sorted by thread id, where
+    /// `alive` tells whether the owning thread is still running.
+    ///
+    /// a thread counts as exited once its thread locals are destroyed, which
+    /// is best-effort: threads the `ThreadLocal` never saw run, such as those
+    /// given a value by `preallocate`, count as alive. values of exited
+    /// threads are those `shrink_to_fit` would drop. borrowing behaves as in
+    /// `iter`.
+    pub fn iter_with_status(&mut self) -> impl Iterator<Item = (ThreadId, bool, &T)>
+    where
+        T: Sync,
+    {
+        registry::Iter::new(self.lock_registry())
+    }
+
+    /// applies `f` to the value of every thread, returning the results with
+    /// their thread id, sorted by thread id. locking behaves as in
+    /// `for_each`.
+    pub fn map_all<R, F: Fn(&T) -> R>(&self, f: F) -> Vec<(ThreadId, R)>
+    where
+        T: Sync,
+    {
+        let mut results = Vec::new();
+        self.for_each(|id, _, value| results.push((id, f(value))));
+        return results;
+    }
+
+    /// returns a handle formatting the value of the current thread.
+    ///
+    /// the value is only accessed, and initialised, when the handle is
+    /// actually formatted.
+    pub fn display(&self) -> impl core::fmt::Display + '_
+    where
+        T: core::fmt::Display,
+    {
+        struct Display<'a, T, A: Allocator, B: Backend>(&'a ThreadLocal<T, A, B>);
+
+        impl<T: core::fmt::Display, A: Allocator, B: Backend> core::fmt::Display for Display<'_, T, A, B> {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                return self.0.get().fmt(f);
+            }
+        }
+
+        Display(self)
+    }
+
+    /// drops the values of every thread when the process exits.
+    ///
+    /// statics never run `Drop`, so without this the values of a static
+    /// `ThreadLocal` are leaked for the whole program.
+    ///
+    /// the values are dropped by an `atexit` handler on the thread calling
+    /// `exit`, in reverse order of registration. handlers registered later
+    /// by other libraries run before it, and nothing is dropped if the
+    /// process ends through `_exit`, `abort` or a signal.
+    ///
+    /// # Safety
+    ///
+    /// other threads may still be running when the handler runs, so once
+    /// the process may be exiting, no thread other than the exiting one may
+    /// access the `ThreadLocal` or use a reference to any of its values.
+    #[cfg(unix)]
+    pub unsafe fn register_atexit(&'static self) {
+        unsafe fn release<T, A: Allocator, B: Backend>(this: usize) {
+            let this = &*(this as *const ThreadLocal<T, A, B>);
+
+            // the exiting thread may still access it from later handlers
+            this.release_values();
+        }
+
+        // `self` is static, and the caller keeps the other threads away
+        atexit::register(self as *const Self as usize, release::<T, A, B>);
+    }
+}
+
+impl<T: Copy, A: Allocator, B: Backend> ThreadLocal<T, A, B> {
+    /// returns a copy of the value for the current thread.
+    ///
+    /// for small values created by `const_new`, this reads the key slot
+    /// directly and never allocates.
+    pub fn get_copied(&self) -> T {
+        if !self.is_inline() {
+            return *self.get();
+        }
+
+        self.check_init().unwrap_or_else(|e| panic!("{}", e));
+
+        unsafe {
+            let word = self.load_slot() as usize;
+
+            if word == 0 {
+                return *self.const_init.as_ref().unwrap_unchecked();
+            }
+            if self.is_inline_word(word) {
+                return unpack_inline(word);
+            }
+            // heap values may be stale
+            return *self.get();
+        }
+    }
+
+    /// copies the values of up to `cap` threads into `out`, in thread id
+    /// order, returning how many were written.
+    ///
+    /// meant for handing values to C, nothing is allocated. values kept
+    /// inline in the key slot are skipped as in `iter`.
+    ///
+    /// # Safety
+    ///
+    /// `out` must be valid for writing `cap` consecutive, suitably aligned
+    /// values of `T`. what it held before is overwritten without being
+    /// dropped.
+    pub unsafe fn export_c(&self, out: *mut T, cap: usize) -> usize
+    where
+        T: Sync,
+    {
+        let mut written = 0;
+
+        let _ = self.try_for_each(|_, _, value| {
+            if written == cap {
+                return Err(());
+            }
+            out.add(written).write(*value);
+            written += 1;
+            return Ok(());
+        });
+
+        return written;
+    }
+
+    /// replaces the value for the current thread through a shared reference.
+    ///
+    /// for small values created by `const_new`, the value is written into
+    /// the key slot directly and never allocates, so statics can be written
+    /// too. a value on the heap, e.g. moved there by `get`, may still be
+    /// borrowed, so it is retired as by `invalidate` rather than
+    /// overwritten: references from `get` keep seeing the old value, which
+    /// is freed once the `ThreadLocal` is borrowed mutably or dropped. other
+    /// instances therefore allocate a new value on every call, and `set`
+    /// overwrites in place instead.
+    pub fn set_copied(&self, value: T) {
+        self.check_init().unwrap_or_else(|e| panic!("{}", e));
+
+        unsafe {
+            let word = self.load_slot() as usize;
+
+            if word != 0 && !self.is_inline_word(word) {
+                self.retire_value();
+            } else if self.is_inline() {
+                self.store_slot(pack_inline(value) as *mut T);
+                return;
+            }
+
+            let reserved = self.reserve_init(false);
+            reserved.unwrap_or_else(|e| panic!("{}", e));
+            let generation = self.generation.load(Ordering::Acquire);
+            let created = self.install(value, generation);
+            created.unwrap_or_else(|e| panic!("{}", e));
+        }
+    }
+
+    /// replaces the value for the current thread with `f` of the old one,
+    /// returning the new value.
+    ///
+    /// a shorthand for `get_copied` and `set_copied`, so it never allocates
+    /// for small values created by `const_new` either.
+    pub fn update_copied<F: FnOnce(T) -> T>(&self, f: F) -> T {
+        let value = f(self.get_copied());
+        self.set_copied(value);
+        return value;
+    }
+
+    /// replaces the value for the current thread.
+    ///
+    /// for small values created by `const_new`, the value is written into the
+    /// key slot directly and never allocates. like `get_mut`, this needs the
+    /// `ThreadLocal` to be owned or uniquely borrowed, since a value on the
+    /// heap is overwritten in place while `get` may have handed it out.
+    pub fn set(&mut self, value: T) {
+        if self.is_inline() {
+            self.check_init().unwrap_or_else(|e| panic!("{}", e));
+
+            unsafe {
+                let ptr = self.load_slot();
+
+                if ptr.is_null() || self.is_inline_word(ptr as usize) {
+                    self.store_slot(pack_inline(value) as *mut T);
+                    return;
+                }
+            }
+        }
+
+        *self.get_mut() = value;
+    }
+}
+
+impl<T, A: Allocator, B: Backend> ThreadLocal<core::cell::RefCell<T>, A, B> {
+    /// borrows the value of the current thread, like `get().borrow()`.
+    ///
+    /// panics if the value is borrowed mutably.
+    pub fn borrow(&self) -> core::cell::Ref<'_, T> {
+        self.get().borrow()
+    }
+
+    /// borrows the value of the current thread mutably, like
+    /// `get().borrow_mut()`.
+    ///
+    /// panics if the value is borrowed.
+    pub fn borrow_mut(&self) -> core::cell::RefMut<'_, T> {
+        self.get().borrow_mut()
+    }
+}
+
+impl<T, A: Allocator, B: Backend> ThreadLocal<core::cell::Cell<T>, A, B> {
+    /// calls `f` with the cell of the current thread.
+    pub fn with_cell<R>(&self, f: impl FnOnce(&core::cell::Cell<T>) -> R) -> R {
+        return f(self.get());
+    }
+}
+
+impl<A: Allocator, B: Backend> ThreadLocal<Vec<u8>, A, B> {
+    /// returns the buffer of the current thread as a byte slice.
+    pub fn as_bytes(&self) -> &[u8] {
+        return self.get().as_slice();
+    }
+
+    /// returns the buffer of the current thread as a mutable byte slice.
+    ///
+    /// # Safety
+    ///
+    /// the same as `get_mut_shared`: no other reference to the buffer of
+    /// the current thread, including those of other threads from `get_for`
+    /// or `for_each`, may be used while the slice is alive.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn bytes_mut(&self) -> &mut [u8] {
+        return self.get_mut_shared().as_mut_slice();
+    }
+
+    /// truncates the buffer of the current thread to zero, keeping its
+    /// capacity.
+    ///
+    /// # Safety
+    ///
+    /// the same as `get_mut_shared`: no other reference to the buffer of
+    /// the current thread, from `get`, `as_bytes` or the like, may be used
+    /// while it is cleared or afterwards.
+    pub unsafe fn clear_buffer(&self) {
+        unsafe { self.get_mut_shared().clear() };
+    }
+}
+
+impl<T, A: Allocator, B: Backend> Drop for ThreadLocal<T, A, B> {
+    fn drop(&mut self) {
+        #[cfg(all(target_family = "wasm", not(feature = "registry-backend")))]
+        {
+            let owner = *self.owner.get_mut();
+            debug_assert!(
+                owner == 0 || owner == ThreadId::current().as_u64(),
+                "a ThreadLocal must be dropped on the thread that created it on wasm"
+            );
+        }
+
+        /// finishes the teardown even if dropping a value panics.
+        struct Teardown<'a, T, A: Allocator, B: Backend>(&'a mut ThreadLocal<T, A, B>);
+
+        impl<T, A: Allocator, B: Backend> Drop for Teardown<'_, T, A, B> {
+            fn drop(&mut self) {
+                let this = &mut *self.0;
+                this.drop_initialiser();
+
+                if this.owns_key && this.key_state.load(Ordering::Acquire) == KEY_READY {
+                    unsafe {
+                        B::delete(this.key());
+                    }
+                }
+            }
+        }
+
+        let id = *self.cleanup_id.get_mut();
+        if id != 0 {
+            cleanup::disable(self as *const Self as usize, id);
+            // wait for exiting threads already dropping their value
+            while self.cleanup_users.load(Ordering::Acquire) != 0 {
+                core::hint::spin_loop();
+            }
+        }
+
+        let teardown = Teardown(self);
+        let (entries, retired) = {
+            let mut registry = teardown.0.lock_registry();
+            (
+                core::mem::take(&mut *registry),
+                core::mem::take(registry.retired()),
+            )
+        };
+        unsafe { Self::free_entries(entries.into_iter().chain(retired)) };
+    }
+}
+
+unsafe impl<T, A: Allocator, B: Backend> Sync for ThreadLocal<T, A, B> {}
+unsafe impl<T, A: Allocator, B: Backend> Send for ThreadLocal<T, A, B> {}
+
+impl<T> AsRef<T> for ThreadLocal<T> {
+    fn as_ref(&self) -> &T {
+        self.get()
+    }
+}
+
+impl<T> AsMut<T> for ThreadLocal<T> {
+    fn as_mut(&mut self) -> &mut T {
+        self.get_mut()
+    }
+}
+
+impl<T> core::ops::Deref for ThreadLocal<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.get()
+    }
+}
+
+impl<T> core::ops::DerefMut for ThreadLocal<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.get_mut()
+    }
+}
+
+impl<T: Default> Default for ThreadLocal<T> {
+    fn default() -> Self {
+        // `T::default` borrows nothing, whatever `T` is
+        ThreadLocal::try_new_with(T::default, false, None).unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for ThreadLocal<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return self.get().fmt(f);
+    }
+}
+
+/// formats the value of the current thread, which also provides `to_string`.
+impl<T: core::fmt::Display> core::fmt::Display for ThreadLocal<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return self.get().fmt(f);
+    }
+}
+
+#[test]
+fn t() {
+    let mut r: ThreadLocal<u8> = ThreadLocal::new(|| 6);
+
+    assert!(*r == 6);
+    *r = 7;
+    assert!(*r == 7);
+    *r = 8;
+    assert!(*r == 8);
+
+    let mut r: ThreadLocal<u8> = ThreadLocal::const_new(6);
+
+    assert!(*r == 6);
+    *r = 7;
+    assert!(*r == 7);
+    *r = 8;
+    assert!(*r == 8);
+}
+
+#[cfg(not(feature = "registry-backend"))]
+#[test]
+fn const_inline_never_allocates() {
+    struct PanicAllocator;
+
+    impl Allocator for PanicAllocator {
+        fn allocate(_size: usize) -> *mut u8 {
+            panic!("inline values must not allocate")
+        }
+        fn deallocate(_ptr: *mut u8) {
+            panic!("inline values must not deallocate")
+        }
+    }
+
+    static COUNTER: ThreadLocal<u32, PanicAllocator> = ThreadLocal::const_new_in(0);
+
+    assert_eq!(COUNTER.get_copied(), 0);
+    std::thread::spawn(|| {
+        assert_eq!(COUNTER.get_copied(), 0);
+        COUNTER.set_copied(5);
+        assert_eq!(COUNTER.update_copied(|n| n + 1), 6);
+    })
+    .join()
+    .unwrap();
+    // the static counter is written per thread
+    for _ in 0..3 {
+        COUNTER.update_copied(|n| n + 1);
+    }
+    assert_eq!(COUNTER.get_copied(), 3);
+
+    let mut counter: ThreadLocal<u32, PanicAllocator> = ThreadLocal::const_new_in(0);
+    counter.set(0);
+    assert_eq!(counter.get_copied(), 0);
+    counter.set(41);
+    counter.set(counter.get_copied() + 1);
+    assert_eq!(counter.get_copied(), 42);
+
+    std::thread::scope(|s| {
+        s.spawn(|| assert_eq!(counter.get_copied(), 0));
+    });
+
+    let mut other: ThreadLocal<u32, PanicAllocator> = ThreadLocal::const_new_in(0);
+    std::thread::spawn(move || {
+        other.set(7);
+        assert_eq!(other.get_copied(), 7);
+    })
+    .join()
+    .unwrap();
+
+    assert_eq!(counter.get_copied(), 42);
+}
+
+#[test]
+fn iter_ordered_sorts_by_thread_id() {
+    let mut tls: ThreadLocal<ThreadId> = ThreadLocal::new(ThreadId::current);
+
+    let mut ids = vec![*tls.get()];
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            ids.push(s.spawn(|| *tls.get()).join().unwrap());
+        }
+    });
+    ids.sort();
+
+    let pairs: Vec<_> = tls.iter_ordered().collect();
+    assert_eq!(pairs.len(), ids.len());
+
+    for ((id, value), expected) in pairs.into_iter().zip(ids) {
+        assert_eq!(id, expected);
+        assert_eq!(*value, expected);
+    }
+}
+
+#[cfg(all(unix, feature = "fork"))]
+#[test]
+fn fork_child_sees_clean_registry() {
+    let mut tls: ThreadLocal<ThreadId> = ThreadLocal::new(ThreadId::current);
+
+    tls.get();
+    std::thread::scope(|s| {
+        s.spawn(|| tls.get());
+        s.spawn(|| tls.get());
+    });
+    assert_eq!(tls.iter().count(), 3);
+
+    unsafe {
+        let pid = libc::fork();
+
+        if pid == 0 {
+            let clean = tls
+                .iter_ordered()
+                .map(|(id, value)| (id, *value))
+                .eq([(ThreadId::current(), ThreadId::current())]);
+            libc::_exit(if clean { 0 } else { 1 });
+        }
+
+        let mut status = 0;
+        assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+
+    // the parent is unaffected
+    assert_eq!(tls.iter().count(), 3);
+}
+
+#[test]
+fn try_get_reports_errors() {
+    use core::sync::atomic::AtomicPtr;
+
+    struct NullAllocator;
+
+    impl Allocator for NullAllocator {
+        fn allocate(_size: usize) -> *mut u8 {
+            core::ptr::null_mut()
+        }
+        fn deallocate(_ptr: *mut u8) {}
+    }
+
+    let re = ThreadLocal::<u64, NullAllocator>::try_new_in(|| 1).and_then(|tls| {
+        tls.try_get()?;
+        Ok(tls)
+    });
+    assert_eq!(re.err(), Some(ThreadLocalError::AllocFailed));
+    let tls: ThreadLocal<u64, NullAllocator> = ThreadLocal::const_new_in(1);
+    assert_eq!(tls.try_get().err(), Some(ThreadLocalError::AllocFailed));
+
+    let creator = ThreadId::current();
+    let tls: ThreadLocal<u32> = ThreadLocal::new(move || {
+        if ThreadId::current() != creator {
+            panic!("initialiser failed");
+        }
+        return 0;
+    });
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let re = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| tls.get()));
+            assert!(re.is_err());
+            assert_eq!(tls.try_get().err(), Some(ThreadLocalError::Poisoned));
+        });
+    });
+
+    static RECURSIVE: AtomicPtr<ThreadLocal<Option<ThreadLocalError>>> =
+        AtomicPtr::new(core::ptr::null_mut());
+    let tls: ThreadLocal<Option<ThreadLocalError>> = ThreadLocal::new(|| {
+        let this = RECURSIVE.load(Ordering::SeqCst);
+        if this.is_null() {
+            return None;
+        }
+        return unsafe { (*this).try_get().err() };
+    });
+    RECURSIVE.store(&tls as *const _ as *mut _, Ordering::SeqCst);
+    std::thread::scope(|s| {
+        s.spawn(|| assert_eq!(*tls.get(), Some(ThreadLocalError::RecursiveInit)));
+    });
+}
+
+#[cfg(all(unix, not(feature = "registry-backend")))]
+#[test]
+fn try_new_reports_key_exhaustion() {
+    unsafe {
+        // exhaust the keys in a child so the other tests keep theirs
+        let pid = libc::fork();
+
+        if pid == 0 {
+            let mut keep = Vec::new();
+            let err = loop {
+                let re = ThreadLocal::<u8>::try_new(|| 0).and_then(|tls| {
+                    tls.try_get()?;
+                    Ok(tls)
+                });
+                match re {
+                    Ok(tls) => keep.push(tls),
+                    Err(e) => break e,
+                }
+            };
+            let code = if err == ThreadLocalError::KeyCreate(libc::EAGAIN) {
+                0
+            } else {
+                1
+            };
+            libc::_exit(code);
+        }
+
+        let mut status = 0;
+        assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+}
+
+#[cfg(feature = "strictly-lazy")]
+#[test]
+fn strictly_lazy_new_never_allocates() {
+    use core::sync::atomic::{AtomicBool, AtomicUsize};
+
+    static CONSTRUCTED: AtomicBool = AtomicBool::new(false);
+    static INIT_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    struct ConstructionAllocator;
+
+    impl Allocator for ConstructionAllocator {
+        fn allocate(size: usize) -> *mut u8 {
+            if !CONSTRUCTED.load(Ordering::SeqCst) {
+                panic!("allocated during construction");
+            }
+            private::DefaultAllocator::allocate(size)
+        }
+        fn deallocate(ptr: *mut u8) {
+            private::DefaultAllocator::deallocate(ptr)
+        }
+    }
+
+    let seed = 5u32;
+    let tls: ThreadLocal<u32, ConstructionAllocator> = ThreadLocal::new_in(move || {
+        INIT_CALLS.fetch_add(1, Ordering::SeqCst);
+        seed
+    });
+    assert_eq!(INIT_CALLS.load(Ordering::SeqCst), 0);
+
+    CONSTRUCTED.store(true, Ordering::SeqCst);
+    assert_eq!(*tls.get(), 5);
+    assert_eq!(INIT_CALLS.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn drop_runs_values_before_initialiser() {
+    use std::sync::Mutex;
+
+    static LOG: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+    struct Logged(&'static str);
+
+    impl Drop for Logged {
+        fn drop(&mut self) {
+            LOG.lock().unwrap().push(self.0);
+        }
+    }
+
+    let captured = Box::new(Logged("initialiser"));
+    let tls: ThreadLocal<Logged> = ThreadLocal::new(move || {
+        let _ = &captured;
+        Logged("value")
+    });
+
+    tls.get();
+    std::thread::scope(|s| {
+        s.spawn(|| tls.get());
+    });
+    drop(tls);
+
+    assert_eq!(*LOG.lock().unwrap(), ["value", "value", "initialiser"]);
+}
+
+#[test]
+fn shrink_to_fit_reclaims_exited_threads() {
+    let mut tls: ThreadLocal<u32> = ThreadLocal::new(|| 1);
+    tls.get();
+
+    std::thread::scope(|s| {
+        for _ in 0..64 {
+            s.spawn(|| *tls.get()).join().unwrap();
+        }
+    });
+
+    let before = tls.lock_registry().capacity();
+    assert!(before >= 65);
+
+    tls.shrink_to_fit();
+
+    assert!(tls.lock_registry().capacity() < before);
+    assert_eq!(tls.iter().count(), 1);
+}
+
+#[test]
+fn inline_zero_is_not_uninitialised() {
+    let mut tls: ThreadLocal<u16> = ThreadLocal::const_new(5);
+
+    tls.set(0);
+    assert_eq!(tls.get_copied(), 0);
+    assert_eq!(*tls.get(), 0);
+}
+
+#[test]
+fn over_aligned_const_values_round_trip() {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    #[repr(align(8))]
+    struct Flag(u8);
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    #[repr(align(2))]
+    struct Pair(u8);
+
+    let mut flag: ThreadLocal<Flag> = ThreadLocal::const_new(Flag(3));
+    let mut pair: ThreadLocal<Pair> = ThreadLocal::const_new(Pair(5));
+
+    // larger than its payload, or more aligned than the slot word
+    assert!(!flag.is_inline());
+    assert_eq!(pair.is_inline(), !cfg!(feature = "registry-backend"));
+
+    assert_eq!(flag.get_copied(), Flag(3));
+    assert_eq!(pair.get_copied(), Pair(5));
+
+    flag.set(Flag(200));
+    pair.set(Pair(201));
+    assert_eq!(*flag.get(), Flag(200));
+    assert_eq!(*pair.get(), Pair(201));
+    assert_eq!(flag.get() as *const Flag as usize % 8, 0);
+}
+
+#[test]
+fn get_or_try_init_caches_only_success() {
+    #[derive(Debug, PartialEq)]
+    enum Fetch {
+        Offline(u32),
+        Tls(ThreadLocalError),
+    }
+
+    impl From<ThreadLocalError> for Fetch {
+        fn from(e: ThreadLocalError) -> Self {
+            return Fetch::Tls(e);
+        }
+    }
+
+    let mut tls: ThreadLocal<u32> = ThreadLocal::new(|| 0);
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let offline = tls.get_or_try_init(|| Err(Fetch::Offline(1)));
+            assert_eq!(offline, Err(Fetch::Offline(1)));
+            let offline = tls.get_or_try_init(|| Err(Fetch::Offline(2)));
+            assert_eq!(offline, Err(Fetch::Offline(2)));
+
+            assert_eq!(tls.get_or_try_init(|| Ok::<_, Fetch>(3)), Ok(&3));
+            assert_eq!(tls.get_or_try_init(|| Ok::<_, Fetch>(4)), Ok(&3));
+            assert_eq!(*tls.get(), 3);
+
+            // a stale value is replaced by `init`, not the stored initialiser
+            tls.invalidate();
+            assert_eq!(tls.get_or_try_init(|| Ok::<_, Fetch>(5)), Ok(&5));
+        });
+    });
+    assert_eq!(tls.iter().filter(|v| **v == 5).count(), 1);
+
+    // failures of the thread local are returned rather than panicking
+    tls.freeze();
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let frozen = tls.get_or_try_init(|| Ok(6));
+            assert_eq!(frozen, Err(Fetch::Tls(ThreadLocalError::Frozen)));
+        });
+    });
+}
+
+#[test]
+fn deinitialize_drops_and_reinitialises() {
+    use core::sync::atomic::AtomicUsize;
+
+    static INITS: AtomicUsize = AtomicUsize::new(0);
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted(usize);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let mut tls: ThreadLocal<Counted> =
+        ThreadLocal::new(|| Counted(INITS.fetch_add(1, Ordering::SeqCst)));
+
+    let first = tls.get().0;
+    unsafe { tls.deinitialize() };
+    assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    assert_eq!(tls.iter().count(), 0);
+
+    assert_eq!(tls.get().0, first + 1);
+
+    let taken = unsafe { tls.take() }.unwrap();
+    assert_eq!(taken.0, first + 1);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    assert!(unsafe { tls.take() }.is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn register_atexit_drops_values() {
+    use core::sync::atomic::AtomicI32;
+
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    struct Reported;
+
+    impl Drop for Reported {
+        fn drop(&mut self) {
+            unsafe { libc::write(PIPE.load(Ordering::SeqCst), b"d".as_ptr() as _, 1) };
+        }
+    }
+
+    unsafe {
+        let mut fds = [0; 2];
+        assert_eq!(libc::pipe(fds.as_mut_ptr()), 0);
+
+        // exit in a child so the test harness keeps running
+        let pid = libc::fork();
+
+        if pid == 0 {
+            libc::close(fds[0]);
+            PIPE.store(fds[1], Ordering::SeqCst);
+
+            let tls: &'static ThreadLocal<Reported> =
+                Box::leak(Box::new(ThreadLocal::new(|| Reported)));
+            tls.get();
+            std::thread::spawn(|| {
+                tls.get();
+            })
+            .join()
+            .unwrap();
+
+            tls.register_atexit();
+            libc::exit(0);
+        }
+
+        libc::close(fds[1]);
+        let mut buf = [0u8; 8];
+        let mut read = 0;
+        loop {
+            let n = libc::read(fds[0], buf[read..].as_mut_ptr() as _, buf.len() - read);
+            if n <= 0 {
+                break;
+            }
+            read += n as usize;
+        }
+        libc::close(fds[0]);
+
+        let mut status = 0;
+        assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(&buf[..read], b"dd");
+    }
+}
+
+#[test]
+fn display_formats_lazily() {
+    use core::sync::atomic::AtomicUsize;
+
+    static INITS: AtomicUsize = AtomicUsize::new(0);
+
+    let tls: ThreadLocal<u32> = ThreadLocal::new(|| {
+        INITS.fetch_add(1, Ordering::SeqCst);
+        42
+    });
+    unsafe { tls.deinitialize() };
+    let inits = INITS.load(Ordering::SeqCst);
+
+    let handle = tls.display();
+    assert_eq!(INITS.load(Ordering::SeqCst), inits);
+
+    assert_eq!(format!("[{:>4}]", handle), "[  42]");
+    assert_eq!(INITS.load(Ordering::SeqCst), inits + 1);
+    assert_eq!(tls.to_string(), "42");
+}
+
+#[test]
+fn reinit_and_replace_reuse_the_allocation() {
+    type CountingAllocator = testing::CountingAllocator<3>;
+
+    let mut tls: ThreadLocal<Vec<u32>, CountingAllocator> = ThreadLocal::new_in(|| vec![1]);
+    tls.get();
+    let (allocs, frees) = (CountingAllocator::allocs(), CountingAllocator::frees());
+
+    for i in 0..16 {
+        assert_eq!(unsafe { tls.replace(vec![i]) }, [1]);
+        unsafe { tls.reinit() };
+        assert_eq!(*tls.get(), [1]);
+    }
+
+    assert_eq!(CountingAllocator::allocs(), allocs);
+    assert_eq!(CountingAllocator::frees(), frees);
+    assert_eq!(tls.iter_ordered().count(), 1);
+}
+
+#[test]
+fn invalidate_reinitialises_lazily() {
+    use std::sync::Barrier;
+
+    static INITS: AtomicUsize = AtomicUsize::new(0);
+
+    let tls: ThreadLocal<usize> = ThreadLocal::new(|| INITS.fetch_add(1, Ordering::SeqCst));
+    let barrier = Barrier::new(2);
+    let main = *tls.get();
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let old = *tls.get();
+            barrier.wait();
+            // main invalidates and reinitialises its own value
+            barrier.wait();
+            assert_ne!(*tls.get(), old);
+        });
+
+        // the worker still borrows `tls`, so its values are visited in place
+        let values = || {
+            tls.map_all(|value| *value)
+                .into_iter()
+                .map(|(_, value)| value)
+                .collect::<Vec<_>>()
+        };
+
+        barrier.wait();
+        let worker = *values().last().unwrap();
+        tls.invalidate();
+        assert_eq!(values(), [main, worker]);
+
+        let renewed = *tls.get();
+        assert_ne!(renewed, main);
+        assert_eq!(*tls.get(), renewed);
+        assert_eq!(values(), [renewed, worker]);
+        barrier.wait();
+    });
+
+    assert_eq!(tls.len(), 2);
+}
+
+#[test]
+fn leaked_thread_ids_reports_exited_threads() {
+    let mut tls: ThreadLocal<u32> = ThreadLocal::new(|| 1);
+    tls.get();
+
+    let exited = std::thread::scope(|s| {
+        s.spawn(|| {
+            tls.get();
+            ThreadId::current()
+        })
+        .join()
+        .unwrap()
+    });
+
+    assert_eq!(tls.leaked_thread_ids(), [exited]);
+
+    tls.shrink_to_fit();
+    assert!(tls.leaked_thread_ids().is_empty());
+}
+
+#[test]
+fn new_zeroed_uses_zeroed_allocation() {
+    static ZEROED: AtomicUsize = AtomicUsize::new(0);
+
+    struct ZeroedOnlyAllocator;
+
+    impl Allocator for ZeroedOnlyAllocator {
+        fn allocate(_size: usize) -> *mut u8 {
+            panic!("zeroed values must use allocate_zeroed")
+        }
+        fn deallocate(ptr: *mut u8) {
+            private::DefaultAllocator::deallocate(ptr)
+        }
+        fn allocate_zeroed(size: usize) -> *mut u8 {
+            ZEROED.fetch_add(1, Ordering::SeqCst);
+            private::DefaultAllocator::allocate_zeroed(size)
+        }
+    }
+
+    let tls: ThreadLocal<[u64; 4096], ZeroedOnlyAllocator> = ThreadLocal::new_zeroed_in();
+    assert!(tls.get().iter().all(|v| *v == 0));
+
+    std::thread::scope(|s| {
+        s.spawn(|| assert!(tls.get().iter().all(|v| *v == 0)));
+    });
+    assert_eq!(ZEROED.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn get_mut_shared_mutates_behind_shared_reference() {
+    let mut tls: ThreadLocal<u32> = ThreadLocal::new(|| 1);
+
+    let shared = &tls;
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            unsafe { *shared.get_mut_shared() += 1 };
+            assert_eq!(*shared.get(), 2);
+        });
+    });
+    unsafe { *shared.get_mut_shared() = 5 };
+    assert_eq!(*tls.get(), 5);
+
+    *tls += 1;
+    assert_eq!(*tls, 6);
+    let mut all: Vec<_> = tls.iter().copied().collect();
+    all.sort();
+    assert_eq!(all, [2, 6]);
+}
+
+#[cfg(all(unix, not(feature = "registry-backend")))]
+#[test]
+fn from_raw_key_adopts_without_deleting() {
+    unsafe {
+        let mut key: libc::pthread_key_t = 0;
+        assert_eq!(libc::pthread_key_create(&mut key, None), 0);
+
+        let tls: ThreadLocal<u32> = ThreadLocal::from_raw_key(key, || 3);
+        assert_eq!(*tls.get(), 3);
+        // the foreign side sees the value through the shared slot
+        assert_eq!(*(libc::pthread_getspecific(key) as *const u32), 3);
+        drop(tls);
+
+        // the key still belongs to its creator
+        assert_eq!(libc::pthread_setspecific(key, 1 as _), 0);
+        assert_eq!(libc::pthread_getspecific(key) as usize, 1);
+        assert_eq!(libc::pthread_key_delete(key), 0);
+    }
+}
+
+#[cfg(all(unix, not(feature = "registry-backend")))]
+#[test]
+fn leaked_key_outlives_the_thread_local() {
+    let mut tls: ThreadLocal<u32> = ThreadLocal::new(|| 3);
+    tls.get();
+    let key = unsafe { tls.key() };
+
+    tls.leak_key();
+    drop(tls);
+
+    unsafe {
+        // the slot still dangles, but the key itself is valid
+        assert_eq!(libc::pthread_setspecific(key, 1 as _), 0);
+        assert_eq!(libc::pthread_getspecific(key) as usize, 1);
+        assert_eq!(libc::pthread_key_delete(key), 0);
+    }
+}
+
+#[test]
+fn initialiser_is_dropped_once() {
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted;
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    struct Exploding;
+
+    impl Drop for Exploding {
+        fn drop(&mut self) {
+            panic!("value destructor failed");
+        }
+    }
+
+    let captured = Box::new(Counted);
+    let mut tls: ThreadLocal<u32> = ThreadLocal::new(move || {
+        let _ = &captured;
+        7
+    });
+    tls.get();
+    tls.clear();
+    assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+    assert_eq!(*tls.get(), 7);
+    assert_eq!(tls.into_inner(), Some(7));
+    assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+
+    let captured = Box::new(Counted);
+    let tls: ThreadLocal<Exploding> = ThreadLocal::new(move || {
+        let _ = &captured;
+        Exploding
+    });
+    tls.get();
+    let re = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(tls)));
+    assert!(re.is_err());
+    assert_eq!(DROPS.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn clear_forgets_values_of_other_threads() {
+    use std::sync::mpsc::channel;
+
+    struct Shared(*mut ThreadLocal<Vec<u32>>);
+    unsafe impl Send for Shared {}
+
+    let ptr = Box::into_raw(Box::new(ThreadLocal::new(|| vec![1])));
+    let shared = Shared(ptr);
+    let (to_worker, jobs) = channel::<()>();
+    let (to_main, done) = channel::<()>();
+
+    // the worker only borrows the `ThreadLocal` between the handshakes
+    let worker = std::thread::spawn(move || unsafe {
+        let tls = shared;
+        (*tls.0).replace(vec![2]);
+        to_main.send(()).unwrap();
+
+        jobs.recv().unwrap();
+        // the old value was freed, a fresh one is created
+        assert_eq!(*(*tls.0).get(), [1]);
+        assert_eq!((*tls.0).take(), Some(vec![1]));
+        to_main.send(()).unwrap();
+    });
+
+    done.recv().unwrap();
+    let tls = unsafe { &mut *ptr };
+    tls.get_mut().push(3);
+    tls.clear();
+    assert_eq!(*tls.get(), [1]);
+
+    to_worker.send(()).unwrap();
+    done.recv().unwrap();
+    worker.join().unwrap();
+    drop(unsafe { Box::from_raw(ptr) });
+}
+
+#[test]
+fn prime_all_initialises_every_thread() {
+    static TLS: ThreadLocal<u64> = ThreadLocal::const_new(3);
+    let barrier = std::sync::Barrier::new(4);
+
+    std::thread::scope(|s| {
+        for _ in 0..3 {
+            s.spawn(|| TLS.prime_all(&barrier));
+        }
+        TLS.prime_all(&barrier);
+    });
+
+    assert!(TLS.assume_primed.load(Ordering::Relaxed));
+    assert_eq!(TLS.len(), 4);
+
+    std::thread::scope(|s| {
+        s.spawn(|| assert_eq!(TLS.get_copied(), 3));
+    });
+}
+
+#[test]
+fn get_or_insert_drops_unused_value() {
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted(u32);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let tls: ThreadLocal<Counted> = ThreadLocal::new(|| Counted(0));
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            assert_eq!(tls.get_or_insert(Counted(1)).0, 1);
+            assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+
+            assert_eq!(tls.get_or_insert(Counted(2)).0, 1);
+            assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+        });
+    });
+}
+
+#[test]
+fn set_max_threads_refuses_extra_threads() {
+    let mut tls: ThreadLocal<u32> = ThreadLocal::new(|| 1);
+    tls.set_max_threads(2);
+    tls.get();
+
+    std::thread::scope(|s| {
+        s.spawn(|| assert_eq!(tls.try_get(), Ok(&1)));
+    });
+    std::thread::scope(|s| {
+        s.spawn(|| assert_eq!(tls.try_get(), Err(ThreadLocalError::ThreadLimit)));
+    });
+
+    assert_eq!(tls.try_get(), Ok(&1));
+    assert_eq!(tls.iter().count(), 2);
+}
+
+#[test]
+fn get_for_reads_a_parked_worker() {
+    use core::sync::atomic::AtomicU32;
+    use std::sync::{mpsc::channel, Barrier};
+
+    let tls: ThreadLocal<AtomicU32> = ThreadLocal::new(|| AtomicU32::new(0));
+    let (ready, parked) = channel();
+    let locked = Barrier::new(2);
+
+    std::thread::scope(|s| {
+        let worker = s.spawn(|| {
+            tls.get().store(42, Ordering::SeqCst);
+            ready.send(ThreadId::current()).unwrap();
+            locked.wait();
+            unsafe { tls.take() }.map(|v| v.into_inner())
+        });
+
+        let id = parked.recv().unwrap();
+        {
+            let value = tls.get_for(id).unwrap();
+            assert_eq!(value.load(Ordering::SeqCst), 42);
+            locked.wait();
+            // the worker cannot take its value while the guard is alive
+            assert!(!worker.is_finished());
+            assert_eq!(value.load(Ordering::SeqCst), 42);
+        }
+
+        assert_eq!(worker.join().unwrap(), Some(42));
+        assert!(tls.get_for(id).is_none());
+    });
+}
+
+#[test]
+fn export_c_fills_a_fixed_buffer() {
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Stats {
+        hits: u64,
+        misses: u64,
+    }
+
+    let tls: ThreadLocal<Stats> = ThreadLocal::new_indexed(vec![
+        Stats { hits: 1, misses: 0 },
+        Stats { hits: 2, misses: 2 },
+        Stats { hits: 3, misses: 3 },
+    ]);
+    tls.get();
+    std::thread::scope(|s| {
+        for _ in 2..4 {
+            s.spawn(|| tls.get());
+        }
+    });
+
+    let mut out = [Stats { hits: 9, misses: 9 }; 4];
+    let written = unsafe { tls.export_c(out.as_mut_ptr(), out.len()) };
+    assert_eq!(written, 3);
+    assert_eq!(out[0], Stats { hits: 1, misses: 0 });
+    let mut rest = [out[1].hits, out[2].hits];
+    rest.sort();
+    assert_eq!(rest, [2, 3]);
+    assert_eq!(out[3], Stats { hits: 9, misses: 9 });
+
+    assert_eq!(unsafe { tls.export_c(out.as_mut_ptr(), 1) }, 1);
+}
+
+#[test]
+fn clear_poison_allows_retrying() {
+    use core::sync::atomic::AtomicBool;
+
+    static FAIL: AtomicBool = AtomicBool::new(false);
+
+    let tls: ThreadLocal<u32> = ThreadLocal::new(|| {
+        if FAIL.load(Ordering::SeqCst) {
+            panic!("initialiser failed");
+        }
+        return 3;
+    });
+    FAIL.store(true, Ordering::SeqCst);
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            assert!(!tls.is_poisoned());
+
+            let get = || std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| *tls.get()));
+            assert!(get().is_err());
+            assert!(tls.is_poisoned());
+
+            FAIL.store(false, Ordering::SeqCst);
+            let err = get().unwrap_err();
+            let msg = err.downcast_ref::<String>().map(|s| s.as_str());
+            assert_eq!(msg, Some("ThreadLocal poisoned"));
+            assert!(tls.is_poisoned());
+
+            tls.clear_poison();
+            assert!(!tls.is_poisoned());
+            assert_eq!(get().unwrap(), 3);
+        });
+    });
+}
+
+#[test]
+fn replace_never_reallocates() {
+    use core::sync::atomic::AtomicBool;
+
+    static SEALED: AtomicBool = AtomicBool::new(false);
+
+    struct SealedAllocator;
+
+    impl Allocator for SealedAllocator {
+        fn allocate(size: usize) -> *mut u8 {
+            assert!(!SEALED.load(Ordering::SeqCst), "replace allocated");
+            private::DefaultAllocator::allocate(size)
+        }
+        fn deallocate(ptr: *mut u8) {
+            assert!(!SEALED.load(Ordering::SeqCst), "replace deallocated");
+            private::DefaultAllocator::deallocate(ptr)
+        }
+    }
+
+    static SMALL: ThreadLocal<u16, SealedAllocator> = ThreadLocal::const_new_in(1);
+    let big: ThreadLocal<String, SealedAllocator> = ThreadLocal::new_in(|| "a".to_string());
+    big.get();
+    // allocates under registry-backend, where values are never inline
+    assert_eq!(unsafe { SMALL.replace(1) }, 1);
+
+    SEALED.store(true, Ordering::SeqCst);
+    assert_eq!(unsafe { SMALL.replace(2) }, 1);
+    assert_eq!(unsafe { SMALL.replace(3) }, 2);
+    assert_eq!(unsafe { big.replace("b".to_string()) }, "a");
+    assert_eq!(unsafe { big.replace("c".to_string()) }, "b");
+    SEALED.store(false, Ordering::SeqCst);
+
+    assert_eq!(SMALL.get_copied(), 3);
+    assert_eq!(*big.get(), "c");
+}
+
+#[test]
+fn get_or_init_shared_runs_init_once() {
+    static INITS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut tls: ThreadLocal<Vec<u32>> = ThreadLocal::new(Vec::new);
+    let barrier = std::sync::Barrier::new(8);
+
+    std::thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| {
+                barrier.wait();
+                let value = tls.get_or_init_shared(|| {
+                    INITS.fetch_add(1, Ordering::SeqCst);
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    vec![1, 2, 3]
+                });
+                assert_eq!(*value, [1, 2, 3]);
+            });
+        }
+    });
+
+    assert_eq!(INITS.load(Ordering::SeqCst), 1);
+    assert_eq!(tls.iter().filter(|v| **v == [1, 2, 3]).count(), 8);
+}
+
+#[test]
+fn cleanup_drops_values_of_exited_threads() {
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted;
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let tls: Pin<Box<ThreadLocal<Counted>>> = Box::pin(ThreadLocal::new(|| Counted));
+    tls.as_ref().enable_cleanup();
+    tls.get();
+    assert_eq!(tls.len(), 1);
+
+    std::thread::scope(|s| {
+        // unlike the end of the scope, joining waits for the thread's destructors
+        s.spawn(|| {
+            tls.get();
+            assert_eq!(tls.len(), 2);
+        })
+        .join()
+        .unwrap();
+    });
+
+    assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    assert_eq!(tls.len(), 1);
+    assert_eq!(tls.map_all(|_| ()).len(), 1);
+    assert!(tls.leaked_thread_ids().is_empty());
+
+    drop(tls);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 2);
+}
+
+#[cfg(unix)]
+#[test]
+fn value_resurrected_during_cleanup_is_kept() {
+    /// makes the next value resurrect itself when dropped.
+    static ARMED: AtomicBool = AtomicBool::new(false);
+    static RESURRECTIONS: AtomicUsize = AtomicUsize::new(0);
+    static TLS: std::sync::LazyLock<ThreadLocal<Touch>> = std::sync::LazyLock::new(|| {
+        ThreadLocal::new(|| Touch(ARMED.swap(false, Ordering::SeqCst)))
+    });
+
+    struct Touch(bool);
+
+    impl Drop for Touch {
+        fn drop(&mut self) {
+            if self.0 {
+                RESURRECTIONS.fetch_add(1, Ordering::SeqCst);
+                // runs while the thread tears down its thread locals
+                assert!(!TLS.get().0);
+            }
+        }
+    }
+
+    std::thread::spawn(|| {
+        Pin::static_ref(&*TLS).enable_cleanup();
+        unsafe { TLS.deinitialize() };
+
+        ARMED.store(true, Ordering::SeqCst);
+        assert!(TLS.get().0);
+    })
+    .join()
+    .unwrap();
+
+    assert_eq!(RESURRECTIONS.load(Ordering::SeqCst), 1);
+    // only the resurrected value is left, never dropped by the static
+    assert_eq!(TLS.len(), 1);
+    assert!(TLS
+        .map_all(|value| value.0)
+        .iter()
+        .all(|(_, resurrected)| !resurrected));
+}
+
+#[cfg(unix)]
+#[test]
+fn drop_waits_for_initialisers_of_exiting_threads() {
+    use core::sync::atomic::AtomicPtr;
+
+    /// the instance values reach again while being dropped, null once dropping.
+    static TLS: AtomicPtr<ThreadLocal<Reinit>> = AtomicPtr::new(core::ptr::null_mut());
+
+    struct Reinit(bool);
+
+    impl Drop for Reinit {
+        fn drop(&mut self) {
+            let tls = TLS.load(Ordering::SeqCst);
+            if self.0 && !tls.is_null() {
+                // runs the initialiser from the exit hook
+                unsafe { (*tls).get() };
+            }
+        }
+    }
+
+    for _ in 0..8 {
+        let text: std::sync::Arc<str> = std::sync::Arc::from("initialiser state");
+        let tls = Box::pin(ThreadLocal::new(move || {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            assert_eq!(&*text, "initialiser state");
+            return Reinit(false);
+        }));
+        tls.as_ref().enable_cleanup();
+        TLS.store(&*tls as *const _ as *mut _, Ordering::SeqCst);
+
+        // the scope ends before the threads destroy their thread locals
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| drop(unsafe { tls.replace(Reinit(true)) }));
+            }
+        });
+
+        // may drop while exiting threads run the initialiser
+        TLS.store(core::ptr::null_mut(), Ordering::SeqCst);
+        drop(tls);
+    }
+}
+
+#[test]
+fn peek_returns_none_before_the_first_get() {
+    struct Peek<'a>(&'a ThreadLocal<u32>, Option<u32>);
+
+    impl Drop for Peek<'_> {
+        fn drop(&mut self) {
+            assert_eq!(self.0.peek().copied(), self.1);
+        }
+    }
+
+    let tls = ThreadLocal::new(|| 7);
+    tls.get();
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            drop(Peek(&tls, None));
+            assert_eq!(tls.len(), 1);
+
+            tls.get();
+            drop(Peek(&tls, Some(7)));
+        });
+    });
+
+    let mut inline: ThreadLocal<u32> = ThreadLocal::const_new(3);
+    inline.set(4);
+    assert_eq!(inline.try_with(|v| *v), Some(4));
+    assert_eq!(inline.peek().is_some(), !inline.is_inline());
+}
+
+#[test]
+fn try_with_does_not_initialise() {
+    let tls = ThreadLocal::new(|| 7);
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            // `new` only initialises the constructing thread
+            assert_eq!(tls.try_with(|v| *v), None);
+
+            tls.get();
+            assert_eq!(tls.try_with(|v| *v + 1), Some(8));
+
+            tls.invalidate();
+            assert_eq!(tls.try_with(|v| *v), None);
+        });
+    });
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn contention_stats_count_spins() {
+    let tls: ThreadLocal<u32> = ThreadLocal::new(|| 1);
+    tls.get();
+    let before = tls.contention_stats();
+
+    std::thread::scope(|s| {
+        // hold the lock so the workers spin while initialising
+        let held = tls.get_for(ThreadId::current()).unwrap();
+        let workers: Vec<_> = (0..4).map(|_| s.spawn(|| *tls.get())).collect();
+
+        // the stats are read without the lock, so wait for a worker to spin on it
+        while tls.contention_stats().spins == before.spins {
+            std::thread::yield_now();
+        }
+        drop(held);
+
+        for worker in workers {
+            assert_eq!(worker.join().unwrap(), 1);
+        }
+    });
+
+    let after = tls.contention_stats();
+    assert!(after.acquisitions > before.acquisitions);
+    assert!(after.spins > before.spins);
+}
+
+#[test]
+fn factory_is_shared_between_instances() {
+    use std::sync::Arc;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    let factory: Arc<dyn Fn() -> ThreadId + Send + Sync> = Arc::new(|| {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+        return ThreadId::current();
+    });
+    // the initialiser only captures the `Arc`, so it is stored in place
+    assert!(initialiser_fits::<Arc<dyn Fn() -> ThreadId + Send + Sync>>());
+
+    let instances: Vec<ThreadLocal<ThreadId>> = (0..3)
+        .map(|_| ThreadLocal::new_from_factory(factory.clone()))
+        .collect();
+    assert_eq!(Arc::strong_count(&factory), 4);
+
+    std::thread::scope(|s| {
+        for _ in 0..2 {
+            s.spawn(|| {
+                for tls in &instances {
+                    assert_eq!(*tls.get(), ThreadId::current());
+                    assert_eq!(*tls.get(), ThreadId::current());
+                }
+            });
+        }
+    });
+    for tls in &instances {
+        tls.get();
+    }
+
+    assert_eq!(CALLS.load(Ordering::SeqCst), 3 * 3);
+    drop(instances);
+    assert_eq!(Arc::strong_count(&factory), 1);
+}
+
+#[test]
+fn migrate_allocator_moves_live_values() {
+    type Counting = testing::CountingAllocator<0>;
+
+    let mut tls: ThreadLocal<String> = ThreadLocal::new(|| String::from("worker"));
+    tls.get_mut().push_str("-main");
+    std::thread::scope(|s| {
+        s.spawn(|| tls.get());
+        s.spawn(|| tls.get());
+    });
+
+    let mut migrated: ThreadLocal<String, Counting> = tls.migrate_allocator();
+    assert_eq!(Counting::allocs(), 3);
+
+    // the slot of the main thread still held the old pointer
+    assert_eq!(*migrated.get(), "worker-main");
+    assert_eq!(migrated.try_with(|v| v.len()), Some(11));
+    let mut values: Vec<&String> = migrated.iter().collect();
+    values.sort();
+    assert_eq!(values, ["worker", "worker", "worker-main"]);
+    assert_eq!(Counting::allocs(), 3);
+
+    drop(migrated);
+    assert_eq!(Counting::frees(), 3);
+}
+
+#[test]
+fn new_indexed_hands_out_values_in_order() {
+    let tls = ThreadLocal::new_indexed(vec!["io", "net", "disk", "timer"]);
+    assert_eq!(*tls.get(), "io");
+
+    for expected in ["net", "disk", "timer", "timer"] {
+        let value = std::thread::scope(|s| s.spawn(|| *tls.get()).join().unwrap());
+        assert_eq!(value, expected);
+    }
+    assert_eq!(*tls.get(), "io");
+}
+
+#[test]
+fn new_chained_sees_previous_value() {
+    let tls = ThreadLocal::new_chained(|prev: Option<&String>| match prev {
+        Some(prev) => format!("{}+", prev),
+        None => String::from("first"),
+    });
+    assert_eq!(tls.get(), "first");
+
+    let second = std::thread::scope(|s| s.spawn(|| tls.get().clone()).join().unwrap());
+    assert_eq!(second, "first+");
+    assert_eq!(tls.get(), "first");
+}
+
+#[test]
+fn shared_arc_is_seen_by_every_thread() {
+    use std::sync::Arc;
+
+    let counter = Arc::new(AtomicU64::new(0));
+    let shared = counter.clone();
+    let mut tls: ThreadLocal<Arc<AtomicU64>> = ThreadLocal::new(move || shared.clone());
+
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..100 {
+                    tls.get().fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        }
+    });
+    tls.get().fetch_add(1, Ordering::Relaxed);
+
+    // every value is a clone of the one `Arc`, so all see the final count
+    assert_eq!(tls.len(), 5);
+    for value in tls.iter() {
+        assert!(Arc::ptr_eq(value, &counter));
+        assert_eq!(value.load(Ordering::Relaxed), 401);
+    }
+    assert_eq!(Arc::strong_count(&counter), 2 + tls.len());
+}
+
+#[test]
+fn const_seed_is_dropped_once() {
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Clone)]
+    struct Seed(u32);
+
+    impl Drop for Seed {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let tls: ThreadLocal<Seed> = ThreadLocal::const_new_cloned(Seed(3));
+    assert!(!tls.is_inline());
+    assert_eq!(tls.get().0, 3);
+
+    std::thread::scope(|s| {
+        for _ in 0..3 {
+            s.spawn(|| assert_eq!(tls.get().0, 3));
+        }
+    });
+    assert_eq!(tls.len(), 4);
+
+    // the clone of one thread is dropped on its own, the seed stays
+    unsafe { tls.deinitialize() };
+    assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    assert_eq!(tls.get().0, 3);
+
+    drop(tls);
+    // four clones and the seed
+    assert_eq!(DROPS.load(Ordering::SeqCst), 6);
+}
+
+#[test]
+fn drain_moves_every_value_out() {
+    let mut tls: ThreadLocal<String> = ThreadLocal::new(|| String::from("main"));
+    tls.get();
+    std::thread::scope(|s| {
+        s.spawn(|| tls.get());
+        s.spawn(|| tls.get());
+    });
+
+    let mut drained: Vec<String> = tls.drain().collect();
+    drained.sort();
+    assert_eq!(drained, ["main", "main", "main"]);
+    assert!(tls.is_empty());
+
+    // the thread local is reusable afterwards
+    assert_eq!(tls.get(), "main");
+    assert_eq!(tls.len(), 1);
+}
+
+#[test]
+fn dropped_drain_frees_the_rest() {
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted;
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let mut tls: ThreadLocal<Counted> = ThreadLocal::new(|| Counted);
+    tls.get();
+    std::thread::scope(|s| {
+        for _ in 0..3 {
+            s.spawn(|| tls.get());
+        }
+    });
+
+    let mut drain = tls.drain();
+    let first = drain.next();
+    assert!(first.is_some());
+    // the value moved out is not dropped by the drain
+    assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+
+    drop(drain);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 3);
+    drop(first);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 4);
+    assert!(tls.is_empty());
+}
+
+#[test]
+fn initialiser_reads_another_thread_local() {
+    use core::cell::Cell;
+
+    static SEED: ThreadLocal<Cell<u32>> = ThreadLocal::const_new_cloned(Cell::new(1));
+
+    let inner: &'static ThreadLocal<u32> =
+        Box::leak(Box::new(ThreadLocal::new(|| SEED.get().get() + 1)));
+    let outer: ThreadLocal<u32> = ThreadLocal::new(move || *inner.get() * 10);
+    assert_eq!(*outer.get(), 20);
+
+    // on a new thread, every level is initialised from within the one above
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            SEED.get().set(4);
+            assert!(inner.try_with(|_| ()).is_none());
+            assert_eq!(*outer.get(), 50);
+            assert_eq!(*inner.get(), 5);
+        });
+    });
+
+    assert_eq!(*outer.get(), 20);
+    assert_eq!(inner.len(), 2);
+}
+
+#[test]
+fn get_returns_fallback_on_alloc_failure() {
+    static FAILING: AtomicBool = AtomicBool::new(true);
+    static EMERGENCY: u64 = 0;
+
+    struct FlakyAllocator;
+
+    impl Allocator for FlakyAllocator {
+        fn allocate(size: usize) -> *mut u8 {
+            if FAILING.load(Ordering::SeqCst) {
+                return core::ptr::null_mut();
+            }
+            private::DefaultAllocator::allocate(size)
+        }
+        fn deallocate(ptr: *mut u8) {
+            private::DefaultAllocator::deallocate(ptr)
+        }
+    }
+
+    let tls: ThreadLocal<u64, FlakyAllocator> = ThreadLocal::new_with_fallback_in(|| 7, &EMERGENCY);
+    assert!(core::ptr::eq(tls.get(), &EMERGENCY));
+    assert_eq!(tls.try_get().err(), Some(ThreadLocalError::AllocFailed));
+    assert!(tls.is_empty());
+
+    // the next access tries again
+    FAILING.store(false, Ordering::SeqCst);
+    assert_eq!(*tls.get(), 7);
+    assert_eq!(tls.len(), 1);
+}
+
+#[test]
+fn new_with_name_sees_thread_name() {
+    let tls = ThreadLocal::new_with_name(|name| name.map(String::from));
+
+    std::thread::scope(|s| {
+        let named = std::thread::Builder::new()
+            .name(String::from("logger"))
+            .spawn_scoped(s, || tls.get().clone())
+            .unwrap();
+        assert_eq!(named.join().unwrap().as_deref(), Some("logger"));
+
+        let unnamed = s.spawn(|| tls.get().clone());
+        assert_eq!(unnamed.join().unwrap(), None);
+    });
+}
+
+/// also meant for `cargo miri test` with `-Zmiri-tree-borrows`.
+#[test]
+fn shared_then_exclusive_access() {
+    let mut tls: ThreadLocal<Vec<u32>> = ThreadLocal::new(|| vec![1]);
+
+    let first = tls.get();
+    assert_eq!(first, &[1]);
+    unsafe { tls.mutate() }.push(2);
+    unsafe { tls.get_mut_shared() }.push(3);
+    assert_eq!(tls.get(), &[1, 2, 3]);
+    assert_eq!(tls.iter().next().unwrap(), &[1, 2, 3]);
+}
+
+#[test]
+fn reinit_if_refreshes_stale_values() {
+    use core::cell::Cell;
+
+    static INITS: AtomicUsize = AtomicUsize::new(0);
+
+    let tls = ThreadLocal::new(|| Cell::new(INITS.fetch_add(1, Ordering::SeqCst) * 100));
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            // nothing to check yet, so the predicate is not called
+            unsafe { tls.reinit_if(|_| unreachable!()) };
+            let first = tls.get().get();
+
+            unsafe { tls.reinit_if(|value| value.get() > first + 5) };
+            assert_eq!(tls.get().get(), first);
+
+            tls.get().set(first + 10);
+            unsafe { tls.reinit_if(|value| value.get() > first + 5) };
+            assert!(tls.get().get() > first + 10);
+        });
+    });
+}
+
+#[test]
+fn storage_layout_matches_allocations() {
+    static REQUESTED: AtomicUsize = AtomicUsize::new(0);
+
+    struct RecordingAllocator;
+
+    impl Allocator for RecordingAllocator {
+        fn allocate(size: usize) -> *mut u8 {
+            REQUESTED.store(size, Ordering::SeqCst);
+            private::DefaultAllocator::allocate(size)
+        }
+        fn deallocate(ptr: *mut u8) {
+            private::DefaultAllocator::deallocate(ptr)
+        }
+    }
+
+    let tls: ThreadLocal<[u64; 3], RecordingAllocator> = ThreadLocal::new_in(|| [1; 3]);
+    tls.get();
+    let layout = tls.storage_layout();
+
+    assert_eq!(layout.size(), REQUESTED.load(Ordering::SeqCst));
+    assert_eq!(layout.align(), core::mem::align_of::<u64>());
+    assert!(layout.size() >= 24);
 }
 
-impl<T: Default> Default for ThreadLocal<T> {
-    fn default() -> Self {
-        ThreadLocal::new(T::default)
+#[test]
+fn value_destructor_may_use_the_allocator() {
+    /// the `(address, size)` of every block not yet freed.
+    static LIVE: std::sync::Mutex<Vec<(usize, usize)>> = std::sync::Mutex::new(Vec::new());
+
+    struct TrackingAllocator;
+
+    impl Allocator for TrackingAllocator {
+        fn allocate(size: usize) -> *mut u8 {
+            let ptr = private::DefaultAllocator::allocate(size);
+            LIVE.lock().unwrap().push((ptr as usize, size));
+            return ptr;
+        }
+        fn deallocate(ptr: *mut u8) {
+            LIVE.lock()
+                .unwrap()
+                .retain(|&(addr, _)| addr != ptr as usize);
+            private::DefaultAllocator::deallocate(ptr)
+        }
     }
+
+    struct Scratch(u64);
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let addr = self as *const Self as usize;
+            let owned = LIVE
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|&(base, size)| (base..base + size).contains(&addr));
+            assert!(
+                owned,
+                "the block of a value was freed before its destructor ran"
+            );
+
+            let scratch = TrackingAllocator::allocate(64);
+            assert!(!scratch.is_null());
+            unsafe { scratch.write_bytes(self.0 as u8, 64) };
+            TrackingAllocator::deallocate(scratch);
+        }
+    }
+
+    let mut tls: ThreadLocal<Scratch, TrackingAllocator> = ThreadLocal::new_in(|| Scratch(2));
+    std::thread::scope(|s| {
+        s.spawn(|| tls.get());
+        s.spawn(|| tls.get());
+    });
+    tls.get();
+
+    tls.clear();
+    tls.get();
+    drop(tls);
+    assert!(LIVE.lock().unwrap().is_empty());
 }
 
-impl<T: core::fmt::Debug> core::fmt::Debug for ThreadLocal<T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        return self.get().fmt(f);
+#[test]
+fn collections_start_with_the_requested_capacity() {
+    let tls: ThreadLocal<Vec<u64>> = ThreadLocal::with_capacity_init(128);
+
+    assert!(tls.get().capacity() >= 128);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            assert!(tls.get().capacity() >= 128);
+            assert!(tls.get().is_empty());
+        });
+    });
+
+    let maps: ThreadLocal<std::collections::HashMap<u32, u32>> =
+        ThreadLocal::with_capacity_init(16);
+    assert!(maps.get().capacity() >= 16);
+}
+
+#[test]
+fn current_ptr_is_stable_until_take() {
+    /// blocks freed during the test, kept so that no address is reused.
+    static FREED: std::sync::Mutex<Vec<usize>> = std::sync::Mutex::new(Vec::new());
+
+    struct NoReuse;
+
+    impl Allocator for NoReuse {
+        fn allocate(size: usize) -> *mut u8 {
+            private::DefaultAllocator::allocate(size)
+        }
+        fn deallocate(ptr: *mut u8) {
+            FREED.lock().unwrap().push(ptr as usize);
+        }
+    }
+
+    let tls: ThreadLocal<u64, NoReuse> = ThreadLocal::new_in(|| 5);
+    let ptr = tls.current_ptr();
+    assert_eq!(ptr as *const u64, tls.get() as *const u64);
+
+    unsafe { tls.replace(6) };
+    unsafe { tls.reinit() };
+    assert_eq!(tls.current_ptr(), ptr);
+    assert_eq!(unsafe { *ptr }, 5);
+
+    assert_eq!(unsafe { tls.take() }, Some(5));
+    let moved = tls.current_ptr();
+    assert_ne!(moved, ptr);
+    assert_eq!(unsafe { *moved }, 5);
+
+    drop(tls);
+    for ptr in FREED.lock().unwrap().drain(..) {
+        private::DefaultAllocator::deallocate(ptr as *mut u8);
     }
 }
 
+#[cfg(debug_assertions)]
 #[test]
-fn t() {
-    let mut r: ThreadLocal<u8> = ThreadLocal::new(|| 6);
+fn max_threads_tripwire_fires() {
+    let tls: ThreadLocal<u32> = ThreadLocal::new(|| 1);
 
-    assert!(*r == 6);
-    *r = 7;
-    assert!(*r == 7);
-    *r = 8;
-    assert!(*r == 8);
+    tls.get();
+    std::thread::scope(|s| {
+        for _ in 0..2 {
+            s.spawn(|| tls.get());
+        }
+    });
+    tls.debug_assert_max_threads(3);
 
-    let mut r: ThreadLocal<u8> = ThreadLocal::const_new(6);
+    std::thread::scope(|s| {
+        s.spawn(|| tls.get());
+    });
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        tls.debug_assert_max_threads(3)
+    }));
+    assert!(result.is_err());
+}
 
-    assert!(*r == 6);
-    *r = 7;
-    assert!(*r == 7);
-    *r = 8;
-    assert!(*r == 8);
+#[test]
+fn frozen_thread_local_refuses_new_threads() {
+    let tls: ThreadLocal<u32> = ThreadLocal::new(|| 1);
+    tls.get();
+    tls.freeze();
+
+    std::thread::scope(|s| {
+        s.spawn(|| assert_eq!(tls.try_get(), Err(ThreadLocalError::Frozen)));
+    });
+    assert_eq!(*tls.get(), 1);
+    assert_eq!(tls.len(), 1);
+
+    tls.unfreeze();
+    std::thread::scope(|s| {
+        s.spawn(|| assert_eq!(tls.try_get(), Ok(&1)));
+    });
+    assert_eq!(tls.len(), 2);
+}
+
+#[test]
+fn map_all_pairs_results_with_threads() {
+    let tls: ThreadLocal<AtomicUsize> = ThreadLocal::new(|| AtomicUsize::new(0));
+
+    let mut ids = vec![ThreadId::current()];
+    tls.get().store(1, Ordering::Relaxed);
+    std::thread::scope(|s| {
+        let workers: Vec<_> = (2..=4)
+            .map(|n| {
+                let tls = &tls;
+                s.spawn(move || {
+                    tls.get().store(n, Ordering::Relaxed);
+                    return ThreadId::current();
+                })
+            })
+            .collect();
+        ids.extend(workers.into_iter().map(|w| w.join().unwrap()));
+    });
+
+    let doubled = tls.map_all(|counter| counter.load(Ordering::Relaxed) * 2);
+    let mut expected: Vec<_> = ids
+        .iter()
+        .zip([2, 4, 6, 8])
+        .map(|(id, n)| (*id, n))
+        .collect();
+    expected.sort();
+    assert_eq!(doubled, expected);
+}
+
+#[test]
+fn refcell_helpers_borrow_the_current_value() {
+    let tls: ThreadLocal<core::cell::RefCell<Vec<u32>>> = ThreadLocal::new(Default::default);
+
+    tls.borrow_mut().push(1);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            tls.borrow_mut().push(2);
+            assert_eq!(*tls.borrow(), [2]);
+        });
+    });
+    assert_eq!(*tls.borrow(), [1]);
+
+    let shared = tls.borrow();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(tls.borrow_mut())));
+    assert!(result.is_err());
+    drop(shared);
+}
+
+#[test]
+fn with_cell_reaches_the_current_cell() {
+    let tls: ThreadLocal<core::cell::Cell<u32>> = ThreadLocal::new(|| core::cell::Cell::new(1));
+
+    tls.with_cell(|cell| cell.set(cell.get() + 1));
+    std::thread::scope(|s| {
+        s.spawn(|| assert_eq!(tls.with_cell(|cell| cell.replace(5)), 1));
+    });
+    assert_eq!(tls.with_cell(core::cell::Cell::get), 2);
+}
+
+#[test]
+fn config_sizes_the_buffer_of_every_thread() {
+    #[derive(Clone)]
+    struct Config {
+        buffer_len: usize,
+    }
+
+    let tls = ThreadLocal::new_from_config(Config { buffer_len: 64 }, |config| {
+        Vec::<u8>::with_capacity(config.buffer_len)
+    });
+
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| assert!(tls.get().capacity() >= 64));
+        }
+    });
+    assert!(tls.get().capacity() >= 64);
+    assert_eq!(tls.len(), 5);
+}
+
+#[test]
+fn expired_value_is_reinitialised() {
+    static INITS: AtomicUsize = AtomicUsize::new(0);
+
+    let ttl = std::time::Duration::from_millis(100);
+    let tls = ThreadLocal::new_with_ttl(ttl, || INITS.fetch_add(1, Ordering::SeqCst));
+
+    let first = *tls.get();
+    assert_eq!(*tls.get(), first);
+
+    std::thread::sleep(ttl * 2);
+    let second = *tls.get();
+    assert_ne!(second, first);
+    assert_eq!(*tls.get(), second);
+    assert_eq!(tls.len(), 1);
+}
+
+#[test]
+fn new_threads_use_the_replaced_initialiser() {
+    let mut tls: ThreadLocal<String> = ThreadLocal::new(|| "old".to_string());
+    tls.get();
+
+    let big = [7u64; 8];
+    tls.set_initializer(move || format!("new {}", big.iter().sum::<u64>()));
+
+    // the value of the current thread is kept
+    assert_eq!(tls.get(), "old");
+    std::thread::scope(|s| {
+        s.spawn(|| assert_eq!(tls.get(), "new 56"));
+    });
+
+    unsafe { tls.reinit() };
+    assert_eq!(tls.get(), "new 56");
+}
+
+#[test]
+fn exited_worker_reports_dead() {
+    let tls: std::sync::Arc<ThreadLocal<u32>> = std::sync::Arc::new(ThreadLocal::new(|| 1));
+    tls.get();
+
+    // unlike a scope, joining waits for the thread locals of the worker
+    let worker = std::thread::spawn({
+        let tls = tls.clone();
+        move || {
+            tls.get();
+            return ThreadId::current();
+        }
+    })
+    .join()
+    .unwrap();
+
+    let mut status = Vec::new();
+    tls.for_each(|id, alive, _| status.push((id, alive)));
+    assert_eq!(status.len(), 2);
+    assert!(status.contains(&(ThreadId::current(), true)));
+    assert!(status.contains(&(worker, false)));
+}
+
+#[test]
+fn panicking_drop_does_not_leak_the_others() {
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Fragile(bool);
+
+    impl Drop for Fragile {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+            if self.0 {
+                panic!("bad destructor");
+            }
+        }
+    }
+
+    let fill = |tls: &ThreadLocal<Fragile>| {
+        tls.get();
+        std::thread::scope(|s| {
+            let fragile = s.spawn(|| core::mem::forget(unsafe { tls.replace(Fragile(true)) }));
+            let others: Vec<_> = (0..3)
+                .map(|_| {
+                    s.spawn(|| {
+                        tls.get();
+                    })
+                })
+                .collect();
+            // joining waits for the threads to exit, so they are seen as dead
+            fragile.join().unwrap();
+            others.into_iter().for_each(|w| w.join().unwrap());
+        });
+    };
+
+    let mut tls: ThreadLocal<Fragile> = ThreadLocal::new(|| Fragile(false));
+    fill(&tls);
+    let before = DROPS.load(Ordering::SeqCst);
+
+    // every value is dropped before the panic is resumed
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| tls.clear()));
+    assert!(result.is_err());
+    assert_eq!(DROPS.load(Ordering::SeqCst), before + 5);
+    assert!(tls.is_empty());
+
+    // values of the exited threads leave the registry before they drop
+    fill(&tls);
+    let before = DROPS.load(Ordering::SeqCst);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| tls.shrink_to_fit()));
+    assert!(result.is_err());
+    assert_eq!(DROPS.load(Ordering::SeqCst), before + 4);
+    assert_eq!(tls.len(), 1);
+    tls.shrink_to_fit();
+    assert_eq!(DROPS.load(Ordering::SeqCst), before + 4);
+
+    #[cfg(all(unix, feature = "fork"))]
+    unsafe {
+        fill(&tls);
+        let pid = libc::fork();
+
+        if pid == 0 {
+            // the child frees the values of the threads it lost
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| tls.len()));
+            let clean = result.is_err() && tls.len() == 1;
+            libc::_exit(if clean { 0 } else { 1 });
+        }
+
+        let mut status = 0;
+        assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| tls.shrink_to_fit()));
+        assert!(result.is_err());
+    }
+
+    fill(&tls);
+    let before = DROPS.load(Ordering::SeqCst);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(tls)));
+    assert!(result.is_err());
+    assert_eq!(DROPS.load(Ordering::SeqCst), before + 5);
+}
+
+#[test]
+fn iter_mut_unchecked_scatters_between_phases() {
+    use std::sync::Barrier;
+
+    let mut tls: ThreadLocal<u64> = ThreadLocal::new(|| 1);
+    let gathered = Barrier::new(4);
+    let scattered = Barrier::new(4);
+
+    std::thread::scope(|s| {
+        for _ in 0..3 {
+            s.spawn(|| {
+                tls.get();
+                gathered.wait();
+                // the coordinator writes the values while workers are parked
+                scattered.wait();
+                assert_eq!(*tls.get(), 10);
+            });
+        }
+
+        gathered.wait();
+        for value in unsafe { tls.iter_mut_unchecked() } {
+            *value *= 10;
+        }
+        scattered.wait();
+    });
+
+    assert_eq!(
+        tls.len(),
+        if cfg!(feature = "strictly-lazy") {
+            3
+        } else {
+            4
+        }
+    );
+    assert!(tls.iter().all(|v| *v == 10));
+}
+
+#[test]
+fn preallocated_threads_do_not_allocate() {
+    use std::sync::{Barrier, Mutex};
+
+    type Counting = testing::CountingAllocator<1>;
+
+    let tls: ThreadLocal<u64, Counting> = ThreadLocal::new_in(|| 9);
+    let ids = Mutex::new(Vec::new());
+    let registered = Barrier::new(5);
+    let preallocated = Barrier::new(5);
+
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                ids.lock().unwrap().push(ThreadId::current());
+                registered.wait();
+                preallocated.wait();
+
+                // the real-time phase
+                let before = Counting::allocs();
+                assert_eq!(*tls.get(), 9);
+                assert_eq!(Counting::allocs(), before);
+            });
+        }
+
+        registered.wait();
+        tls.preallocate(&ids.lock().unwrap());
+        assert_eq!(
+            tls.len(),
+            if cfg!(feature = "strictly-lazy") {
+                4
+            } else {
+                5
+            }
+        );
+        preallocated.wait();
+    });
+}
+
+#[cfg(not(feature = "registry-backend"))]
+#[test]
+fn custom_backend_holds_the_slots() {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    static KEYS: AtomicUsize = AtomicUsize::new(0);
+    static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+    std::thread_local! {
+        static SLOTS: RefCell<HashMap<usize, usize>> = RefCell::new(HashMap::new());
+    }
+
+    /// slots in a std thread local map, no platform keys involved.
+    struct MapBackend;
+
+    unsafe impl Backend for MapBackend {
+        type Key = usize;
+
+        unsafe fn create() -> Result<usize, ThreadLocalError> {
+            LIVE.fetch_add(1, Ordering::SeqCst);
+            return Ok(KEYS.fetch_add(1, Ordering::SeqCst));
+        }
+        unsafe fn get(key: usize) -> *mut u8 {
+            SLOTS.with(|slots| slots.borrow().get(&key).copied().unwrap_or(0) as *mut u8)
+        }
+        unsafe fn set(key: usize, value: *mut u8) {
+            SLOTS.with(|slots| slots.borrow_mut().insert(key, value as usize));
+        }
+        unsafe fn delete(_key: usize) {
+            LIVE.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    let mut tls: ThreadLocal<ThreadId, _, MapBackend> =
+        ThreadLocal::with_backend(ThreadId::current);
+    let main = *tls.get();
+
+    std::thread::scope(|s| {
+        s.spawn(|| assert_ne!(*tls.get(), main));
+    });
+    assert_eq!(*tls.get(), main);
+    assert_eq!(tls.iter().count(), 2);
+    assert_eq!(LIVE.load(Ordering::SeqCst), 1);
+
+    let key = KEYS.load(Ordering::SeqCst) - 1;
+    assert_eq!(
+        SLOTS.with(|slots| slots.borrow()[&key]),
+        tls.get() as *const _ as usize
+    );
+
+    drop(tls);
+    assert_eq!(LIVE.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn byte_buffer_is_written_and_read_per_thread() {
+    let tls: ThreadLocal<Vec<u8>> = ThreadLocal::new(|| vec![0; 4]);
+
+    unsafe { tls.bytes_mut().copy_from_slice(b"main") };
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            assert_eq!(tls.as_bytes(), [0; 4]);
+            unsafe { tls.bytes_mut()[0] = 1 };
+            assert_eq!(tls.as_bytes(), [1, 0, 0, 0]);
+        });
+    });
+    assert_eq!(tls.as_bytes(), b"main");
+
+    let capacity = tls.get().capacity();
+    unsafe { tls.clear_buffer() };
+    assert!(tls.as_bytes().is_empty());
+    assert_eq!(tls.get().capacity(), capacity);
+}
+
+#[test]
+fn failed_construction_deletes_the_key() {
+    type CountingBackend = testing::CountingBackend<0>;
+
+    struct NullAllocator;
+
+    impl Allocator for NullAllocator {
+        fn allocate(_size: usize) -> *mut u8 {
+            core::ptr::null_mut()
+        }
+        fn deallocate(_ptr: *mut u8) {}
+    }
+
+    let re = ThreadLocal::<u64, NullAllocator, CountingBackend>::try_new_in(|| 1);
+    #[cfg(not(feature = "strictly-lazy"))]
+    assert_eq!(re.err(), Some(ThreadLocalError::AllocFailed));
+    #[cfg(feature = "strictly-lazy")]
+    assert_eq!(
+        re.unwrap().try_get().err(),
+        Some(ThreadLocalError::AllocFailed)
+    );
+    assert_eq!(CountingBackend::live(), 0);
+
+    // an initialiser too large for its slot fails before any value
+    #[cfg(not(feature = "strictly-lazy"))]
+    {
+        let big = [0u8; 64];
+        let re =
+            ThreadLocal::<u64, NullAllocator, CountingBackend>::try_new_in(move || big[0] as u64);
+        assert_eq!(re.err(), Some(ThreadLocalError::AllocFailed));
+        assert_eq!(CountingBackend::live(), 0);
+    }
+}
+
+#[test]
+fn reset_take_returns_the_old_value_and_reseeds() {
+    let tls: ThreadLocal<Vec<u32>> = ThreadLocal::new(|| Vec::with_capacity(8));
+
+    let buffer = unsafe { tls.get_mut_shared() };
+    buffer.extend([1, 2, 3]);
+    let allocation = tls.get() as *const Vec<u32>;
+
+    assert_eq!(unsafe { tls.reset_take() }, Some(vec![1, 2, 3]));
+    assert!(tls.get().is_empty());
+    assert!(tls.get().capacity() >= 8);
+    assert_eq!(tls.get() as *const Vec<u32>, allocation);
+    assert_eq!(tls.len(), 1);
+
+    unsafe { tls.deinitialize() };
+    assert_eq!(unsafe { tls.reset_take() }, None);
+}
+
+#[test]
+fn memory_budget_refuses_the_third_value() {
+    let tls: ThreadLocal<[u64; 4]> = ThreadLocal::new(|| [0; 4]);
+    tls.get();
+
+    let per_value = tls.allocated_bytes();
+    assert!(per_value >= tls.storage_layout().size());
+    tls.set_memory_budget(2 * per_value);
+
+    std::thread::scope(|s| {
+        s.spawn(|| assert!(tls.try_get().is_ok()));
+    });
+    assert_eq!(tls.allocated_bytes(), 2 * per_value);
+    std::thread::scope(|s| {
+        s.spawn(|| assert_eq!(tls.try_get().err(), Some(ThreadLocalError::MemoryBudget)));
+    });
+    assert_eq!(tls.len(), 2);
+
+    tls.set_memory_budget(usize::MAX);
+    std::thread::scope(|s| {
+        s.spawn(|| assert!(tls.try_get().is_ok()));
+    });
+    assert_eq!(tls.len(), 3);
+}
+
+#[test]
+fn nested_thread_locals_are_torn_down_with_the_outer_one() {
+    type CountingBackend = testing::CountingBackend<1>;
+
+    static CREATED: AtomicUsize = AtomicUsize::new(0);
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted;
+
+    impl Counted {
+        fn new() -> Self {
+            CREATED.fetch_add(1, Ordering::SeqCst);
+            return Counted;
+        }
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    type Inner = ThreadLocal<Counted, private::DefaultAllocator, CountingBackend>;
+
+    let mut outer: ThreadLocal<Inner, private::DefaultAllocator, CountingBackend> =
+        ThreadLocal::with_backend(|| ThreadLocal::with_backend(Counted::new));
+    outer.get().get();
+
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                outer.get().get();
+            });
+        }
+    });
+    // every thread also reaches the inner instances of the others
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                outer.for_each(|_, _, inner| {
+                    inner.get();
+                });
+            });
+        }
+    });
+
+    assert_eq!(outer.len(), 5);
+    assert_eq!(outer.iter().map(|inner| inner.len()).sum::<usize>(), 25);
+    #[cfg(not(any(feature = "strictly-lazy", feature = "registry-backend")))]
+    assert_eq!(CountingBackend::live(), 6);
+
+    drop(outer);
+    assert_eq!(CountingBackend::live(), 0);
+    assert_eq!(CREATED.load(Ordering::SeqCst), 25);
+    assert_eq!(DROPPED.load(Ordering::SeqCst), 25);
+}
+
+#[test]
+fn invalidated_values_outlive_their_references() {
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted(Vec<u32>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let mut tls = ThreadLocal::new(|| Counted(vec![1, 2, 3]));
+    let old = tls.get().0.as_slice();
+
+    tls.invalidate();
+    let new = tls.get().0.as_slice();
+    assert_ne!(old.as_ptr(), new.as_ptr());
+    assert_eq!(old, [1, 2, 3]);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+    assert_eq!(tls.len(), 1);
+    assert_eq!(tls.allocated_bytes(), 2 * tls.bytes_per_value());
+
+    // nothing borrows the old value once `self` is borrowed mutably
+    tls.shrink_to_fit();
+    assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    assert_eq!(tls.allocated_bytes(), tls.bytes_per_value());
+    drop(tls);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn expired_values_outlive_their_references() {
+    let ttl = std::time::Duration::from_millis(10);
+    let mut tls = ThreadLocal::new_with_ttl(ttl, || vec![1u32, 2, 3]);
+
+    let old = tls.get().as_slice();
+    std::thread::sleep(ttl * 2);
+    let new = tls.get().as_slice();
+
+    assert_ne!(old.as_ptr(), new.as_ptr());
+    assert_eq!(old, [1, 2, 3]);
+    assert_eq!(tls.len(), 1);
+
+    tls.clear();
+    assert_eq!(tls.allocated_bytes(), 0);
+}
+
+#[test]
+fn accesses_from_for_each_fail_instead_of_deadlocking() {
+    let tls: ThreadLocal<u32> = ThreadLocal::new(|| 1);
+    tls.get();
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let mut visited = 0;
+            tls.for_each(|_, _, value| {
+                // the current thread has no value yet, which needs the lock
+                assert_eq!(tls.try_get(), Err(ThreadLocalError::RegistryLocked));
+                let len = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| tls.len()));
+                assert!(len.is_err());
+                visited += *value;
+            });
+            assert_eq!(visited, 1);
+
+            // the lock is released afterwards
+            assert_eq!(tls.try_get(), Ok(&1));
+            assert_eq!(tls.len(), 2);
+        });
+    });
+}
+
+#[test]
+fn replace_waits_for_readers_of_get_for() {
+    use std::sync::Barrier;
+
+    let tls: ThreadLocal<Vec<u32>> = ThreadLocal::new(|| vec![1]);
+    let barrier = Barrier::new(2);
+
+    std::thread::scope(|s| {
+        let worker = s.spawn(|| {
+            tls.get();
+            barrier.wait();
+            barrier.wait();
+            // blocks until the reference below is dropped
+            return unsafe { tls.replace(vec![2]) };
+        });
+
+        barrier.wait();
+        let id = tls
+            .map_all(|_| ())
+            .into_iter()
+            .map(|(id, _)| id)
+            .find(|id| *id != ThreadId::current());
+        let locked = tls.get_for(id.unwrap()).unwrap();
+        barrier.wait();
+
+        assert!(!worker.is_finished());
+        assert_eq!(*locked, [1]);
+        drop(locked);
+        assert_eq!(worker.join().unwrap(), [1]);
+    });
+}
+
+#[test]
+fn racing_initialisers_stay_within_max_threads() {
+    let (tls, refused) = testing::race_initialisers(|| 1u32, |tls| tls.set_max_threads(3));
+
+    assert_eq!(refused, [ThreadLocalError::ThreadLimit; 6]);
+    assert_eq!(tls.len(), 3);
+}
+
+#[test]
+fn racing_initialisers_stay_within_the_memory_budget() {
+    let mut per_value = 0;
+    let (tls, refused) = testing::race_initialisers(
+        || [0u64; 4],
+        |tls| {
+            per_value = tls.allocated_bytes();
+            tls.set_memory_budget(3 * per_value);
+        },
+    );
+
+    assert_eq!(refused, [ThreadLocalError::MemoryBudget; 6]);
+    assert_eq!(tls.allocated_bytes(), 3 * per_value);
+}
+
+#[test]
+fn panicking_preallocation_poisons_the_thread() {
+    use std::sync::{mpsc, Barrier};
+
+    static ARMED: AtomicBool = AtomicBool::new(false);
+
+    let tls: ThreadLocal<u64> = ThreadLocal::new(|| {
+        assert!(!ARMED.load(Ordering::SeqCst), "bad initialiser");
+        return 1;
+    });
+    let constructed = tls.len();
+    ARMED.store(true, Ordering::SeqCst);
+
+    let preallocated = Barrier::new(2);
+    let (id_tx, id_rx) = mpsc::channel();
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            id_tx.send(ThreadId::current()).unwrap();
+            preallocated.wait();
+            // the initialiser is not run a second time
+            assert_eq!(tls.try_get().err(), Some(ThreadLocalError::Poisoned));
+        });
+
+        let ids = [id_rx.recv().unwrap()];
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| tls.preallocate(&ids)));
+        assert!(result.is_err());
+        preallocated.wait();
+    });
+    assert_eq!(tls.len(), constructed);
+}
+
+#[test]
+fn peek_inside_for_each_does_not_panic() {
+    let tls: ThreadLocal<u32> = ThreadLocal::new(|| 1);
+    tls.get();
+    assert_eq!(tls.peek(), Some(&1));
+
+    // before an invalidation, the slot alone is trusted
+    let mut peeked = Vec::new();
+    tls.for_each(|_, _, _| peeked.push(tls.peek().copied()));
+    assert_eq!(peeked, [Some(1)]);
+
+    tls.invalidate();
+    tls.get();
+    peeked.clear();
+    tls.for_each(|_, _, _| peeked.push(tls.peek().copied()));
+    assert_eq!(peeked, [None]);
+    assert_eq!(tls.peek(), Some(&1));
+}
+
+#[test]
+fn set_copied_retires_borrowed_values() {
+    let inline: ThreadLocal<u32> = ThreadLocal::const_new(1);
+    let moved = inline.get();
+    inline.set_copied(2);
+    assert_eq!((*moved, inline.get_copied()), (1, 2));
+
+    let mut heap: ThreadLocal<u32> = ThreadLocal::new(|| 1);
+    let old = heap.get();
+    heap.set_copied(2);
+    assert_eq!(heap.update_copied(|n| n * 10), 20);
+    assert_eq!((*old, *heap.get()), (1, 20));
+    assert_eq!(heap.len(), 1);
+
+    // the retired values are freed once borrowed mutably
+    let retired = heap.allocated_bytes();
+    heap.shrink_to_fit();
+    assert!(heap.allocated_bytes() < retired);
+    assert_eq!(*heap.get(), 20);
 }
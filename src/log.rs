@@ -0,0 +1,83 @@
+use core::cell::UnsafeCell;
+
+use crate::{private, Allocator, ThreadLocal};
+
+/// Append-only per-thread logs, merged on demand.
+///
+/// every thread appends to a buffer of its own, so `push` takes no lock
+/// once the buffer of the thread exists. `collect` drains the buffers of
+/// all threads into one log, which needs exclusive access.
+pub struct ThreadLocalLog<T, A: Allocator = private::DefaultAllocator> {
+    buffers: ThreadLocal<UnsafeCell<Vec<T>>, A>,
+}
+
+impl<T: Send> ThreadLocalLog<T> {
+    /// creates an empty log.
+    pub fn new() -> Self {
+        Self::new_in()
+    }
+}
+
+impl<T: Send, A: Allocator> ThreadLocalLog<T, A> {
+    pub fn new_in() -> Self {
+        Self {
+            buffers: ThreadLocal::new_in(|| UnsafeCell::new(Vec::new())),
+        }
+    }
+
+    /// appends `item` to the buffer of the current thread.
+    pub fn push(&self, item: T) {
+        // only the current thread reaches its buffer while `self` is shared
+        unsafe { (*self.buffers.get().get()).push(item) };
+    }
+
+    /// drains the buffers of every thread, one thread after another in
+    /// order of thread id, keeping the order each thread pushed in.
+    ///
+    /// the buffers keep their capacity for later pushes.
+    pub fn collect(&mut self) -> Vec<T> {
+        let mut log = Vec::new();
+        for buffer in self.buffers.iter_mut() {
+            log.append(buffer.get_mut());
+        }
+        return log;
+    }
+}
+
+impl<T: Send> Default for ThreadLocalLog<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn pushes_of_every_thread_are_collected() {
+    let mut log = ThreadLocalLog::new();
+
+    log.push((0, 0));
+    std::thread::scope(|s| {
+        for thread in 1..=4 {
+            let log = &log;
+            s.spawn(move || {
+                for i in 0..100 {
+                    log.push((thread, i));
+                }
+            });
+        }
+    });
+
+    let merged = log.collect();
+    assert_eq!(merged.len(), 401);
+    for thread in 1..=4 {
+        let pushed: Vec<_> = merged
+            .iter()
+            .filter(|(t, _)| *t == thread)
+            .map(|(_, i)| *i)
+            .collect();
+        assert_eq!(pushed, (0..100).collect::<Vec<_>>());
+    }
+
+    assert!(log.collect().is_empty());
+    log.push((0, 1));
+    assert_eq!(log.collect(), [(0, 1)]);
+}
@@ -0,0 +1,123 @@
+//! An in-memory `Backend` simulating threads, for deterministic tests.
+//!
+//! Enabled by the `mock` feature. Each OS thread can pretend to be any
+//! number of simulated threads in turn by calling `set_current_thread`,
+//! which changes what `ThreadId::current` returns on that OS thread. Paired
+//! with `MockBackend`, a single test thread can then drive the lazy
+//! initialisation, iteration and drop logic of a `ThreadLocal` for many
+//! threads, without spawning any.
+//!
+//! ```rust
+//! use lazy_thread_local::mock::{self, MockBackend};
+//! use lazy_thread_local::ThreadLocal;
+//!
+//! mock::set_current_thread(1);
+//! let tls: ThreadLocal<u64, _, MockBackend> = ThreadLocal::with_backend(|| 0);
+//!
+//! for thread in 1..=3 {
+//!     mock::set_current_thread(thread);
+//!     tls.get();
+//! }
+//! assert_eq!(tls.len(), 3);
+//! mock::clear_current_thread();
+//! ```
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::{Backend, ThreadLocalError};
+
+/// simulated ids live above the ids handed out to real threads.
+const MOCK_BASE: u64 = 1 << 62;
+
+std::thread_local! {
+    static SIMULATED: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// makes the calling OS thread act as simulated thread `id`.
+pub fn set_current_thread(id: u64) {
+    SIMULATED.with(|current| current.set(Some(MOCK_BASE | id)));
+}
+
+/// makes the calling OS thread act as itself again.
+pub fn clear_current_thread() {
+    SIMULATED.with(|current| current.set(None));
+}
+
+/// the id of the simulated thread, if the calling thread simulates one.
+pub(crate) fn current_thread() -> Option<u64> {
+    SIMULATED.try_with(|current| current.get()).ok().flatten()
+}
+
+static NEXT_KEY: AtomicUsize = AtomicUsize::new(0);
+/// slot words by `(thread id, key)`.
+static SLOTS: Mutex<BTreeMap<(u64, usize), usize>> = Mutex::new(BTreeMap::new());
+
+fn slots() -> std::sync::MutexGuard<'static, BTreeMap<(u64, usize), usize>> {
+    SLOTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A backend keeping slots in memory, per simulated thread.
+pub struct MockBackend;
+
+unsafe impl Backend for MockBackend {
+    type Key = usize;
+
+    unsafe fn create() -> Result<usize, ThreadLocalError> {
+        return Ok(NEXT_KEY.fetch_add(1, Ordering::Relaxed));
+    }
+
+    unsafe fn get(key: usize) -> *mut u8 {
+        let thread = crate::ThreadId::current().as_u64();
+        return slots().get(&(thread, key)).copied().unwrap_or(0) as *mut u8;
+    }
+
+    unsafe fn set(key: usize, value: *mut u8) {
+        let thread = crate::ThreadId::current().as_u64();
+        slots().insert((thread, key), value as usize);
+    }
+
+    unsafe fn delete(key: usize) {
+        slots().retain(|&(_, k), _| k != key);
+    }
+}
+
+#[test]
+fn simulated_threads_are_dropped() {
+    use crate::{ThreadId, ThreadLocal};
+
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted(ThreadId);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    set_current_thread(0);
+    let tls: ThreadLocal<Counted, _, MockBackend> =
+        ThreadLocal::with_backend(|| Counted(ThreadId::current()));
+
+    for thread in 0..10 {
+        set_current_thread(thread);
+        assert_eq!(tls.get().0, ThreadId::current());
+        assert_eq!(tls.get().0.as_u64(), MOCK_BASE | thread);
+    }
+    assert_eq!(tls.len(), 10);
+
+    set_current_thread(4);
+    unsafe { tls.deinitialize() };
+    assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    assert_eq!(tls.len(), 9);
+
+    clear_current_thread();
+    assert_ne!(ThreadId::current().as_u64() & MOCK_BASE, MOCK_BASE);
+
+    drop(tls);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 10);
+    assert!(slots().is_empty());
+}
@@ -0,0 +1,164 @@
+//! an allocator placing values on the NUMA node of the allocating thread,
+//! enabled by the `numa` feature.
+
+#[cfg(target_os = "linux")]
+use core::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+use crate::Allocator;
+
+/// `mbind` has not been tried yet.
+#[cfg(target_os = "linux")]
+const MBIND_UNKNOWN: u8 = 0;
+#[cfg(target_os = "linux")]
+const MBIND_WORKS: u8 = 1;
+/// the kernel has no NUMA support, or the process may not use it.
+#[cfg(target_os = "linux")]
+const MBIND_FAILS: u8 = 2;
+
+#[cfg(target_os = "linux")]
+static MBIND: AtomicU8 = AtomicU8::new(MBIND_UNKNOWN);
+
+/// the address and length of every live NUMA bound mapping, sorted by address.
+static MAPPINGS: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+fn mappings() -> std::sync::MutexGuard<'static, Vec<(usize, usize)>> {
+    MAPPINGS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Allocates each value on the NUMA node local to the thread allocating it.
+///
+/// values are mapped with `mmap` and bound with `mbind(MPOL_LOCAL)` on
+/// Linux, so their pages come from the node of the thread touching them
+/// first, which is the thread the value belongs to. mappings are rounded
+/// up to whole pages. where `mbind` is unavailable, e.g. on other systems,
+/// kernels without NUMA or sandboxes forbidding it, values are allocated
+/// with `malloc` instead.
+///
+/// ```rust
+/// use lazy_thread_local::{NumaAllocator, ThreadLocal};
+///
+/// let tls: ThreadLocal<[u64; 512], NumaAllocator> = ThreadLocal::new_in(|| [0; 512]);
+/// assert_eq!(tls.get()[0], 0);
+/// ```
+pub struct NumaAllocator;
+
+/// maps `size` zeroed bytes bound to the local node, `None` if NUMA binding
+/// is unavailable.
+#[cfg(target_os = "linux")]
+fn map(size: usize) -> Option<*mut u8> {
+    /// allocate on the node of the CPU touching a page first.
+    const MPOL_LOCAL: libc::c_long = 4;
+
+    if MBIND.load(Ordering::Relaxed) == MBIND_FAILS || size == 0 {
+        return None;
+    }
+
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let len = size.checked_add(page - 1)? & !(page - 1);
+
+    unsafe {
+        let ptr = libc::mmap(
+            core::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+
+        let bound = libc::syscall(
+            libc::SYS_mbind,
+            ptr,
+            len,
+            MPOL_LOCAL,
+            core::ptr::null::<libc::c_ulong>(),
+            0 as libc::c_ulong,
+            0 as libc::c_uint,
+        );
+        if bound != 0 {
+            // later allocations go to malloc straight away
+            MBIND.store(MBIND_FAILS, Ordering::Relaxed);
+            libc::munmap(ptr, len);
+            return None;
+        }
+        MBIND.store(MBIND_WORKS, Ordering::Relaxed);
+
+        let mut mappings = mappings();
+        let idx = mappings.partition_point(|(addr, _)| *addr < ptr as usize);
+        mappings.insert(idx, (ptr as usize, len));
+
+        return Some(ptr as *mut u8);
+    }
+}
+
+/// memory cannot be bound to a node on this platform.
+#[cfg(not(target_os = "linux"))]
+fn map(_size: usize) -> Option<*mut u8> {
+    None
+}
+
+/// unmaps `ptr` if it was returned by `map`, reporting whether it was.
+fn unmap(ptr: *mut u8) -> bool {
+    let mut mappings = mappings();
+
+    match mappings.binary_search_by_key(&(ptr as usize), |(addr, _)| *addr) {
+        Ok(idx) => {
+            let (addr, len) = mappings.remove(idx);
+            unsafe { libc::munmap(addr as _, len) };
+            return true;
+        }
+        Err(_) => return false,
+    }
+}
+
+impl Allocator for NumaAllocator {
+    fn allocate(size: usize) -> *mut u8 {
+        if let Some(ptr) = map(size) {
+            return ptr;
+        }
+        unsafe { libc::malloc(size) as *mut u8 }
+    }
+    fn deallocate(ptr: *mut u8) {
+        if unmap(ptr) {
+            return;
+        }
+        unsafe { libc::free(ptr as _) };
+    }
+    fn allocate_zeroed(size: usize) -> *mut u8 {
+        // fresh mappings are zeroed
+        if let Some(ptr) = map(size) {
+            return ptr;
+        }
+        unsafe { libc::calloc(1, size) as *mut u8 }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn numa_values_allocate_with_or_without_binding() {
+    let ptr = NumaAllocator::allocate_zeroed(100);
+    assert!(!ptr.is_null());
+    unsafe {
+        assert!(core::slice::from_raw_parts(ptr, 100)
+            .iter()
+            .all(|b| *b == 0));
+        ptr.write_bytes(7, 100);
+    }
+    let mapped = mappings().iter().any(|(addr, _)| *addr == ptr as usize);
+    assert_eq!(mapped, MBIND.load(Ordering::Relaxed) == MBIND_WORKS);
+    NumaAllocator::deallocate(ptr);
+    assert!(!unmap(ptr));
+
+    let mut tls: crate::ThreadLocal<u64, NumaAllocator> = crate::ThreadLocal::new_in(|| 1);
+    tls.get();
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| assert_eq!(*tls.get(), 1));
+        }
+    });
+    assert_eq!(tls.iter().sum::<u64>(), 5);
+}
@@ -0,0 +1,458 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::thread_id::Liveness;
+use crate::{ThreadId, ThreadLocalError};
+
+/// the state of a thread's slot, kept apart from the slot pointer so that
+/// every pointer value stays usable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum State {
+    /// the initialiser is running on the thread.
+    Initialising,
+    /// the initialiser panicked on the thread.
+    Poisoned,
+    /// the thread holds a value.
+    Present,
+}
+
+/// a per-thread value owned by a `ThreadLocal`.
+pub(crate) struct Entry<T> {
+    pub thread_id: ThreadId,
+    /// null unless the state is `Present`.
+    pub value: *mut T,
+    pub state: State,
+    pub liveness: Liveness,
+    /// the generation of the `ThreadLocal` the value was created in.
+    pub generation: usize,
+    /// when the value was first seen by `ThreadLocal::new_with_ttl`.
+    pub created: Option<std::time::Instant>,
+}
+
+/// the per-thread values of a `ThreadLocal`, sorted by thread id.
+pub(crate) struct Registry<T> {
+    locked: AtomicBool,
+    /// the id of the thread holding the lock, 0 while it is free.
+    owner: AtomicU64,
+    entries: UnsafeCell<Vec<Entry<T>>>,
+    /// values replaced while references to them may still be alive.
+    retired: UnsafeCell<Vec<Entry<T>>>,
+    #[cfg(all(unix, feature = "fork"))]
+    fork_generation: core::sync::atomic::AtomicUsize,
+    #[cfg(feature = "metrics")]
+    acquisitions: core::sync::atomic::AtomicU64,
+    #[cfg(feature = "metrics")]
+    spins: core::sync::atomic::AtomicU64,
+}
+
+/// Counters of how often the registry lock of a `ThreadLocal` was taken.
+///
+/// obtained from `ThreadLocal::contention_stats`. a high ratio of spins to
+/// acquisitions means threads often wait on each other to initialise or
+/// iterate values.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ContentionStats {
+    /// times the lock was acquired.
+    pub acquisitions: u64,
+    /// failed attempts to acquire the lock while another thread held it.
+    pub spins: u64,
+}
+
+impl<T> Registry<T> {
+    pub const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            owner: AtomicU64::new(0),
+            entries: UnsafeCell::new(Vec::new()),
+            retired: UnsafeCell::new(Vec::new()),
+            #[cfg(all(unix, feature = "fork"))]
+            fork_generation: core::sync::atomic::AtomicUsize::new(0),
+            #[cfg(feature = "metrics")]
+            acquisitions: core::sync::atomic::AtomicU64::new(0),
+            #[cfg(feature = "metrics")]
+            spins: core::sync::atomic::AtomicU64::new(0),
+        }
+    }
+
+    /// returns the lock counters collected so far.
+    #[cfg(feature = "metrics")]
+    pub fn stats(&self) -> ContentionStats {
+        ContentionStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            spins: self.spins.load(Ordering::Relaxed),
+        }
+    }
+
+    /// locks the registry as `try_lock`, reporting whether the process
+    /// forked since it was last locked. the caller is expected to prune
+    /// stale entries.
+    #[cfg(all(unix, feature = "fork"))]
+    pub fn try_lock_checked(&self) -> Result<(RegistryGuard<'_, T>, bool), ThreadLocalError> {
+        crate::fork::install();
+
+        let generation = crate::fork::generation();
+        let seen = self.fork_generation.load(Ordering::Acquire);
+
+        if seen != generation
+            && self
+                .fork_generation
+                .compare_exchange(seen, generation, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            // the lock may be held by a thread that does not exist in the child
+            self.owner.store(0, Ordering::Relaxed);
+            self.locked.store(false, Ordering::Release);
+            return Ok((self.try_lock()?, true));
+        }
+
+        return Ok((self.try_lock()?, false));
+    }
+
+    /// locks the registry, failing instead of deadlocking if the current
+    /// thread holds it already, e.g. when `ThreadLocal::for_each` accesses
+    /// the same `ThreadLocal`.
+    pub fn try_lock(&self) -> Result<RegistryGuard<'_, T>, ThreadLocalError> {
+        let thread_id = ThreadId::current().as_u64();
+
+        // only the current thread ever stores its own id
+        if self.owner.load(Ordering::Relaxed) == thread_id {
+            return Err(ThreadLocalError::RegistryLocked);
+        }
+
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            #[cfg(feature = "metrics")]
+            self.spins.fetch_add(1, Ordering::Relaxed);
+            core::hint::spin_loop();
+        }
+        #[cfg(feature = "metrics")]
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        self.owner.store(thread_id, Ordering::Relaxed);
+
+        return Ok(RegistryGuard { registry: self });
+    }
+}
+
+pub(crate) struct RegistryGuard<'a, T> {
+    registry: &'a Registry<T>,
+}
+
+impl<T> RegistryGuard<'_, T> {
+    /// records the state of the current thread, keeping the entries sorted.
+    pub fn set_current(&mut self, state: State, value: *mut T) {
+        let thread_id = ThreadId::current();
+
+        match self.binary_search_by_key(&thread_id, |e| e.thread_id) {
+            Ok(idx) => {
+                self[idx].state = state;
+                self[idx].value = value;
+            }
+            Err(idx) => {
+                let liveness = Liveness::current();
+                let entry = Entry {
+                    thread_id,
+                    value,
+                    state,
+                    liveness,
+                    generation: 0,
+                    created: None,
+                };
+                (**self).insert(idx, entry)
+            }
+        }
+    }
+
+    /// records that the initialiser of `thread_id` panicked, unless the
+    /// thread holds a value by now.
+    pub fn poison(&mut self, thread_id: ThreadId) {
+        if thread_id == ThreadId::current() {
+            return self.set_current(State::Poisoned, core::ptr::null_mut());
+        }
+
+        match self.binary_search_by_key(&thread_id, |e| e.thread_id) {
+            Ok(idx) if self[idx].state == State::Present => {}
+            Ok(idx) => {
+                self[idx].state = State::Poisoned;
+                self[idx].value = core::ptr::null_mut();
+            }
+            Err(idx) => {
+                let entry = Entry {
+                    thread_id,
+                    value: core::ptr::null_mut(),
+                    state: State::Poisoned,
+                    liveness: Liveness::assumed_alive(),
+                    generation: 0,
+                    created: None,
+                };
+                (**self).insert(idx, entry);
+            }
+        }
+    }
+
+    /// records the value of the current thread, created in `generation`.
+    pub fn set_present(&mut self, value: *mut T, generation: usize) {
+        self.set_current(State::Present, value);
+
+        let thread_id = ThreadId::current();
+        if let Ok(idx) = self.binary_search_by_key(&thread_id, |e| e.thread_id) {
+            self[idx].generation = generation;
+            self[idx].created = None;
+        }
+        crate::trace::initialised::<T>(thread_id);
+    }
+
+    /// returns the state of the current thread, `None` if it has no entry.
+    pub fn current_state(&self) -> Option<State> {
+        let thread_id = ThreadId::current();
+        let idx = self
+            .binary_search_by_key(&thread_id, |e| e.thread_id)
+            .ok()?;
+        return Some(self[idx].state);
+    }
+
+    /// returns the generation of the current thread's value.
+    pub fn current_generation(&self) -> Option<usize> {
+        let thread_id = ThreadId::current();
+        let idx = self
+            .binary_search_by_key(&thread_id, |e| e.thread_id)
+            .ok()?;
+        return Some(self[idx].generation);
+    }
+
+    /// returns whether the value of the current thread is older than `ttl`
+    /// at `now`. values not dated by `mark_created` yet are not.
+    pub fn is_expired(&self, now: std::time::Instant, ttl: core::time::Duration) -> bool {
+        let thread_id = ThreadId::current();
+
+        match self.binary_search_by_key(&thread_id, |e| e.thread_id) {
+            // `Instant` never goes backwards, and saturates if it did
+            Ok(idx) => match self[idx].created {
+                Some(created) => return now.saturating_duration_since(created) > ttl,
+                None => return false,
+            },
+            Err(_) => return false,
+        }
+    }
+
+    /// dates the value of the current thread to `now`, unless it was
+    /// dated already.
+    pub fn mark_created(&mut self, now: std::time::Instant) {
+        let thread_id = ThreadId::current();
+
+        if let Ok(idx) = self.binary_search_by_key(&thread_id, |e| e.thread_id) {
+            self[idx].created.get_or_insert(now);
+        }
+    }
+
+    /// returns the value of the current thread, null if it has none.
+    pub fn current_value(&self) -> *mut T {
+        let thread_id = ThreadId::current();
+
+        match self.binary_search_by_key(&thread_id, |e| e.thread_id) {
+            Ok(idx) if self[idx].state == State::Present => return self[idx].value,
+            _ => return core::ptr::null_mut(),
+        }
+    }
+
+    /// records `value` for a thread other than the current one, unless it
+    /// already has an entry. returns whether it was recorded.
+    pub fn insert_for(&mut self, thread_id: ThreadId, value: *mut T, generation: usize) -> bool {
+        match self.binary_search_by_key(&thread_id, |e| e.thread_id) {
+            Ok(_) => return false,
+            Err(idx) => {
+                let entry = Entry {
+                    thread_id,
+                    value,
+                    state: State::Present,
+                    // refreshed once the thread takes the value over
+                    liveness: Liveness::assumed_alive(),
+                    generation,
+                    created: None,
+                };
+                (**self).insert(idx, entry);
+                crate::trace::initialised::<T>(thread_id);
+                return true;
+            }
+        }
+    }
+
+    /// takes over a value recorded for the current thread by `insert_for`,
+    /// returning null if there is none.
+    pub fn adopt_current(&mut self) -> *mut T {
+        let thread_id = ThreadId::current();
+
+        match self.binary_search_by_key(&thread_id, |e| e.thread_id) {
+            Ok(idx) if self[idx].state == State::Present => {
+                self[idx].liveness = Liveness::current();
+                return self[idx].value;
+            }
+            _ => return core::ptr::null_mut(),
+        }
+    }
+
+    /// marks the current thread as running its initialiser.
+    pub fn begin_init(&mut self) -> Result<(), ThreadLocalError> {
+        let thread_id = ThreadId::current();
+
+        if let Ok(idx) = self.binary_search_by_key(&thread_id, |e| e.thread_id) {
+            match self[idx].state {
+                State::Initialising => return Err(ThreadLocalError::RecursiveInit),
+                State::Poisoned => return Err(ThreadLocalError::Poisoned),
+                State::Present => {}
+            }
+        }
+
+        self.set_current(State::Initialising, core::ptr::null_mut());
+        return Ok(());
+    }
+
+    /// moves the value of the current thread to the retired values, which
+    /// the owning `ThreadLocal` frees once no reference to them is left.
+    pub fn retire_current(&mut self) {
+        let thread_id = ThreadId::current();
+
+        if let Ok(idx) = self.binary_search_by_key(&thread_id, |e| e.thread_id) {
+            let entry = self.remove(idx);
+            if !entry.value.is_null() {
+                self.retired().push(entry);
+            }
+        }
+    }
+
+    /// returns the values moved aside by `retire_current`.
+    pub fn retired(&mut self) -> &mut Vec<Entry<T>> {
+        unsafe { &mut *self.registry.retired.get() }
+    }
+
+    /// forgets the current thread.
+    pub fn remove_current(&mut self) {
+        let thread_id = ThreadId::current();
+
+        if let Ok(idx) = self.binary_search_by_key(&thread_id, |e| e.thread_id) {
+            self.remove(idx);
+        }
+    }
+}
+
+impl<T> core::ops::Deref for RegistryGuard<'_, T> {
+    type Target = Vec<Entry<T>>;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.registry.entries.get() }
+    }
+}
+
+impl<T> core::ops::DerefMut for RegistryGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.registry.entries.get() }
+    }
+}
+
+impl<T> Drop for RegistryGuard<'_, T> {
+    fn drop(&mut self) {
+        self.registry.owner.store(0, Ordering::Relaxed);
+        self.registry.locked.store(false, Ordering::Release);
+    }
+}
+
+/// A reference to the value of some thread, holding the registry lock.
+///
+/// while it is alive, the owning thread cannot drop, take, replace or
+/// reinitialise the value, and other threads cannot initialise theirs.
+/// obtained from `ThreadLocal::get_for`.
+pub struct LockedRef<'a, T> {
+    _guard: RegistryGuard<'a, T>,
+    value: &'a T,
+}
+
+impl<'a, T> LockedRef<'a, T> {
+    /// returns the reference to the value of `thread_id`, if it has one.
+    pub(crate) fn new(guard: RegistryGuard<'a, T>, thread_id: ThreadId) -> Option<Self> {
+        let idx = guard
+            .binary_search_by_key(&thread_id, |e| e.thread_id)
+            .ok()?;
+        let entry = &guard[idx];
+
+        if entry.state != State::Present {
+            return None;
+        }
+        // the value stays alive until the registry is unlocked
+        let value = unsafe { &*crate::cell_value(entry.value) };
+
+        return Some(Self {
+            _guard: guard,
+            value,
+        });
+    }
+}
+
+impl<T> core::ops::Deref for LockedRef<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+/// iterates the registry in thread id order while holding its lock.
+pub(crate) struct Iter<'a, T> {
+    guard: RegistryGuard<'a, T>,
+    index: usize,
+}
+
+impl<'a, T> Iter<'a, T> {
+    pub fn new(guard: RegistryGuard<'a, T>) -> Self {
+        Self { guard, index: 0 }
+    }
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    /// the owner of a value, whether it is still alive, and the value.
+    type Item = (ThreadId, bool, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = self.guard.get(self.index)?;
+            self.index += 1;
+
+            if entry.state == State::Present {
+                // values live until the registry is drained by the owning `ThreadLocal`
+                let alive = entry.liveness.is_alive();
+                return Some((entry.thread_id, alive, unsafe {
+                    &*crate::cell_value(entry.value)
+                }));
+            }
+        }
+    }
+}
+
+/// like `Iter`, but hands out the values mutably.
+pub(crate) struct IterMut<'a, T> {
+    guard: RegistryGuard<'a, T>,
+    index: usize,
+}
+
+impl<'a, T> IterMut<'a, T> {
+    /// the caller must ensure that no other reference to the values exists.
+    pub unsafe fn new(guard: RegistryGuard<'a, T>) -> Self {
+        Self { guard, index: 0 }
+    }
+}
+
+impl<'a, T> Iterator for IterMut<'a, T> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = self.guard.get(self.index)?;
+            self.index += 1;
+
+            if entry.state == State::Present {
+                // each entry is visited once, so the references stay unique
+                return Some(unsafe { &mut *crate::cell_value(entry.value) });
+            }
+        }
+    }
+}
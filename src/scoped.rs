@@ -0,0 +1,56 @@
+use core::marker::PhantomData;
+
+use crate::{private, Allocator, ThreadLocal, ThreadLocalInitialiser};
+
+/// A `ThreadLocal` whose initialiser borrows from its environment.
+///
+/// `ThreadLocal::new` requires a `'static` initialiser since the thread
+/// local may outlive anything it borrows. A scoped thread local carries the
+/// lifetime `'env` of the borrows instead, so it can be shared with the
+/// threads of `std::thread::scope`. Obtained from `ThreadLocal::new_scoped`.
+///
+/// It derefs to the underlying `ThreadLocal`, but never mutably, since the
+/// `ThreadLocal` could then be swapped out and outlive `'env`.
+pub struct ScopedThreadLocal<'env, T, A: Allocator = private::DefaultAllocator> {
+    inner: ThreadLocal<T, A>,
+    _env: PhantomData<&'env ()>,
+}
+
+impl<'env, T, A: Allocator> ScopedThreadLocal<'env, T, A> {
+    pub fn new_in<I: ThreadLocalInitialiser<T> + 'env>(init: I) -> Self {
+        Self {
+            // the initialiser is only reachable through `self`, which cannot outlive `'env`
+            inner: ThreadLocal::try_new_with(init, false, None).unwrap_or_else(|e| panic!("{}", e)),
+            _env: PhantomData,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    /// consumes the thread local, returning the value of the current thread.
+    pub fn into_inner(self) -> Option<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T, A: Allocator> core::ops::Deref for ScopedThreadLocal<'_, T, A> {
+    type Target = ThreadLocal<T, A>;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+#[test]
+fn initialiser_borrows_the_stack() {
+    let prefix = String::from("worker-");
+
+    let tls: ScopedThreadLocal<'_, String> = ThreadLocal::new_scoped(|| prefix.clone() + "0");
+
+    std::thread::scope(|s| {
+        s.spawn(|| assert_eq!(*tls.get(), "worker-0"));
+    });
+    assert_eq!(*tls.get(), "worker-0");
+    assert_eq!(tls.len(), 2);
+}
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+
+use crate::{private, Allocator, ThreadId, ThreadLocal, ThreadLocalInitialiser};
+
+/// A `ThreadLocal` split into several shards, each with its own registry.
+///
+/// threads initialising or iterating values lock the registry of a
+/// `ThreadLocal`, which becomes a bottleneck when many threads come and go.
+/// a sharded thread local assigns each thread to one shard by its id, so
+/// threads of different shards never wait on each other. aggregations such
+/// as `iter` and `fold` walk every shard in turn.
+///
+/// each shard is a `ThreadLocal` of its own, holding a platform key.
+/// obtained from `ThreadLocal::sharded`.
+pub struct ShardedThreadLocal<T, A: Allocator = private::DefaultAllocator> {
+    shards: Box<[ThreadLocal<T, A>]>,
+}
+
+impl<T, A: Allocator> ShardedThreadLocal<T, A> {
+    /// creates a thread local of `shards` shards sharing `init`.
+    ///
+    /// panics if `shards` is 0.
+    pub fn new_in<I>(init: I, shards: usize) -> Self
+    where
+        I: ThreadLocalInitialiser<T> + Send + Sync + 'static,
+    {
+        assert!(
+            shards > 0,
+            "a sharded thread local needs at least one shard"
+        );
+
+        let init = Arc::new(init);
+        let home = Self::index_of(ThreadId::current(), shards);
+
+        let shards = (0..shards)
+            .map(|idx| {
+                let init = init.clone();
+                let init = move || init.init();
+
+                // only the shard of the constructing thread is initialised eagerly
+                let shard = if idx == home {
+                    ThreadLocal::try_new_with(init, false, None)
+                } else {
+                    ThreadLocal::try_new_uninit(init, false, None)
+                };
+                return shard.unwrap_or_else(|e| panic!("{}", e));
+            })
+            .collect();
+
+        return Self { shards };
+    }
+
+    /// ids are handed out in sequence, so threads spread evenly over the shards.
+    fn index_of(id: ThreadId, shards: usize) -> usize {
+        (id.as_u64() % shards as u64) as usize
+    }
+
+    /// returns the shard of the current thread.
+    pub fn shard(&self) -> &ThreadLocal<T, A> {
+        &self.shards[Self::index_of(ThreadId::current(), self.shards.len())]
+    }
+
+    /// returns the number of shards.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn get(&self) -> &T {
+        self.shard().get()
+    }
+
+    /// runs `f` with the value of the current thread.
+    pub fn with<R, F: FnOnce(&T) -> R>(&self, f: F) -> R {
+        self.shard().with(f)
+    }
+
+    /// iterates the values of all threads, one shard after another.
+    ///
+    /// borrowing behaves as in `ThreadLocal::iter`.
+    pub fn iter(&mut self) -> impl Iterator<Item = &T>
+    where
+        T: Sync,
+    {
+        self.shards.iter_mut().flat_map(|shard| shard.iter())
+    }
+
+    /// folds the values of all threads, one shard after another.
+    ///
+    /// each shard stays locked while its values are visited, as in
+    /// `ThreadLocal::for_each`.
+    pub fn fold<B, F: FnMut(B, &T) -> B>(&self, init: B, mut f: F) -> B
+    where
+        T: Sync,
+    {
+        let mut acc = Some(init);
+        for shard in self.shards.iter() {
+            shard.for_each(|_, _, value| acc = acc.take().map(|acc| f(acc, value)));
+        }
+        // `acc` is only ever taken to be put back
+        return acc.unwrap();
+    }
+
+    /// returns the number of threads holding a value.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    /// returns whether no thread holds a value.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[test]
+fn shards_split_threads() {
+    let tls: ShardedThreadLocal<ThreadId> = ThreadLocal::sharded(ThreadId::current, 4);
+    let main = *tls.get();
+
+    std::thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| assert_eq!(*tls.get(), ThreadId::current()));
+        }
+    });
+
+    assert_eq!(tls.len(), 9);
+    assert_eq!(tls.fold(0, |count, id| count + (*id == main) as usize), 1);
+    assert!(tls.shards.iter().filter(|shard| !shard.is_empty()).count() > 1);
+}
@@ -0,0 +1,230 @@
+//! a backend sharing one per-thread table between all its instances.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::{Backend, ThreadId, ThreadLocalError};
+
+/// the slots of a thread by key index, with the generation of the key they
+/// were written for.
+type Table = Vec<(u32, usize)>;
+
+/// the index of every deleted key, with the generation of its next use.
+static FREE: Mutex<Vec<(u32, u32)>> = Mutex::new(Vec::new());
+/// the number of indices handed out so far.
+static INDICES: AtomicU32 = AtomicU32::new(0);
+/// slot words by `(thread id, key)` of threads whose table was freed.
+static LATE: Mutex<BTreeMap<(u64, u64), usize>> = Mutex::new(BTreeMap::new());
+
+fn free() -> std::sync::MutexGuard<'static, Vec<(u32, u32)>> {
+    FREE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn late() -> std::sync::MutexGuard<'static, BTreeMap<(u64, u64), usize>> {
+    LATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// the table of a thread once `FreeTable` freed it.
+const FREED: *mut Table = usize::MAX as *mut Table;
+
+/// frees the table of the thread when its thread locals are destroyed.
+struct FreeTable;
+
+impl Drop for FreeTable {
+    fn drop(&mut self) {
+        let table = TABLE.replace(FREED);
+        if !table.is_null() && table != FREED {
+            unsafe { drop(Box::from_raw(table)) };
+        }
+    }
+}
+
+std::thread_local! {
+    // without drop glue, the pointer stays readable while the thread
+    // destroys its other thread locals
+    static TABLE: Cell<*mut Table> = const { Cell::new(core::ptr::null_mut()) };
+    static GUARD: FreeTable = const { FreeTable };
+}
+
+/// runs `f` with the table of the current thread, `None` once it was freed.
+fn with_table<R>(f: impl FnOnce(&mut Table) -> R) -> Option<R> {
+    let mut table = TABLE.get();
+
+    if table == FREED {
+        return None;
+    }
+    if table.is_null() {
+        // the guard is gone, or going, when the thread is exiting already
+        if GUARD.try_with(|_| ()).is_err() {
+            TABLE.set(FREED);
+            return None;
+        }
+        table = Box::into_raw(Box::new(Table::new()));
+        TABLE.set(table);
+    }
+
+    // `f` never reaches the table again
+    return Some(f(unsafe { &mut *table }));
+}
+
+/// splits a key into its index and generation.
+fn split(key: u64) -> (usize, u32) {
+    return (key as u32 as usize, (key >> 32) as u32);
+}
+
+/// A backend keeping the slots of all its instances in one table per thread.
+///
+/// the default backend creates a platform key for every `ThreadLocal`, and
+/// platforms limit how many exist at once, e.g. 1024 on Linux. keys of this
+/// backend are indices into a table of the thread instead, held by a single
+/// `std::thread_local!`, so any number of instances can be alive, at the
+/// cost of a table lookup per access. threads accessing an instance after
+/// their table was freed on exit fall back to a global map, whose entries
+/// are kept until the key is deleted with its `ThreadLocal`.
+///
+/// ```rust
+/// use lazy_thread_local::{SharedKeyBackend, ThreadLocal};
+///
+/// let many: Vec<ThreadLocal<u32, _, SharedKeyBackend>> =
+///     (0..2000).map(|i| ThreadLocal::with_backend(move || i)).collect();
+/// assert_eq!(*many[1500].get(), 1500);
+/// ```
+pub struct SharedKeyBackend;
+
+unsafe impl Backend for SharedKeyBackend {
+    /// the index of the key in the low half, its generation in the high half.
+    type Key = u64;
+
+    unsafe fn create() -> Result<u64, ThreadLocalError> {
+        // a deleted index comes back under a new generation, so the stale
+        // slots other threads hold for it read as empty
+        let (index, generation) = match free().pop() {
+            Some(reused) => reused,
+            None => {
+                let index = INDICES
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_add(1))
+                    .map_err(|_| ThreadLocalError::KeyExhausted)?;
+                (index, 1)
+            }
+        };
+        return Ok(index as u64 | (generation as u64) << 32);
+    }
+
+    unsafe fn get(key: u64) -> *mut u8 {
+        let (index, generation) = split(key);
+
+        let word = with_table(|table| match table.get(index) {
+            Some(&(slot_generation, word)) if slot_generation == generation => word,
+            _ => 0,
+        });
+        let word = word.unwrap_or_else(|| {
+            let thread = ThreadId::current().as_u64();
+            return late().get(&(thread, key)).copied().unwrap_or(0);
+        });
+        return word as *mut u8;
+    }
+
+    unsafe fn set(key: u64, value: *mut u8) {
+        let (index, generation) = split(key);
+
+        let stored = with_table(|table| {
+            if table.len() <= index {
+                table.resize(index + 1, (0, 0));
+            }
+            table[index] = (generation, value as usize);
+        });
+        if stored.is_none() {
+            let thread = ThreadId::current().as_u64();
+            late().insert((thread, key), value as usize);
+        }
+    }
+
+    unsafe fn delete(key: u64) {
+        let (index, generation) = split(key);
+
+        late().retain(|&(_, k), _| k != key);
+        // an index whose generations ran out is retired
+        if let Some(next) = generation.checked_add(1) {
+            free().push((index as u32, next));
+        }
+    }
+}
+
+#[test]
+fn more_instances_than_platform_keys_stay_isolated() {
+    use crate::ThreadLocal;
+
+    let tls: Vec<ThreadLocal<Cell<u32>, _, SharedKeyBackend>> = (0..2000)
+        .map(|i| ThreadLocal::with_backend(move || Cell::new(i)))
+        .collect();
+
+    for (i, tls) in tls.iter().enumerate() {
+        assert_eq!(tls.get().get(), i as u32);
+    }
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for (i, tls) in tls.iter().enumerate() {
+                assert_eq!(tls.get().get(), i as u32);
+                tls.get().set(i as u32 + 1);
+            }
+            assert!(tls
+                .iter()
+                .enumerate()
+                .all(|(i, tls)| tls.get().get() == i as u32 + 1));
+        });
+    });
+    assert!(tls
+        .iter()
+        .enumerate()
+        .all(|(i, tls)| tls.get().get() == i as u32));
+    assert!(tls.iter().all(|tls| tls.len() == 2));
+
+    // reused indices start out empty on every thread
+    drop(tls);
+    let reused: Vec<ThreadLocal<u32, _, SharedKeyBackend>> =
+        (0..2000).map(|_| ThreadLocal::with_backend(|| 7)).collect();
+    assert!(reused.iter().all(|tls| *tls.get() == 7 && tls.len() == 1));
+}
+
+#[test]
+fn slots_stay_reachable_while_the_thread_exits() {
+    use crate::ThreadLocal;
+    use core::sync::atomic::AtomicUsize;
+    use std::sync::LazyLock;
+
+    /// the value of the exiting thread, from before it exited.
+    static VALUE: AtomicUsize = AtomicUsize::new(0);
+    static TLS: LazyLock<ThreadLocal<u32, crate::private::DefaultAllocator, SharedKeyBackend>> =
+        LazyLock::new(|| ThreadLocal::with_backend(|| 1));
+
+    /// accesses `TLS` while the thread destroys its thread locals.
+    struct Exiting;
+
+    impl Drop for Exiting {
+        fn drop(&mut self) {
+            // the registry still holds the value if the slot is gone
+            assert_eq!(
+                TLS.get() as *const u32 as usize,
+                VALUE.load(Ordering::SeqCst)
+            );
+        }
+    }
+
+    std::thread_local! {
+        static EXITING: Exiting = const { Exiting };
+    }
+
+    TLS.get();
+    std::thread::spawn(|| {
+        // registered before the table, so it may run after the table is freed
+        EXITING.with(|_| ());
+        VALUE.store(TLS.get() as *const u32 as usize, Ordering::SeqCst);
+    })
+    .join()
+    .unwrap();
+
+    // one value per thread, however late the thread reached it
+    assert_eq!(TLS.len(), 2);
+}
@@ -0,0 +1,70 @@
+//! serializing the values of all threads, enabled by the `serde` feature.
+
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+use crate::{Allocator, Backend, ThreadLocal};
+
+/// The values of all running threads, serialized as a map from thread id
+/// to value.
+///
+/// obtained from `ThreadLocal::serialize_all`. entries are sorted by thread
+/// id, and the registry stays locked while they are serialized, as in
+/// `ThreadLocal::for_each`.
+pub struct SnapshotSerialize<'a, T, A: Allocator, B: Backend>(&'a ThreadLocal<T, A, B>);
+
+impl<T: Serialize + Sync, A: Allocator, B: Backend> Serialize for SnapshotSerialize<'_, T, A, B> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        self.0.try_for_each(|id, alive, value| {
+            if alive {
+                map.serialize_entry(&id.as_u64(), value)?;
+            }
+            return Ok(());
+        })?;
+        return map.end();
+    }
+}
+
+impl<T: Serialize + Sync, A: Allocator, B: Backend> ThreadLocal<T, A, B> {
+    /// returns a handle serializing the values of all running threads, for
+    /// debugging dumps and crash reports.
+    ///
+    /// values of threads that have exited are left out.
+    pub fn serialize_all(&self) -> SnapshotSerialize<'_, T, A, B> {
+        SnapshotSerialize(self)
+    }
+}
+
+#[test]
+fn snapshot_round_trips_through_json() {
+    use std::collections::BTreeMap;
+
+    let tls: ThreadLocal<Vec<u64>> = ThreadLocal::new(|| vec![crate::ThreadId::current().as_u64()]);
+    tls.get();
+
+    let barrier = std::sync::Barrier::new(4);
+    std::thread::scope(|s| {
+        for _ in 0..3 {
+            s.spawn(|| {
+                tls.get();
+                // every thread stays alive until the snapshot is taken
+                barrier.wait();
+                barrier.wait();
+            });
+        }
+
+        barrier.wait();
+        let json = serde_json::to_string(&tls.serialize_all()).unwrap();
+        barrier.wait();
+
+        let parsed: BTreeMap<u64, Vec<u64>> = serde_json::from_str(&json).unwrap();
+        let expected: BTreeMap<u64, Vec<u64>> = tls
+            .map_all(|value| value.clone())
+            .into_iter()
+            .map(|(id, value)| (id.as_u64(), value))
+            .collect();
+        assert_eq!(parsed.len(), 4);
+        assert_eq!(parsed, expected);
+        assert!(parsed.iter().all(|(id, value)| value == &[*id]));
+    });
+}
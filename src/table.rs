@@ -0,0 +1,138 @@
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use crate::thread_id::current_index;
+use crate::ThreadId;
+
+/// bucket `i` holds `2^i` slots, which covers every index.
+const BUCKETS: usize = usize::BITS as usize;
+
+/// the slot word of one thread.
+struct Slot {
+    /// the thread owning the slot, 0 if none. indexes are reused, so a
+    /// slot left by an exited thread is ignored by its successor.
+    owner: AtomicU64,
+    word: AtomicUsize,
+}
+
+/// per-object slots indexed by thread index, replacing platform keys.
+///
+/// only the owning thread reads or writes a slot, so reads are lock free
+/// and buckets are only allocated when a thread with a higher index
+/// first writes.
+pub(crate) struct Table {
+    buckets: [AtomicPtr<Slot>; BUCKETS],
+}
+
+/// returns the bucket and offset of an index.
+fn locate(index: usize) -> (usize, usize) {
+    let position = index + 1;
+    let bucket = (usize::BITS - 1 - position.leading_zeros()) as usize;
+    return (bucket, position - (1 << bucket));
+}
+
+impl Table {
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const NULL: AtomicPtr<Slot> = AtomicPtr::new(core::ptr::null_mut());
+        Self {
+            buckets: [NULL; BUCKETS],
+        }
+    }
+
+    /// returns the slot of the current thread, allocating its bucket if `create`.
+    fn slot(&self, index: usize, create: bool) -> Option<&Slot> {
+        let (bucket, offset) = locate(index);
+        let mut ptr = self.buckets[bucket].load(Ordering::Acquire);
+
+        if ptr.is_null() {
+            if !create {
+                return None;
+            }
+
+            let slots: Box<[Slot]> = (0..1usize << bucket)
+                .map(|_| Slot {
+                    owner: AtomicU64::new(0),
+                    word: AtomicUsize::new(0),
+                })
+                .collect();
+            let new = Box::into_raw(slots) as *mut Slot;
+
+            match self.buckets[bucket].compare_exchange(
+                core::ptr::null_mut(),
+                new,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => ptr = new,
+                Err(current) => {
+                    // another thread allocated the bucket first
+                    unsafe { drop(Self::bucket_box(new, bucket)) };
+                    ptr = current;
+                }
+            }
+        }
+
+        return Some(unsafe { &*ptr.add(offset) });
+    }
+
+    unsafe fn bucket_box(ptr: *mut Slot, bucket: usize) -> Box<[Slot]> {
+        Box::from_raw(core::ptr::slice_from_raw_parts_mut(ptr, 1 << bucket))
+    }
+
+    /// reads the slot word of the current thread, `None` if the thread has
+    /// released its index or the slot was last written by another thread.
+    pub fn get(&self) -> Option<usize> {
+        let index = current_index()?;
+        let owner = ThreadId::current().as_u64();
+
+        match self.slot(index, false) {
+            Some(slot) if slot.owner.load(Ordering::Relaxed) == owner => {
+                return Some(slot.word.load(Ordering::Relaxed));
+            }
+            Some(slot) if slot.owner.load(Ordering::Relaxed) != 0 => return None,
+            _ => return Some(0),
+        }
+    }
+
+    /// writes the slot word of the current thread, doing nothing if the
+    /// thread has released its index.
+    pub fn set(&self, word: usize) {
+        let Some(index) = current_index() else {
+            return;
+        };
+
+        if let Some(slot) = self.slot(index, true) {
+            slot.owner
+                .store(ThreadId::current().as_u64(), Ordering::Relaxed);
+            slot.word.store(word, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        for (bucket, ptr) in self.buckets.iter_mut().enumerate() {
+            let ptr = *ptr.get_mut();
+            if !ptr.is_null() {
+                unsafe { drop(Self::bucket_box(ptr, bucket)) };
+            }
+        }
+    }
+}
+
+#[test]
+fn reused_index_starts_uninitialised() {
+    let tls: crate::ThreadLocal<ThreadId> = crate::ThreadLocal::new(ThreadId::current);
+
+    for _ in 0..8 {
+        std::thread::scope(|s| {
+            s.spawn(|| assert_eq!(*tls.get(), ThreadId::current()));
+        });
+    }
+
+    assert_eq!(locate(0), (0, 0));
+    assert_eq!(locate(1), (1, 0));
+    assert_eq!(locate(2), (1, 1));
+    assert_eq!(locate(6), (2, 3));
+    assert_eq!(locate(7), (3, 0));
+}
@@ -0,0 +1,108 @@
+//! fixtures shared by the tests of several modules.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{private, Allocator, Backend, ThreadLocal, ThreadLocalError};
+
+/// the live keys of every `CountingBackend`, by its `ID`.
+static LIVE: [AtomicUsize; 4] = [const { AtomicUsize::new(0) }; 4];
+
+/// The default backend, counting its live keys.
+///
+/// tests run in parallel, so each test counting keys picks its own `ID`.
+pub(crate) struct CountingBackend<const ID: usize>;
+
+impl<const ID: usize> CountingBackend<ID> {
+    /// returns the number of keys created and not yet deleted.
+    pub(crate) fn live() -> usize {
+        return LIVE[ID].load(Ordering::SeqCst);
+    }
+}
+
+unsafe impl<const ID: usize> Backend for CountingBackend<ID> {
+    type Key = <private::DefaultBackend as Backend>::Key;
+
+    unsafe fn create() -> Result<Self::Key, ThreadLocalError> {
+        let key = private::DefaultBackend::create()?;
+        LIVE[ID].fetch_add(1, Ordering::SeqCst);
+        return Ok(key);
+    }
+    unsafe fn get(key: Self::Key) -> *mut u8 {
+        private::DefaultBackend::get(key)
+    }
+    unsafe fn set(key: Self::Key, value: *mut u8) {
+        private::DefaultBackend::set(key, value)
+    }
+    unsafe fn delete(key: Self::Key) {
+        LIVE[ID].fetch_sub(1, Ordering::SeqCst);
+        private::DefaultBackend::delete(key)
+    }
+}
+
+/// the blocks allocated and freed by every `CountingAllocator`, by its `ID`.
+static ALLOCS: [AtomicUsize; 4] = [const { AtomicUsize::new(0) }; 4];
+static FREES: [AtomicUsize; 4] = [const { AtomicUsize::new(0) }; 4];
+
+/// The default allocator, counting its allocations and frees.
+///
+/// as with `CountingBackend`, each test counting blocks picks its own `ID`.
+pub(crate) struct CountingAllocator<const ID: usize>;
+
+impl<const ID: usize> CountingAllocator<ID> {
+    /// returns the number of blocks allocated so far.
+    pub(crate) fn allocs() -> usize {
+        return ALLOCS[ID].load(Ordering::SeqCst);
+    }
+
+    /// returns the number of blocks freed so far.
+    pub(crate) fn frees() -> usize {
+        return FREES[ID].load(Ordering::SeqCst);
+    }
+}
+
+impl<const ID: usize> Allocator for CountingAllocator<ID> {
+    fn allocate(size: usize) -> *mut u8 {
+        ALLOCS[ID].fetch_add(1, Ordering::SeqCst);
+        private::DefaultAllocator::allocate(size)
+    }
+    fn deallocate(ptr: *mut u8) {
+        FREES[ID].fetch_add(1, Ordering::SeqCst);
+        private::DefaultAllocator::deallocate(ptr)
+    }
+}
+
+/// races eight threads into a new `ThreadLocal` once `limit` has been set on
+/// it, returning it with the errors of the threads refused a value.
+///
+/// the calling thread holds the first value. the initialiser is slowed down
+/// to keep every racing thread inside it at once.
+pub(crate) fn race_initialisers<T: Send>(
+    init: impl Fn() -> T + Send + Sync + 'static,
+    limit: impl FnOnce(&ThreadLocal<T>),
+) -> (ThreadLocal<T>, Vec<ThreadLocalError>) {
+    use std::sync::Barrier;
+
+    let tls = ThreadLocal::new(move || {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        return init();
+    });
+    tls.get();
+    limit(&tls);
+
+    let barrier = Barrier::new(8);
+    let refused = std::thread::scope(|s| {
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                s.spawn(|| {
+                    barrier.wait();
+                    return tls.try_get().err();
+                })
+            })
+            .collect();
+        return workers
+            .into_iter()
+            .filter_map(|w| w.join().unwrap())
+            .collect();
+    });
+    return (tls, refused);
+}
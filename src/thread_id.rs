@@ -0,0 +1,199 @@
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// A unique identifier of a thread.
+///
+/// Ids are handed out in the order threads first ask for one and are never
+/// reused, so they also sort threads by their first use of this crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+std::thread_local! {
+    static CURRENT: Cell<u64> = const { Cell::new(0) };
+}
+
+/// A source of thread ids.
+///
+/// By default ids are handed out by the crate and cached in std thread
+/// locals. Hosts that know their threads better, such as a wasm embedder,
+/// can provide their own with `set_thread_id_source`. the crate still
+/// requires std either way.
+pub trait ThreadIdSource {
+    /// returns the id of the calling thread.
+    ///
+    /// ids must not be 0, must differ between threads and must never be
+    /// reused, since values stay associated with the id of their thread.
+    fn current_thread_id() -> u64;
+}
+
+/// The default `ThreadIdSource`, built on std thread locals.
+pub struct StdThreadIdSource;
+
+impl ThreadIdSource for StdThreadIdSource {
+    fn current_thread_id() -> u64 {
+        CURRENT.with(|id| {
+            if id.get() == 0 {
+                id.set(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+            }
+            return id.get();
+        })
+    }
+}
+
+/// the `current_thread_id` of a custom source, null for the std source.
+static SOURCE: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// makes `S` the source of every `ThreadId`.
+///
+/// this must be called before any `ThreadLocal` is accessed, since ids of
+/// different sources may collide.
+pub fn set_thread_id_source<S: ThreadIdSource>() {
+    let source: fn() -> u64 = S::current_thread_id;
+    SOURCE.store(source as *mut (), Ordering::Release);
+}
+
+impl ThreadId {
+    /// returns the id of the calling thread.
+    pub fn current() -> Self {
+        #[cfg(feature = "mock")]
+        if let Some(id) = crate::mock::current_thread() {
+            return ThreadId(id);
+        }
+
+        let source = SOURCE.load(Ordering::Acquire);
+        if source.is_null() {
+            return ThreadId(StdThreadIdSource::current_thread_id());
+        }
+
+        let source: fn() -> u64 = unsafe { core::mem::transmute(source) };
+        return ThreadId(source());
+    }
+
+    #[cfg(all(unix, feature = "fork"))]
+    pub(crate) fn from_u64(id: u64) -> Self {
+        ThreadId(id)
+    }
+
+    /// returns the raw value of the id.
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// reports whether a thread is still running.
+#[derive(Clone)]
+pub(crate) struct Liveness(Arc<AtomicBool>);
+
+/// flips the liveness flag of its thread when thread locals are destroyed.
+struct ExitHook(Arc<AtomicBool>);
+
+impl Drop for ExitHook {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+std::thread_local! {
+    static EXIT_HOOK: ExitHook = ExitHook(Arc::new(AtomicBool::new(true)));
+}
+
+impl Liveness {
+    /// returns the liveness handle of the calling thread.
+    pub fn current() -> Self {
+        EXIT_HOOK
+            .try_with(|hook| Liveness(hook.0.clone()))
+            // the thread is already tearing down its thread locals
+            .unwrap_or_else(|_| Liveness(Arc::new(AtomicBool::new(false))))
+    }
+
+    /// returns a handle for a thread that has not been seen yet.
+    pub fn assumed_alive() -> Self {
+        Liveness(Arc::new(AtomicBool::new(true)))
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// indexes released by exited threads, handed out again before new ones.
+#[cfg(feature = "registry-backend")]
+static FREE_INDEXES: std::sync::Mutex<Vec<usize>> = std::sync::Mutex::new(Vec::new());
+#[cfg(feature = "registry-backend")]
+static NEXT_INDEX: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+/// owns the index of its thread, releasing it once thread locals are destroyed.
+#[cfg(feature = "registry-backend")]
+struct IndexHook(usize);
+
+#[cfg(feature = "registry-backend")]
+impl Drop for IndexHook {
+    fn drop(&mut self) {
+        FREE_INDEXES
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(self.0);
+    }
+}
+
+#[cfg(feature = "registry-backend")]
+std::thread_local! {
+    static INDEX: IndexHook = IndexHook(
+        FREE_INDEXES
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop()
+            .unwrap_or_else(|| NEXT_INDEX.fetch_add(1, Ordering::Relaxed)),
+    );
+}
+
+/// returns a small index unique among running threads, or `None` once the
+/// calling thread has released it while tearing down.
+///
+/// indexes are reused, so they only identify a thread together with its
+/// `ThreadId`.
+#[cfg(feature = "registry-backend")]
+pub(crate) fn current_index() -> Option<usize> {
+    INDEX.try_with(|hook| hook.0).ok()
+}
+
+#[cfg(unix)]
+#[test]
+fn custom_source_provides_ids() {
+    struct HostIds;
+
+    impl ThreadIdSource for HostIds {
+        fn current_thread_id() -> u64 {
+            std::thread_local! {
+                static HOST_ID: u64 = HOST_NEXT.fetch_add(1, Ordering::Relaxed);
+            }
+            return HOST_ID.with(|id| *id);
+        }
+    }
+
+    static HOST_NEXT: AtomicU64 = AtomicU64::new(1 << 40);
+
+    unsafe {
+        // the source is process wide, so switch it in a child
+        let pid = libc::fork();
+
+        if pid == 0 {
+            set_thread_id_source::<HostIds>();
+
+            let tls: crate::ThreadLocal<ThreadId> = crate::ThreadLocal::new(ThreadId::current);
+            let main = *tls.get();
+            let other = std::thread::spawn(ThreadId::current).join().unwrap();
+
+            let ok = main.as_u64() == 1 << 40 && other.as_u64() == (1 << 40) + 1;
+            libc::_exit(if ok { 0 } else { 1 });
+        }
+
+        let mut status = 0;
+        assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+}
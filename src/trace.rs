@@ -0,0 +1,48 @@
+//! lifecycle events of per-thread values, emitted with the `tracing` feature.
+//!
+//! without the feature these functions are empty and compile to nothing.
+
+use crate::ThreadId;
+
+/// records that a value of `T` was initialised for `thread`.
+#[inline(always)]
+#[cfg_attr(not(feature = "tracing"), allow(clippy::extra_unused_type_parameters))]
+pub(crate) fn initialised<T>(thread: ThreadId) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(
+        thread = thread.as_u64(),
+        ty = core::any::type_name::<T>(),
+        "thread local value initialised"
+    );
+    #[cfg(not(feature = "tracing"))]
+    let _ = thread;
+}
+
+/// records that the value of `T` of `thread` is being dropped.
+#[inline(always)]
+#[cfg_attr(not(feature = "tracing"), allow(clippy::extra_unused_type_parameters))]
+pub(crate) fn dropped<T>(thread: ThreadId) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(
+        thread = thread.as_u64(),
+        ty = core::any::type_name::<T>(),
+        "thread local value dropped"
+    );
+    #[cfg(not(feature = "tracing"))]
+    let _ = thread;
+}
+
+#[cfg(all(test, feature = "tracing"))]
+#[tracing_test::traced_test]
+#[test]
+fn first_get_emits_init_event() {
+    // events are captured within the span of the test thread
+    let tls: crate::ThreadLocal<u64> = crate::ThreadLocal::new(|| 1);
+    assert_eq!(*tls.get(), 1);
+
+    assert!(logs_contain("thread local value initialised"));
+    assert!(logs_contain("ty=\"u64\""));
+
+    drop(tls);
+    assert!(logs_contain("thread local value dropped"));
+}
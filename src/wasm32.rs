@@ -1,13 +1,17 @@
 use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
-use crate::Allocator;
-use crate::ThreadLocal;
+use crate::ThreadId;
+use crate::ThreadLocalError;
 
+/// the value a thread holds for a key.
+///
+/// a key is a single process wide id owned by one `ThreadLocal`, while
+/// values are stored per `(thread_id, key)` pair.
 struct KeyStore {
-    thread_id: u64,
+    thread_id: ThreadId,
     key: usize,
     value: usize,
-    dtor: Option<unsafe extern "C" fn(*mut u8)>,
 }
 
 impl PartialEq for KeyStore {
@@ -20,11 +24,7 @@ impl Eq for KeyStore {}
 
 impl PartialOrd for KeyStore {
     fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
-        if self.thread_id == other.thread_id {
-            return self.key.partial_cmp(&other.key);
-        }
-
-        return self.thread_id.partial_cmp(&other.thread_id);
+        Some(self.cmp(other))
     }
 }
 
@@ -37,122 +37,153 @@ impl Ord for KeyStore {
     }
 }
 
-static mut KEYS: Vec<KeyStore> = Vec::new();
-static mut RECYCLE_KEYS: Vec<usize> = Vec::new();
-static KEY_COUNT: AtomicUsize = AtomicUsize::new(0);
+static KEYS: Mutex<Vec<KeyStore>> = Mutex::new(Vec::new());
+static KEY_ALLOCATOR: KeyAllocator = KeyAllocator::new(usize::MAX);
 
-impl<T, A: Allocator> ThreadLocal<T, A> {
-    unsafe fn create_key() -> usize {
-        unsafe extern "C" fn dtor<T, A: Allocator>(ptr: *mut u8) {
-            if ptr.is_null() {
-                return;
-            }
-            let ptr = ptr as *mut T;
-            core::ptr::drop_in_place(ptr);
-            A::deallocate(ptr as _);
-        }
-
-        let id: u64 = core::mem::transmute(std::thread::current().id());
-        let key = KEY_COUNT.fetch_add(1, Ordering::SeqCst);
+fn keys() -> std::sync::MutexGuard<'static, Vec<KeyStore>> {
+    KEYS.lock().unwrap_or_else(|e| e.into_inner())
+}
 
-        let store = KeyStore {
-            thread_id: id,
-            key,
-            value: 0,
-            dtor: Some(dtor::<T, A>),
-        };
+/// hands out keys below `limit`, reusing deleted keys first.
+struct KeyAllocator {
+    count: AtomicUsize,
+    recycled: Mutex<Vec<usize>>,
+    limit: usize,
+}
 
-        match KEYS.binary_search(&store) {
-            Err(idx) => {
-                KEYS.insert(idx, store);
-            }
-            // key already used
-            Ok(_) => {
-                // try to get from recycled keys
-                if let Some(key) = RECYCLE_KEYS.pop() {
-                    return key;
-                } else {
-                    // key overflow
-                    panic!("thread local keys exceeded usize::MAX")
-                }
-            }
+impl KeyAllocator {
+    const fn new(limit: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            recycled: Mutex::new(Vec::new()),
+            limit,
         }
-        return key;
     }
 
-    unsafe fn get_key(key: usize) -> *mut T {
-        unsafe extern "C" fn dtor<T, A: Allocator>(ptr: *mut u8) {
-            if ptr.is_null() {
-                return;
-            }
-            let ptr = ptr as *mut T;
-            core::ptr::drop_in_place(ptr);
-            A::deallocate(ptr as _);
+    fn allocate(&self) -> Result<usize, ThreadLocalError> {
+        if let Some(key) = self
+            .recycled
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop()
+        {
+            return Ok(key);
         }
 
-        let thread_id: u64 = core::mem::transmute(std::thread::current().id());
+        // never wraps, so a fresh key is never in use
+        return self
+            .count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                if count < self.limit {
+                    return Some(count + 1);
+                }
+                return None;
+            })
+            .map_err(|_| ThreadLocalError::KeyExhausted);
+    }
+
+    fn release(&self, key: usize) {
+        self.recycled
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(key);
+    }
+}
+
+/// keys emulated on top of `ThreadId`, the default backend on wasm.
+pub struct Emulated;
+
+unsafe impl crate::Backend for Emulated {
+    type Key = usize;
+
+    unsafe fn create() -> Result<usize, ThreadLocalError> {
+        let key = KEY_ALLOCATOR.allocate()?;
+
+        #[cfg(feature = "metrics")]
+        crate::key_usage::created();
+        return Ok(key);
+    }
+
+    unsafe fn get(key: usize) -> *mut u8 {
         let store = KeyStore {
-            thread_id,
+            thread_id: ThreadId::current(),
             key,
             value: 0,
-            dtor: Some(dtor::<T, A>),
         };
 
-        match KEYS.binary_search(&store) {
-            Ok(idx) => {
-                let s = &KEYS[idx];
-                return s.value as *mut T;
-            }
-            Err(idx) => {
-                KEYS.insert(idx, store);
-
-                return 0 as *mut T;
-            }
+        let keys = keys();
+        match keys.binary_search(&store) {
+            Ok(idx) => return keys[idx].value as *mut u8,
+            Err(_) => return core::ptr::null_mut(),
         }
     }
 
-    unsafe fn set_key(key: usize, value: *mut T) {
-        unsafe extern "C" fn dtor<T, A: Allocator>(ptr: *mut u8) {
-            if ptr.is_null() {
-                return;
-            }
-            let ptr = ptr as *mut T;
-            core::ptr::drop_in_place(ptr);
-            A::deallocate(ptr as _);
-        }
-
-        let thread_id: u64 = core::mem::transmute(std::thread::current().id());
+    unsafe fn set(key: usize, value: *mut u8) {
         let store = KeyStore {
-            thread_id,
+            thread_id: ThreadId::current(),
             key,
             value: value as usize,
-            dtor: Some(dtor::<T, A>),
         };
 
-        match KEYS.binary_search(&store) {
+        let mut keys = keys();
+        match keys.binary_search(&store) {
             Ok(idx) => {
-                let s = &mut KEYS[idx];
-                s.value = value as usize;
+                keys[idx].value = value as usize;
             }
             Err(idx) => {
-                KEYS.insert(idx, store);
+                keys.insert(idx, store);
             }
         }
     }
 
-    unsafe fn delete_key(key: usize) {
-        for s in &mut KEYS {
-            if s.key == key {
-                let ptr = s.value as *mut u8;
-                s.value = 0;
+    unsafe fn delete(key: usize) {
+        // values are owned by the registry of the `ThreadLocal`
+        keys().retain(|s| s.key != key);
 
-                if let Some(dtor) = s.dtor {
-                    dtor(ptr);
-                    s.dtor = None;
-                }
-            };
-        }
+        KEY_ALLOCATOR.release(key);
 
-        RECYCLE_KEYS.push(key);
+        #[cfg(feature = "metrics")]
+        crate::key_usage::recycled();
     }
 }
+
+#[test]
+fn key_is_shared_across_threads() {
+    let mut tls: crate::ThreadLocal<ThreadId> = crate::ThreadLocal::new(ThreadId::current);
+    let main = *tls.get();
+
+    std::thread::scope(|s| {
+        let workers: Vec<_> = (0..4).map(|_| s.spawn(|| *tls.get())).collect();
+
+        for worker in workers {
+            let id = worker.join().unwrap();
+            assert_ne!(id, main);
+        }
+    });
+
+    assert_eq!(*tls.get(), main);
+    assert_eq!(tls.iter().count(), 5);
+}
+
+#[test]
+fn key_allocator_recycles_and_reports_exhaustion() {
+    let keys = KeyAllocator::new(2);
+
+    assert_eq!(keys.allocate(), Ok(0));
+    assert_eq!(keys.allocate(), Ok(1));
+    assert_eq!(keys.allocate(), Err(ThreadLocalError::KeyExhausted));
+
+    keys.release(0);
+    assert_eq!(keys.allocate(), Ok(0));
+    assert_eq!(keys.allocate(), Err(ThreadLocalError::KeyExhausted));
+}
+
+#[cfg(all(debug_assertions, not(feature = "registry-backend")))]
+#[test]
+fn drop_on_another_thread_is_caught() {
+    let tls: crate::ThreadLocal<u32> = crate::ThreadLocal::new(|| 1);
+    tls.get();
+
+    let dropped = std::thread::spawn(move || drop(tls)).join();
+    assert!(dropped.is_err());
+}
@@ -0,0 +1,15 @@
+//! compile tests of the bounds on initialisers and const seeds.
+
+#[cfg(not(target_family = "wasm"))]
+#[test]
+fn new_rejects_non_send_initialisers() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/new_rc_initialiser.rs");
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[test]
+fn const_new_rejects_non_copy_seeds() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/const_new_not_copy.rs");
+}
@@ -0,0 +1,86 @@
+//! checks at runtime that `InlineThreadLocal` never touches the allocator.
+//!
+//! the crate requires std, so this is a std test counting the calls of a
+//! global allocator rather than a `#![no_std]` build.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lazy_thread_local::{InlineThreadLocal, ThreadLocalError};
+
+/// counts the allocator calls made by threads that asked for it.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// counts a call to the allocator if the current thread asked for it.
+fn count() {
+    if COUNTED.try_with(|counted| counted.get()).unwrap_or(false) {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// runs `f` with the allocator calls of the current thread counted.
+fn counted<R>(f: impl FnOnce() -> R) -> R {
+    COUNTED.with(|counted| counted.set(true));
+    let result = f();
+    COUNTED.with(|counted| counted.set(false));
+    result
+}
+
+thread_local! {
+    static COUNTED: Cell<bool> = const { Cell::new(false) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count();
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+static TLS: InlineThreadLocal<u64, 8> = InlineThreadLocal::new(|| 7);
+
+#[test]
+fn eight_threads_never_allocate() {
+    let accessed = std::thread::scope(|s| {
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                s.spawn(|| {
+                    // the first and later accesses, which find the claimed slot
+                    counted(|| *TLS.get() + *TLS.get()) / 2
+                })
+            })
+            .collect();
+        workers.into_iter().map(|w| w.join().unwrap()).sum::<u64>()
+    });
+
+    assert_eq!(accessed, 56);
+    assert_eq!(TLS.len(), 8);
+
+    // refusing a thread beyond the capacity allocates nothing either
+    let refused = std::thread::spawn(|| counted(|| TLS.try_get().copied()))
+        .join()
+        .unwrap();
+    assert_eq!(refused, Err(ThreadLocalError::ThreadLimit));
+    assert_eq!(ALLOCATIONS.load(Ordering::SeqCst), 0);
+}
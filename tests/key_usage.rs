@@ -0,0 +1,29 @@
+//! checks the process wide key counters, in a process of its own.
+#![cfg(feature = "metrics")]
+
+use lazy_thread_local::{global_key_usage, ThreadLocal};
+
+#[test]
+fn live_keys_return_to_baseline() {
+    let baseline = global_key_usage();
+
+    let locals: Vec<ThreadLocal<u32>> = (0..8).map(|i| ThreadLocal::new(move || i)).collect();
+    for tls in &locals {
+        tls.get();
+    }
+
+    let usage = global_key_usage();
+    // the registry backend keeps slots without platform keys
+    if cfg!(not(feature = "registry-backend")) {
+        assert_eq!(usage.created, baseline.created + 8);
+        assert_eq!(usage.live, baseline.live + 8);
+    }
+
+    drop(locals);
+    let usage = global_key_usage();
+    assert_eq!(usage.live, baseline.live);
+    assert_eq!(
+        usage.created - baseline.created,
+        usage.recycled - baseline.recycled
+    );
+}
@@ -0,0 +1,8 @@
+//! compile tests of the `large-copy-check` feature.
+
+#[cfg(all(feature = "large-copy-check", not(target_family = "wasm")))]
+#[test]
+fn const_new_rejects_large_values() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/const_new_too_large.rs");
+}
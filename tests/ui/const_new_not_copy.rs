@@ -0,0 +1,8 @@
+use lazy_thread_local::ThreadLocal;
+
+// copied bitwise into every thread, so a `String` would be freed by each
+static TLS: ThreadLocal<String> = ThreadLocal::const_new(String::new());
+
+fn main() {
+    TLS.get();
+}
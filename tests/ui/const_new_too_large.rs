@@ -0,0 +1,9 @@
+use lazy_thread_local::ThreadLocal;
+
+static PAGE: ThreadLocal<[u8; 4096]> = ThreadLocal::const_new([0; 4096]);
+static TOO_LARGE: ThreadLocal<[u8; 8192]> = ThreadLocal::const_new([0; 8192]);
+
+fn main() {
+    PAGE.get();
+    TOO_LARGE.get();
+}
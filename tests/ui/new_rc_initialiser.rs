@@ -0,0 +1,8 @@
+use lazy_thread_local::ThreadLocal;
+use std::rc::Rc;
+
+fn main() {
+    let rc = Rc::new(1);
+    let tls: ThreadLocal<u32> = ThreadLocal::new(move || *rc);
+    tls.get();
+}